@@ -0,0 +1,74 @@
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use web_hook::config::Config;
+use web_hook::storage::crypt::{self, Encryption, Header};
+use web_hook::storage::{chain, open_segment, Filter, Keyring, PathRules};
+
+// Keys of the config the operator can read, `keys` as ID=PATH and age
+// identity files.
+pub fn keyring(config: &Config, keys: &[String], identities: &[String]) -> io::Result<Keyring> {
+    let mut keyring = Keyring::default();
+    for bucket in config.buckets.values() {
+        if let Some(Encryption::Aes256Gcm { key_id, key_file }) = &bucket.encryption {
+            if let Ok(key) = crypt::load_key(Path::new(key_file)) {
+                keyring.aes.insert(key_id.clone(), key);
+            }
+        }
+    }
+    for key in keys {
+        let (id, path) = key.split_once('=').ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "keys are given as ID=PATH")
+        })?;
+        keyring
+            .aes
+            .insert(id.to_string(), crypt::load_key(Path::new(path))?);
+    }
+    for path in identities {
+        keyring.add_identities(Path::new(path))?;
+    }
+    Ok(keyring)
+}
+
+pub fn run(
+    dir: &str,
+    config: &Config,
+    paths: &PathRules,
+    bucket: &str,
+    device_id: &str,
+    date: Option<&str>,
+    keyring: &Keyring,
+) -> io::Result<()> {
+    let log_path = paths
+        .resolve(bucket, device_id)
+        .and_then(|p| config.layout.check(&p).map(|_| p))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let filter = Filter {
+        bucket: Some(&log_path.bucket),
+        device_id: Some(&log_path.device_id),
+        key: date,
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for segment in config.layout.list(Path::new(dir), filter)? {
+        let mut reader = open_segment(&segment)?;
+        if !reader.fill_buf()?.starts_with(crypt::HEADER.as_bytes()) {
+            io::copy(&mut reader, &mut out)?;
+            continue;
+        }
+        // encrypted, bodies are printed in the clear
+        let mut lines = reader.lines();
+        let first = lines.next().transpose()?.unwrap_or_default();
+        let header = chain::split(&first).map_or(first.as_str(), |(line, _)| line);
+        let header = Header::parse(header).unwrap();
+        let context =
+            |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", segment.path.display(), e));
+        keyring.check(&header).map_err(context)?;
+        for line in lines {
+            let line = keyring.decrypt_line(&header, &line?).map_err(context)?;
+            writeln!(out, "{}", line)?;
+        }
+    }
+    out.flush()
+}
@@ -0,0 +1,3 @@
+pub mod cat;
+pub mod retention;
+pub mod verify;
@@ -0,0 +1,14 @@
+use chrono::prelude::*;
+use std::io;
+use std::path::Path;
+use web_hook::config::Config;
+use web_hook::storage::retention;
+
+pub fn run(dir: &str, config: &Config, dry_run: bool) -> io::Result<()> {
+    let report = retention::enforce(Path::new(dir), config, Utc::now(), dry_run)?;
+    println!("{}", report);
+    match report.failed.len() {
+        0 => Ok(()),
+        n => Err(io::Error::other(format!("{} segments failed", n))),
+    }
+}
@@ -0,0 +1,23 @@
+use std::io;
+use std::path::Path;
+use web_hook::config::Config;
+use web_hook::storage::chain;
+
+// Exits with an error when a link is broken.
+pub fn run(dir: &str, config: &Config) -> io::Result<()> {
+    let key = match &config.chain {
+        Some(chain) => chain.key()?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no \"chain\" in the config",
+            ))
+        }
+    };
+    let report = chain::verify(Path::new(dir), config, &key)?;
+    println!("{}", report);
+    match report.broken {
+        Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "chain broken")),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,226 @@
+use crate::fields::Fields;
+use crate::idempotency::IdempotencyConfig;
+use crate::meta::Capture;
+use crate::redact::Redactor;
+use crate::sink::{SinkConfig, SpoolConfig};
+use crate::storage::{ChainConfig, Clock, Encryption, Layout, Partition, Retention, UploadConfig};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 256 << 10;
+
+/**
+ * Optional JSON config file, for settings that differ per bucket.
+ * The "*" bucket applies to every bucket without its own entry:
+ *
+ *   {
+ *     "timezone": "Asia/Shanghai",
+ *     "layout": "{bucket}/{device_hash:2}/{device_id}/{date}.log",
+ *     "buckets": {
+ *       "*":   { "retention": { "max_age_days": 90 } },
+ *       "sms": { "retention": { "max_total_bytes": 10737418240, "archive_dir": "/mnt/archive" } },
+ *       "gps": { "partition": "hourly", "timezone": "UTC" }
+ *     }
+ *   }
+ *
+ * See `sink` for the "sinks" section, `sink::spool` for "spool",
+ * `storage::upload` for "upload", `storage::chain` for "chain",
+ * `idempotency` for "idempotency", `meta` for the "capture" of buckets,
+ * `fields` for their "fields" and `redact` for "redact".
+ */
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Config {
+    // IANA name, defaults to UTC
+    pub timezone: Option<Tz>,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default)]
+    pub sinks: HashMap<String, SinkConfig>,
+    #[serde(default)]
+    pub buckets: HashMap<String, BucketConfig>,
+    // object storage for closed segments
+    pub upload: Option<UploadConfig>,
+    // keeps records failed sinks could not take
+    pub spool: Option<SpoolConfig>,
+    // hash-chained lines and daily seals
+    pub chain: Option<ChainConfig>,
+    // answers retried posts once
+    pub idempotency: Option<IdempotencyConfig>,
+    // name of this server in records, defaults to the host name
+    pub instance: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct BucketConfig {
+    pub retention: Option<Retention>,
+    pub timezone: Option<Tz>,
+    #[serde(default)]
+    pub partition: Partition,
+    // where records go, the "file" sink when empty
+    #[serde(default)]
+    pub sinks: Vec<Route>,
+    // bodies in the log files, see `storage::crypt`
+    pub encryption: Option<Encryption>,
+    // request metadata kept with records
+    pub capture: Option<Capture>,
+    // bytes, larger bodies are refused with 413, 256K by default
+    pub max_body_size: Option<u64>,
+    // bytes of a body kept in the record, a larger body goes to a file
+    // next to the log and the record keeps only its start
+    pub max_inline_size: Option<u64>,
+    // body fields for cat, from and the text
+    pub fields: Option<Fields>,
+    // personal data scrubbed before the sinks
+    pub redact: Option<Redactor>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Route {
+    pub name: String,
+    #[serde(default)]
+    pub policy: Policy,
+}
+
+// A record is rejected when a required sink fails, best-effort sinks may
+// fail with only a warning.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    #[default]
+    Required,
+    BestEffort,
+}
+
+impl Config {
+    pub fn load(path: &str) -> io::Result<Config> {
+        let text = fs::read_to_string(path)?;
+        let config: Config = serde_json::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config
+            .check()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
+
+    // Settings that parse alone but not together.
+    pub fn check(&self) -> Result<(), String> {
+        for (name, bucket) in self.buckets.iter() {
+            self.layout
+                .check_partition(bucket.partition)
+                .map_err(|e| format!("bucket {}: {}", name, e))?;
+        }
+        // buckets without any entry
+        if !self.buckets.contains_key("*") {
+            self.layout.check_partition(Partition::default())?;
+        }
+        for (name, bucket) in self.buckets.iter() {
+            self.check_encryption(name, bucket)?;
+        }
+        Ok(())
+    }
+
+    // Only the file sink encrypts, an encrypted bucket must not reach any
+    // other place on disk: the spool, or a sink that queues, dead-letters
+    // or stores records in plain text.
+    fn check_encryption(&self, name: &str, bucket: &BucketConfig) -> Result<(), String> {
+        if bucket.encryption.is_none() {
+            return Ok(());
+        }
+        if self.spool.is_some() {
+            return Err(format!(
+                "bucket {}: encrypted records would be spooled in plain text",
+                name
+            ));
+        }
+        for route in bucket.sinks.iter() {
+            if self
+                .sinks
+                .get(&route.name)
+                .is_some_and(|sink| sink.plaintext())
+            {
+                return Err(format!(
+                    "bucket {}: sink {} would take encrypted records in plain text",
+                    name, route.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bucket(&self, bucket: &str) -> Option<&BucketConfig> {
+        self.buckets.get(bucket).or_else(|| self.buckets.get("*"))
+    }
+
+    pub fn max_body_size(&self, bucket: &str) -> u64 {
+        self.bucket(bucket)
+            .and_then(|b| b.max_body_size)
+            .unwrap_or(DEFAULT_MAX_BODY_SIZE)
+    }
+
+    pub fn instance(&self) -> String {
+        self.instance
+            .clone()
+            .or_else(|| env::var("HOSTNAME").ok())
+            .or_else(|| fs::read_to_string("/etc/hostname").ok())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| String::from("unknown"))
+    }
+
+    pub fn clock(&self, bucket: &str) -> Clock {
+        let bucket = self.bucket(bucket);
+        Clock {
+            tz: bucket
+                .and_then(|b| b.timezone)
+                .or(self.timezone)
+                .unwrap_or(Tz::UTC),
+            partition: bucket.map(|b| b.partition).unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_encryption() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "sinks": { "es": { "type": "elasticsearch", "url": "http://localhost:9200" } },
+                "buckets": {
+                    "sms": {
+                        "encryption": { "cipher": "age", "recipients": ["age1x"] },
+                        "sinks": [{ "name": "file" }, { "name": "es" }]
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.check().unwrap_err(),
+            "bucket sms: sink es would take encrypted records in plain text"
+        );
+
+        // databases and streams are refused too
+        let mut config = config;
+        for sink in [
+            r#"{ "type": "postgres", "url": "postgres://localhost/hooks" }"#,
+            r#"{ "type": "redis", "address": "127.0.0.1:6379" }"#,
+        ] {
+            config
+                .sinks
+                .insert(String::from("es"), serde_json::from_str(sink).unwrap());
+            assert!(config.check().is_err());
+        }
+
+        config.buckets.get_mut("sms").unwrap().sinks.pop();
+        assert!(config.check().is_ok());
+        config.spool = Some(serde_json::from_str("{}").unwrap());
+        assert!(config.check().unwrap_err().contains("spooled"));
+    }
+}
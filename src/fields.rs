@@ -0,0 +1,144 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/**
+ * Bodies with fields. With "fields" set for a bucket, form and JSON bodies
+ * are parsed and the named fields fill in the record:
+ *
+ *   "buckets": {
+ *     "sms": { "fields": { "cat": "type", "from": "sender", "text": "content" } }
+ *   }
+ *
+ * `cat` and `from` in the query still win over the body. Once the text is
+ * taken from a field, the other fields go to the meta as "field.{name}" so
+ * nothing of the body is lost. Multipart bodies are always parsed, their file
+ * parts are kept as attachments, see `storage::attach`.
+ */
+pub const FIELD_PREFIX: &str = "field.";
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Fields {
+    pub cat: Option<String>,
+    pub from: Option<String>,
+    // the message, the record body
+    pub text: Option<String>,
+}
+
+// A body split up by `Fields`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Parsed {
+    pub cat: Option<String>,
+    pub from: Option<String>,
+    pub text: Option<String>,
+    // unmapped fields
+    pub rest: BTreeMap<String, String>,
+}
+
+impl Parsed {
+    // Meta for the unmapped fields, only needed once the body is replaced.
+    pub fn meta(&self) -> BTreeMap<String, String> {
+        match self.text {
+            Some(_) => self
+                .rest
+                .iter()
+                .map(|(k, v)| (format!("{}{}", FIELD_PREFIX, k), v.clone()))
+                .collect(),
+            None => BTreeMap::new(),
+        }
+    }
+}
+
+impl Fields {
+    pub fn split(&self, fields: Vec<(String, String)>) -> Parsed {
+        let mut parsed = Parsed::default();
+        for (name, value) in fields {
+            let is = |field: &Option<String>| field.as_deref() == Some(name.as_str());
+            if is(&self.cat) && parsed.cat.is_none() {
+                parsed.cat = Some(value);
+            } else if is(&self.from) && parsed.from.is_none() {
+                parsed.from = Some(value);
+            } else if is(&self.text) && parsed.text.is_none() {
+                parsed.text = Some(value);
+            } else {
+                parsed.rest.entry(name).or_insert(value);
+            }
+        }
+        parsed
+    }
+}
+
+// Top-level fields of a form or JSON body, None for other bodies or those
+// that do not parse. Nested JSON values are kept as JSON text.
+pub fn parse(content_type: &str, text: &str) -> Option<Vec<(String, String)>> {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if mime == "application/x-www-form-urlencoded" {
+        serde_urlencoded::from_str(text).ok()
+    } else if mime == "application/json" || mime.ends_with("+json") {
+        match serde_json::from_str(text).ok()? {
+            Value::Object(map) => Some(
+                map.into_iter()
+                    .map(|(k, v)| {
+                        let v = match v {
+                            Value::String(s) => s,
+                            v => v.to_string(),
+                        };
+                        (k, v)
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields {
+        Fields {
+            cat: Some(String::from("type")),
+            from: Some(String::from("sender")),
+            text: Some(String::from("content")),
+        }
+    }
+
+    #[test]
+    fn test_fields_parse() {
+        let form = parse(
+            "application/x-www-form-urlencoded; charset=utf-8",
+            "type=text&sender=10086&content=%E4%BD%A0%E5%A5%BD+hi&sim=2",
+        )
+        .unwrap();
+        let parsed = fields().split(form);
+        assert_eq!(parsed.cat.as_deref(), Some("text"));
+        assert_eq!(parsed.from.as_deref(), Some("10086"));
+        assert_eq!(parsed.text.as_deref(), Some("你好 hi"));
+        assert_eq!(parsed.meta().get("field.sim").unwrap(), "2");
+
+        let json = parse(
+            "application/json",
+            r#"{"sender": 10086, "content": "hi", "extra": {"a": [1]}}"#,
+        )
+        .unwrap();
+        let parsed = fields().split(json);
+        assert_eq!(parsed.cat, None);
+        assert_eq!(parsed.from.as_deref(), Some("10086"));
+        assert_eq!(parsed.meta().get("field.extra").unwrap(), r#"{"a":[1]}"#);
+
+        // no text field, the body stays as it is
+        let parsed = fields().split(parse("application/json", r#"{"type": "x"}"#).unwrap());
+        assert!(parsed.meta().is_empty());
+
+        assert_eq!(parse("application/json", "[1, 2]"), None);
+        assert_eq!(parse("text/plain", "a=1"), None);
+    }
+}
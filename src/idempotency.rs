@@ -0,0 +1,370 @@
+use actix_web::{web, HttpRequest};
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/**
+ * Idempotent posts. A request with an Idempotency-Key header, or with the
+ * configured field in a JSON body, is answered once. A retry with the same
+ * key within the TTL gets the first response back and writes nothing:
+ *
+ *   "idempotency": { "ttl": 86400, "field": "msg_id" }
+ *
+ * Keys are per bucket and device. Responses are kept as files below
+ * `.idempotency` in the work dir, so they outlive a restart, and swept once
+ * they expire. A request written in parts, a batch, keeps how far it got
+ * and the ids it gave out under its key, a retry of it resumes there with
+ * the same ids.
+ */
+pub const HEADER: &str = "idempotency-key";
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_KEY: usize = 255;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdempotencyConfig {
+    // defaults to `.idempotency` in the work dir
+    pub dir: Option<String>,
+    // seconds a response is kept
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    // top-level field of JSON bodies used when the header is missing
+    pub field: Option<String>,
+}
+
+fn default_ttl() -> u64 {
+    86400
+}
+
+// A response as first sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stored {
+    pub scope: String,
+    pub key: String,
+    // SHA-256 of the request body, a key is not for another body
+    pub digest: String,
+    pub request_id: String,
+    pub content_type: String,
+    pub body: String,
+    pub expires: DateTime<Utc>,
+    // parts written by a request that did not finish, no response yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<usize>,
+    // ids of all parts, handed out by the first try
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum Claim<'a> {
+    // first time, answer it and `save` the response
+    New(Pending<'a>),
+    Replay(Stored),
+    // the first request with the key is not done yet
+    InFlight,
+    // the key came with another body before
+    Mismatch,
+}
+
+// A claimed key, released on drop. Not saving it lets a retry try again.
+#[derive(Debug)]
+pub struct Pending<'a> {
+    store: &'a Store,
+    name: String,
+    stored: Stored,
+}
+
+impl Pending<'_> {
+    pub fn save(mut self, request_id: &str, content_type: &str, body: &str) -> io::Result<()> {
+        self.stored.request_id = request_id.to_string();
+        self.stored.content_type = content_type.to_string();
+        self.stored.body = body.to_string();
+        self.stored.progress = None;
+        self.stored.parts = Vec::new();
+        write(
+            &self.store.path(&self.name),
+            &serde_json::to_vec(&self.stored)?,
+        )
+    }
+
+    // Parts an earlier try of the request wrote already.
+    pub fn resume(&self) -> usize {
+        self.stored.progress.unwrap_or(0)
+    }
+
+    // Request id and part ids of an earlier try, to answer with again.
+    pub fn resumed(&self) -> Option<(&str, &[String])> {
+        match self.stored.progress {
+            Some(_) => Some((&self.stored.request_id, &self.stored.parts)),
+            None => None,
+        }
+    }
+
+    // Ids to keep with the progress, before the first part is written.
+    pub fn start(&mut self, request_id: &str, parts: Vec<String>) {
+        self.stored.request_id = request_id.to_string();
+        self.stored.parts = parts;
+    }
+
+    // Note `done` parts as written, a retry skips them. Written on the
+    // blocking pool.
+    pub async fn progress(&mut self, done: usize) -> io::Result<()> {
+        self.stored.progress = Some(done);
+        let path = self.store.path(&self.name);
+        let data = serde_json::to_vec(&self.stored)?;
+        web::block(move || write(&path, &data))
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?
+    }
+}
+
+fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.store.inflight.lock().unwrap().remove(&self.name);
+    }
+}
+
+#[derive(Debug)]
+pub struct Store {
+    config: IdempotencyConfig,
+    dir: PathBuf,
+    inflight: Mutex<HashSet<String>>,
+}
+
+impl Store {
+    pub fn open(config: &IdempotencyConfig, dir: &Path) -> io::Result<Self> {
+        let dir = match &config.dir {
+            Some(own) => PathBuf::from(own),
+            None => dir.join(".idempotency"),
+        };
+        fs::create_dir_all(&dir)?;
+        Ok(Store {
+            config: config.clone(),
+            dir,
+            inflight: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn ttl(&self) -> u64 {
+        self.config.ttl
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    // The key of a request, from the header or else the body field.
+    pub fn key(&self, req: &HttpRequest, body: &str) -> Result<Option<String>, String> {
+        let key = match req.headers().get(HEADER) {
+            Some(v) => Some(v.to_str().map_err(|e| e.to_string())?.to_string()),
+            None => self.config.field.as_ref().and_then(|field| {
+                match serde_json::from_str::<serde_json::Value>(body)
+                    .ok()?
+                    .get(field)?
+                {
+                    serde_json::Value::String(s) => Some(s.clone()),
+                    serde_json::Value::Number(n) => Some(n.to_string()),
+                    _ => None,
+                }
+            }),
+        };
+        match key {
+            Some(key) if key.is_empty() || key.len() > MAX_KEY => {
+                Err(format!("bad idempotency key, 1 to {} chars", MAX_KEY))
+            }
+            key => Ok(key),
+        }
+    }
+
+    // `digest` is the hex SHA-256 of the request body.
+    pub fn claim(
+        &self,
+        scope: &str,
+        key: &str,
+        digest: &str,
+        now: DateTime<Utc>,
+    ) -> io::Result<Claim<'_>> {
+        let name = hex::encode(Sha256::digest(format!("{}\n{}", scope, key)));
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.contains(&name) {
+            return Ok(Claim::InFlight);
+        }
+        let path = self.path(&name);
+        let mut resumed = None;
+        if let Ok(data) = fs::read(&path) {
+            match serde_json::from_slice::<Stored>(&data) {
+                Ok(stored) if stored.expires > now && stored.digest != digest => {
+                    return Ok(Claim::Mismatch)
+                }
+                Ok(stored) if stored.expires > now && stored.progress.is_some() => {
+                    resumed = Some(stored)
+                }
+                Ok(stored) if stored.expires > now => return Ok(Claim::Replay(stored)),
+                // expired, or cut short by a crash
+                _ => fs::remove_file(&path)?,
+            }
+        }
+        inflight.insert(name.clone());
+        let stored = resumed.unwrap_or_else(|| Stored {
+            scope: scope.to_string(),
+            key: key.to_string(),
+            digest: digest.to_string(),
+            request_id: String::new(),
+            content_type: String::new(),
+            body: String::new(),
+            expires: now + Duration::seconds(self.config.ttl as i64),
+            progress: None,
+            parts: Vec::new(),
+        });
+        Ok(Claim::New(Pending {
+            store: self,
+            name,
+            stored,
+        }))
+    }
+
+    // Remove expired responses. Returns how many.
+    pub fn sweep(&self, now: DateTime<Utc>) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let expired = match fs::read(&path) {
+                Ok(data) => serde_json::from_slice::<Stored>(&data)
+                    .map(|s| s.expires <= now)
+                    .unwrap_or(true),
+                Err(_) => continue,
+            };
+            if expired {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn digest(body: &[u8]) -> String {
+        hex::encode(Sha256::digest(body))
+    }
+
+    fn store(dir: &Path) -> Store {
+        let _ = fs::remove_dir_all(dir);
+        let config = IdempotencyConfig {
+            dir: None,
+            ttl: 60,
+            field: Some(String::from("msg_id")),
+        };
+        Store::open(&config, dir).unwrap()
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let store = store(Path::new("./logs/web_hook_test/idempotency_key"));
+        let req = TestRequest::default()
+            .insert_header(("Idempotency-Key", "k1"))
+            .to_http_request();
+        assert_eq!(store.key(&req, "{\"msg_id\":7}").unwrap().unwrap(), "k1");
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(store.key(&req, "{\"msg_id\":7}").unwrap().unwrap(), "7");
+        assert_eq!(store.key(&req, "plain text").unwrap(), None);
+        assert!(store.key(&req, "{\"msg_id\":\"\"}").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_idempotency_claim() {
+        let dir = Path::new("./logs/web_hook_test/idempotency_claim");
+        let store = store(dir);
+        let now = Utc.with_ymd_and_hms(2022, 4, 1, 0, 0, 0).unwrap();
+
+        let pending = match store.claim("sms/100", "k1", &digest(b"hi"), now).unwrap() {
+            Claim::New(pending) => pending,
+            claim => panic!("{:?}", claim),
+        };
+        assert!(matches!(
+            store.claim("sms/100", "k1", &digest(b"hi"), now).unwrap(),
+            Claim::InFlight
+        ));
+        pending.save("r-1", "text/plain", "ok").unwrap();
+
+        // a restart keeps what was answered
+        let store = Store::open(&store.config, dir).unwrap();
+        match store.claim("sms/100", "k1", &digest(b"hi"), now).unwrap() {
+            Claim::Replay(stored) => {
+                assert_eq!(
+                    (stored.request_id.as_str(), stored.body.as_str()),
+                    ("r-1", "ok")
+                )
+            }
+            claim => panic!("{:?}", claim),
+        }
+        assert!(matches!(
+            store
+                .claim("sms/100", "k1", &digest(b"other"), now)
+                .unwrap(),
+            Claim::Mismatch
+        ));
+        // keys are per device
+        assert!(matches!(
+            store.claim("sms/200", "k1", &digest(b"hi"), now).unwrap(),
+            Claim::New(_)
+        ));
+        // a dropped claim is free again
+        assert!(matches!(
+            store.claim("sms/200", "k1", &digest(b"hi"), now).unwrap(),
+            Claim::New(_)
+        ));
+
+        // a request cut short resumes where it stopped
+        let mut pending = match store.claim("sms/300", "k1", &digest(b"hi"), now).unwrap() {
+            Claim::New(pending) => pending,
+            claim => panic!("{:?}", claim),
+        };
+        assert_eq!(pending.resume(), 0);
+        assert!(pending.resumed().is_none());
+        pending.start("r-3", vec![String::from("a"), String::from("b")]);
+        pending.progress(1).await.unwrap();
+        drop(pending);
+        match store.claim("sms/300", "k1", &digest(b"hi"), now).unwrap() {
+            Claim::New(pending) => {
+                assert_eq!(pending.resume(), 1);
+                let (id, parts) = pending.resumed().unwrap();
+                assert_eq!((id, parts.len()), ("r-3", 2));
+                pending.save("r-3", "text/plain", "ok").unwrap();
+            }
+            claim => panic!("{:?}", claim),
+        }
+        assert!(matches!(
+            store.claim("sms/300", "k1", &digest(b"hi"), now).unwrap(),
+            Claim::Replay(_)
+        ));
+
+        let later = now + Duration::seconds(60);
+        assert_eq!(store.sweep(now).unwrap(), 0);
+        assert_eq!(store.sweep(later).unwrap(), 2);
+        assert!(matches!(
+            store
+                .claim("sms/100", "k1", &digest(b"other"), later)
+                .unwrap(),
+            Claim::New(_)
+        ));
+    }
+}
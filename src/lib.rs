@@ -1,21 +1,35 @@
 use actix_web::dev::Payload;
 use actix_web::http::header::USER_AGENT;
 use actix_web::{error, web, Error, FromRequest, HttpRequest, Result};
-use base64;
 use futures_util::future::{err, ok, Ready};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+pub mod config;
+pub mod fields;
+pub mod idempotency;
+pub mod meta;
+pub mod redact;
+pub mod sink;
+pub mod storage;
 
 // constants
 // pub static SECRET: &'static str = "12345";
 // pub static UA: &'static str = "foobar";
 
 // structs
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default)]
 pub struct AppData {
     pub dir: String,
     pub ua: String,
     pub secret: String,
+    // see `Config::instance`
+    pub instance: String,
+    pub config: config::Config,
+    pub paths: storage::PathRules,
+    pub sinks: sink::Sinks,
+    pub idempotency: Option<Arc<idempotency::Store>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,12 +39,12 @@ pub struct TokenParams {
 }
 
 // utils
-pub fn get_user_agent<'a>(req: &'a HttpRequest) -> Option<&'a str> {
+pub fn get_user_agent(req: &HttpRequest) -> Option<&str> {
     req.headers().get(USER_AGENT)?.to_str().ok()
 }
 
 pub fn is_authorized(req: &HttpRequest) -> bool {
-    let ua = get_user_agent(&req);
+    let ua = get_user_agent(req);
     let query = web::Query::<TokenParams>::from_query(req.query_string()).unwrap();
     let app_data = req.app_data::<web::Data<AppData>>().unwrap();
 
@@ -52,21 +66,22 @@ pub fn is_authorized(req: &HttpRequest) -> bool {
 }
 
 pub fn get_token<'a>(ts: Option<&'a str>, app_data: &'a web::Data<AppData>) -> Option<String> {
-    if ts.is_some() {
+    if let Some(ts) = ts {
         let mut hasher = Sha256::new();
-        hasher.update(format!(
-            "{}!!{}##{}",
-            app_data.secret,
-            ts.unwrap(),
-            app_data.secret
-        ));
+        hasher.update(format!("{}!!{}##{}", app_data.secret, ts, app_data.secret));
         let hash = hasher.finalize();
-        Some(base64::encode_config(&hash, base64::URL_SAFE_NO_PAD))
+        Some(base64::encode_config(hash, base64::URL_SAFE_NO_PAD))
     } else {
         None
     }
 }
 
+// Names the secret in records without giving it away.
+pub fn key_id(secret: &str) -> String {
+    let hash = Sha256::digest(secret.as_bytes());
+    hex::encode(&hash[..4])
+}
+
 // [Middleware::Extractor] AuthorizedUrl
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthorizedUrl;
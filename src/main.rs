@@ -1,9 +1,22 @@
+mod cmd;
 mod pages;
 
-use actix_web::{middleware, web, App, HttpServer};
-use clap::Parser;
+use actix_web::{middleware, rt, web, App, HttpServer};
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand};
 use env_logger::Env;
-use log::info;
+use log::{info, warn};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use web_hook::config::Config;
+use web_hook::idempotency;
+use web_hook::sink::Sinks;
+use web_hook::storage::{
+    chain, path, retention, s3, Codec, Compactor, Layout, PathRules, Rotation, Uploader,
+};
 use web_hook::AppData;
 
 const NAME: &str = env!("CARGO_PKG_NAME");
@@ -23,22 +36,213 @@ struct Cli {
     // Service Port
     #[clap(short, long, default_value_t = 8080)]
     port: u16,
+    // Per bucket settings, JSON
+    #[clap(short, long)]
+    config: Option<String>,
+    // Time zone for file partitions and line timestamps, IANA name
+    #[clap(long)]
+    timezone: Option<Tz>,
+    // Path template of log files below the work dir
+    #[clap(long)]
+    layout: Option<Layout>,
+    // Chars allowed in bucket and device_id, as a regex char class
+    #[clap(long, default_value_t = String::from(path::DEFAULT_CHARS))]
+    path_chars: String,
+    // Max length of bucket and device_id
+    #[clap(long, default_value_t = path::DEFAULT_MAX_LEN)]
+    path_max_len: usize,
+    // Lowercase bucket and device_id before use
+    #[clap(long)]
+    path_lowercase: bool,
+    // Roll to the next segment of the day at this size, e.g. 512K, 64M, 1G
+    #[clap(long, parse(try_from_str = parse_size))]
+    max_size: Option<u64>,
+    // Roll to the next segment of the day after this many records
+    #[clap(long)]
+    max_records: Option<u64>,
+    // Compress closed segments of past dates: gzip or zstd
+    #[clap(long)]
+    compress: Option<Codec>,
+    // Compression level, defaults to 6 for gzip and 3 for zstd
+    #[clap(long)]
+    compress_level: Option<i32>,
+    // Seconds between compaction runs
+    #[clap(long, default_value_t = 3600)]
+    compact_interval: u64,
+    // Seconds between retention runs
+    #[clap(long, default_value_t = 3600)]
+    retention_interval: u64,
+    // Only report what retention would remove
+    #[clap(long)]
+    retention_dry_run: bool,
+    // Seconds between uploads of closed segments, see "upload" in the config
+    #[clap(long, default_value_t = 3600)]
+    upload_interval: u64,
+    // Seconds between seals of past days, see "chain" in the config
+    #[clap(long, default_value_t = 3600)]
+    seal_interval: u64,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    // Print the log of a device, segments in order, encrypted bodies decrypted
+    #[clap(alias = "decrypt")]
+    Cat {
+        bucket: String,
+        device_id: String,
+        // Only this partition, e.g. 20220401, or a prefix of it
+        #[clap(long)]
+        date: Option<String>,
+        // AES key as ID=PATH, keys in the config are tried too
+        #[clap(long = "key", multiple_occurrences = true)]
+        keys: Vec<String>,
+        // age identity file
+        #[clap(long = "identity", multiple_occurrences = true)]
+        identities: Vec<String>,
+    },
+    // Apply the retention rules once and print what was removed
+    Retention {
+        #[clap(long)]
+        dry_run: bool,
+    },
+    // Check the hash chains and seals of the work dir, report the first broken link
+    Verify,
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let factor = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("unknown size unit: {}", unit)),
+    };
+    num.parse::<u64>()
+        .map(|n| n * factor)
+        .map_err(|e| e.to_string())
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> io::Result<()> {
     let cli = Cli::parse();
     let env = Env::default().filter_or("LOG_LEVEL", "debug");
     env_logger::init_from_env(env);
 
-    HttpServer::new(|| {
-        let cli = Cli::parse();
-        let data = web::Data::new(AppData {
-            dir: cli.dir,
-            secret: cli.secret,
-            ua: cli.ua,
-        });
-        info!("Starting HTTP server at http://localhost:{}", cli.port);
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if cli.timezone.is_some() {
+        config.timezone = cli.timezone;
+    }
+    if let Some(layout) = &cli.layout {
+        config.layout = layout.clone();
+    }
+
+    let paths = PathRules::new(&cli.path_chars, cli.path_max_len, cli.path_lowercase)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    if let Some(command) = &cli.command {
+        return match command {
+            Command::Cat {
+                bucket,
+                device_id,
+                date,
+                keys,
+                identities,
+            } => cmd::cat::run(
+                &cli.dir,
+                &config,
+                &paths,
+                bucket,
+                device_id,
+                date.as_deref(),
+                &cmd::cat::keyring(&config, keys, identities)?,
+            ),
+            Command::Retention { dry_run } => cmd::retention::run(&cli.dir, &config, *dry_run),
+            Command::Verify => cmd::verify::run(&cli.dir, &config),
+        };
+    }
+
+    if let Some(codec) = cli.compress {
+        spawn_compactor(
+            Compactor::new(codec, cli.compress_level),
+            config.clone(),
+            PathBuf::from(&cli.dir),
+            Duration::from_secs(cli.compact_interval),
+        );
+    }
+
+    if config.buckets.values().any(|b| b.retention.is_some()) {
+        spawn_retention(
+            config.clone(),
+            PathBuf::from(&cli.dir),
+            Duration::from_secs(cli.retention_interval),
+            cli.retention_dry_run,
+        );
+    }
+
+    if let Some(upload) = &config.upload {
+        spawn_uploader(
+            Uploader::new(upload.clone())?,
+            config.clone(),
+            PathBuf::from(&cli.dir),
+            Duration::from_secs(cli.upload_interval),
+            cli.compress.is_some(),
+        );
+    }
+
+    if let Some(chain) = &config.chain {
+        spawn_sealer(
+            chain.key()?,
+            config.clone(),
+            PathBuf::from(&cli.dir),
+            Duration::from_secs(cli.seal_interval),
+        );
+    }
+
+    let idempotency = match &config.idempotency {
+        Some(idempotency) => {
+            let store = Arc::new(idempotency::Store::open(idempotency, Path::new(&cli.dir))?);
+            spawn_sweeper(store.clone());
+            Some(store)
+        }
+        None => None,
+    };
+
+    let sinks = Sinks::build(
+        &config,
+        Path::new(&cli.dir),
+        Rotation {
+            max_size: cli.max_size,
+            max_records: cli.max_records,
+        },
+    )?;
+    sinks.check()?;
+    sinks.start();
+
+    // shared by all workers, rotation state must not be per worker
+    let data = web::Data::new(AppData {
+        dir: cli.dir,
+        secret: cli.secret,
+        ua: cli.ua,
+        instance: config.instance(),
+        config,
+        paths,
+        sinks,
+        idempotency,
+    });
+    let sinks = data.sinks.clone();
+
+    info!("Starting HTTP server at http://localhost:{}", cli.port);
+    HttpServer::new(move || {
         App::new()
             // store in application storage
             .app_data(data.clone())
@@ -47,8 +251,109 @@ async fn main() -> std::io::Result<()> {
             .service(pages::hello::get)
             .service(pages::hello::post)
             .service(pages::log::action)
+            .service(pages::log::batch)
+            .service(pages::status::get)
     })
     .bind(("0.0.0.0", cli.port))?
     .run()
-    .await
+    .await?;
+
+    sinks.flush().await;
+    Ok(())
+}
+
+fn spawn_compactor(compactor: Compactor, config: Config, dir: PathBuf, every: Duration) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            let compactor = compactor.clone();
+            let config = config.clone();
+            let dir = dir.clone();
+            match web::block(move || compactor.run(&dir, &config, Utc::now())).await {
+                Ok(Ok(done)) => info!("compaction done, {} segments", done.len()),
+                Ok(Err(e)) => warn!("compaction failed: {}", e),
+                Err(e) => warn!("compaction failed: {}", e),
+            }
+        }
+    });
+}
+
+fn spawn_retention(config: Config, dir: PathBuf, every: Duration, dry_run: bool) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            let config = config.clone();
+            let dir = dir.clone();
+            match web::block(move || retention::enforce(&dir, &config, Utc::now(), dry_run)).await {
+                Ok(Ok(report)) => {
+                    for line in report.to_string().lines() {
+                        info!("retention: {}", line);
+                    }
+                }
+                Ok(Err(e)) => warn!("retention failed: {}", e),
+                Err(e) => warn!("retention failed: {}", e),
+            }
+        }
+    });
+}
+
+fn spawn_uploader(
+    uploader: Uploader,
+    config: Config,
+    dir: PathBuf,
+    every: Duration,
+    compressed: bool,
+) {
+    rt::spawn(async move {
+        let http = s3::client(Duration::from_secs(300));
+        let mut interval = rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            match uploader
+                .run(&http, &dir, &config, Utc::now(), compressed)
+                .await
+            {
+                Ok(done) => info!("upload done, {} segments", done.len()),
+                Err(e) => warn!("upload failed: {}", e),
+            }
+        }
+    });
+}
+
+fn spawn_sealer(key: Vec<u8>, config: Config, dir: PathBuf, every: Duration) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            let key = key.clone();
+            let config = config.clone();
+            let dir = dir.clone();
+            match web::block(move || chain::seal(&dir, &config, &key, Utc::now())).await {
+                Ok(Ok(sealed)) if sealed.is_empty() => {}
+                Ok(Ok(sealed)) => info!("sealed {}", sealed.join(", ")),
+                Ok(Err(e)) => warn!("seal failed: {}", e),
+                Err(e) => warn!("seal failed: {}", e),
+            }
+        }
+    });
+}
+
+// Expired responses go at least hourly, sooner for shorter TTLs.
+fn spawn_sweeper(store: Arc<idempotency::Store>) {
+    rt::spawn(async move {
+        let every = Duration::from_secs(store.ttl().clamp(1, 3600));
+        let mut interval = rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            let store = store.clone();
+            match web::block(move || store.sweep(Utc::now())).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => info!("{} idempotency keys expired", removed),
+                Ok(Err(e)) => warn!("idempotency sweep failed: {}", e),
+                Err(e) => warn!("idempotency sweep failed: {}", e),
+            }
+        }
+    });
 }
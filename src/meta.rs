@@ -0,0 +1,323 @@
+use actix_web::http::header::{HeaderMap, CONTENT_TYPE};
+use actix_web::HttpRequest;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use ulid::Ulid;
+
+/**
+ * Request metadata kept with a record, in `Record::meta`. Nothing is kept
+ * unless a bucket asks for it:
+ *
+ *   "buckets": {
+ *     "sms": {
+ *       "capture": {
+ *         "client_ip": true,
+ *         "real_ip": true, "proxies": ["10.0.0.0/8", "127.0.0.1"],
+ *         "headers": ["X-App-Version"],
+ *         "content_type": true, "body_size": true,
+ *         "key_id": true, "instance": true
+ *       }
+ *     }
+ *   }
+ *
+ * The real IP is only taken from X-Forwarded-For, Forwarded or X-Real-IP
+ * when the peer is one of the trusted proxies, anyone else could send them.
+ *
+ * Every record gets a "request_id" whatever the bucket says, a ULID unless
+ * the client sent its own X-Request-Id.
+ */
+pub const HEADER_PREFIX: &str = "header.";
+pub const REQUEST_ID: &str = "request_id";
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID: usize = 128;
+
+// The X-Request-Id of the client, or a new ULID.
+pub fn request_id(req: &HttpRequest) -> Result<String, String> {
+    match req.headers().get(REQUEST_ID_HEADER) {
+        None => Ok(Ulid::new().to_string()),
+        Some(v) => match v.to_str() {
+            Ok(id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID => Ok(id.to_string()),
+            _ => Err(format!(
+                "bad X-Request-Id, 1 to {} visible chars",
+                MAX_REQUEST_ID
+            )),
+        },
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Capture {
+    // the peer address of the connection
+    #[serde(default)]
+    pub client_ip: bool,
+    // the client behind trusted proxies
+    #[serde(default)]
+    pub real_ip: bool,
+    // addresses or CIDR ranges
+    #[serde(default)]
+    pub proxies: Vec<Cidr>,
+    // kept as "header.{lowercase name}"
+    #[serde(default)]
+    pub headers: Vec<String>,
+    #[serde(default)]
+    pub content_type: bool,
+    #[serde(default)]
+    pub body_size: bool,
+    // the id of the key the request was signed with
+    #[serde(default)]
+    pub key_id: bool,
+    // the "instance" of the config, the host name by default
+    #[serde(default)]
+    pub instance: bool,
+}
+
+// An address range, a single address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.as_str(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("bad proxy {}: {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("bad proxy {}: prefix over {}", s, max))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Capture {
+    fn trusted(&self, ip: &IpAddr) -> bool {
+        self.proxies.iter().any(|p| p.contains(ip))
+    }
+
+    // The first address from the right no trusted proxy stands for.
+    pub fn real_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusted(&peer) {
+            return peer;
+        }
+        let mut hops: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(parse_ip)
+            .collect();
+        if hops.is_empty() {
+            hops = headers
+                .get_all("forwarded")
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split([',', ';']))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .filter(|(k, _)| k.eq_ignore_ascii_case("for"))
+                .filter_map(|(_, v)| parse_ip(v))
+                .collect();
+        }
+        if let Some(ip) = hops.iter().rev().find(|ip| !self.trusted(ip)) {
+            return *ip;
+        }
+        if hops.is_empty() {
+            if let Some(ip) = headers
+                .get("x-real-ip")
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_ip)
+            {
+                return ip;
+            }
+        }
+        hops.first().copied().unwrap_or(peer)
+    }
+
+    pub fn capture(
+        &self,
+        req: &HttpRequest,
+        body_size: u64,
+        key_id: &str,
+        instance: &str,
+    ) -> BTreeMap<String, String> {
+        let mut meta = BTreeMap::new();
+        let peer = req.peer_addr().map(|a| a.ip());
+        if let (true, Some(peer)) = (self.client_ip, peer) {
+            meta.insert(String::from("client_ip"), peer.to_string());
+        }
+        if let (true, Some(peer)) = (self.real_ip, peer) {
+            let ip = self.real_ip(peer, req.headers());
+            meta.insert(String::from("real_ip"), ip.to_string());
+        }
+        for name in self.headers.iter() {
+            let values: Vec<&str> = req
+                .headers()
+                .get_all(name.as_str())
+                .filter_map(|v| v.to_str().ok())
+                .collect();
+            if !values.is_empty() {
+                let key = format!("{}{}", HEADER_PREFIX, name.to_ascii_lowercase());
+                meta.insert(key, values.join(", "));
+            }
+        }
+        if self.content_type {
+            if let Some(v) = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+            {
+                meta.insert(String::from("content_type"), v.to_string());
+            }
+        }
+        if self.body_size {
+            meta.insert(String::from("body_size"), body_size.to_string());
+        }
+        if self.key_id {
+            meta.insert(String::from("key_id"), key_id.to_string());
+        }
+        if self.instance {
+            meta.insert(String::from("instance"), instance.to_string());
+        }
+        meta
+    }
+}
+
+// "1.2.3.4", "1.2.3.4:80", "[::1]:80" or "\"[::1]\"" as in Forwarded.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    s.parse::<IpAddr>()
+        .ok()
+        .or_else(|| s.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| s.trim_start_matches('[').trim_end_matches(']').parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn capture() -> Capture {
+        serde_json::from_value(serde_json::json!({
+            "client_ip": true,
+            "real_ip": true,
+            "proxies": ["10.0.0.0/8", "::1"],
+            "headers": ["X-App-Version", "X-Missing"],
+            "content_type": true,
+            "body_size": true,
+            "key_id": true,
+            "instance": true,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_capture() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "1.1.1.1, 2.2.2.2, 10.0.0.1"))
+            .insert_header(("X-App-Version", "3.1"))
+            .insert_header(("Content-Type", "text/plain"))
+            .to_http_request();
+        let meta = capture().capture(&req, 12, "a1b2c3d4", "web-1");
+        let expected: BTreeMap<String, String> = [
+            ("body_size", "12"),
+            ("client_ip", "10.0.0.2"),
+            ("content_type", "text/plain"),
+            ("header.x-app-version", "3.1"),
+            ("instance", "web-1"),
+            ("key_id", "a1b2c3d4"),
+            ("real_ip", "2.2.2.2"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(meta, expected);
+
+        // nothing asked for, nothing kept
+        assert!(Capture::default().capture(&req, 12, "", "").is_empty());
+    }
+
+    #[test]
+    fn test_real_ip() {
+        let capture = capture();
+        let real_ip = |peer: &str, headers: &[(&str, &str)]| {
+            let mut req = TestRequest::default();
+            for header in headers {
+                req = req.insert_header(*header);
+            }
+            let req = req.to_http_request();
+            capture
+                .real_ip(peer.parse().unwrap(), req.headers())
+                .to_string()
+        };
+        // only trusted proxies are believed
+        assert_eq!(real_ip("3.3.3.3", &[("X-Real-IP", "1.1.1.1")]), "3.3.3.3");
+        assert_eq!(real_ip("10.1.2.3", &[("X-Real-IP", "1.1.1.1")]), "1.1.1.1");
+        assert_eq!(
+            real_ip(
+                "::1",
+                &[("Forwarded", "for=\"[2001:db8::1]:80\";proto=https")]
+            ),
+            "2001:db8::1"
+        );
+        // all hops trusted, the first is the client
+        assert_eq!(
+            real_ip(
+                "10.0.0.2",
+                &[("X-Forwarded-For", "10.9.9.9:1234, 10.0.0.1")]
+            ),
+            "10.9.9.9"
+        );
+        assert_eq!(real_ip("10.0.0.2", &[]), "10.0.0.2");
+
+        assert!(Cidr::try_from(String::from("10.0.0.0/33")).is_err());
+        assert!(Cidr::try_from(String::from("nope")).is_err());
+    }
+
+    #[test]
+    fn test_request_id() {
+        let a = request_id(&TestRequest::default().to_http_request()).unwrap();
+        let b = request_id(&TestRequest::default().to_http_request()).unwrap();
+        assert_eq!(a.len(), 26);
+        assert_ne!(a, b);
+        assert!(Ulid::from_string(&a).is_ok());
+
+        let req = TestRequest::default()
+            .insert_header(("X-Request-Id", "abc-123"))
+            .to_http_request();
+        assert_eq!(request_id(&req).unwrap(), "abc-123");
+        let req = TestRequest::default()
+            .insert_header(("X-Request-Id", "x".repeat(129)))
+            .to_http_request();
+        assert!(request_id(&req).is_err());
+    }
+}
@@ -69,6 +69,7 @@ mod tests {
                     dir: String::from("./logs/web_hook_test"),
                     secret: String::from("12345"),
                     ua: String::from("foobar"),
+                    ..Default::default()
                 }))
                 .service(get),
         )
@@ -135,6 +136,7 @@ mod tests {
                     dir: String::from("./logs/web_hook_test"),
                     secret: String::from("12345"),
                     ua: String::from("foobar"),
+                    ..Default::default()
                 }))
                 .service(get),
         )
@@ -167,6 +169,7 @@ mod tests {
                     dir: String::from("./logs/web_hook_test"),
                     secret: String::from("12345"),
                     ua: String::from("foobar"),
+                    ..Default::default()
                 }))
                 .service(post),
         )
@@ -244,6 +247,7 @@ mod tests {
                     dir: String::from("./logs/web_hook_test"),
                     secret: String::from("12345"),
                     ua: String::from("foobar"),
+                    ..Default::default()
                 }))
                 .service(post),
         )
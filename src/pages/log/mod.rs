@@ -1,53 +1,144 @@
 mod types;
 
-use actix_web::{error, post, web, Error, Result};
+use actix_multipart::Multipart;
+use actix_web::http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::{error, post, web, Error, HttpRequest, HttpResponse, Result};
 use backtrace::Backtrace;
 use chrono::prelude::*;
-use regex::Regex;
-use std::fs;
-use std::fs::OpenOptions;
-use std::io;
-use std::io::prelude::*;
-use types::{PathParams, QueryParams};
-use web_hook::{AppData, AuthorizedUrl};
+use chrono_tz::Tz;
+use futures_util::StreamExt;
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use types::{Ack, BatchAck, Item, ItemResult, PathParams, QueryParams};
+use web_hook::fields;
+use web_hook::idempotency::{Claim, Pending, REPLAYED_HEADER};
+use web_hook::meta::{self, REQUEST_ID, REQUEST_ID_HEADER};
+use web_hook::sink::{Record, SinkError};
+use web_hook::storage::{Location, LogPath, Sidecar};
+use web_hook::{key_id, AppData, AuthorizedUrl};
+
+// items of one batch, at most
+const MAX_BATCH: usize = 1000;
 
 #[post("/log/{bucket}/{device_id}")]
 pub async fn action(
+    req: HttpRequest,
     app_data: web::Data<AppData>,
     path: web::Path<PathParams>,
     query: web::Query<QueryParams>,
-    bytes: web::Bytes,
+    payload: web::Payload,
     authed: Result<AuthorizedUrl>,
-) -> Result<String, Error> {
+) -> Result<HttpResponse, Error> {
     let bt = Backtrace::new();
     match authed {
-        Ok(_) => match String::from_utf8(bytes.to_vec()) {
-            Ok(text) => {
-                if text.is_empty() {
-                    return Err(error::ErrorBadRequest("missing body"));
-                }
-                if let Err(e) = write_log_line(
-                    app_data.dir.as_str(),
-                    path.bucket.as_str(),
-                    path.device_id.as_str(),
-                    query
-                        .cat
-                        .clone()
-                        .unwrap_or(String::from("unknown"))
-                        .as_str(),
-                    query
-                        .from
-                        .clone()
-                        .unwrap_or(String::from("unknown"))
-                        .as_str(),
-                    text.as_str(),
-                ) {
-                    return Err(error::ErrorInternalServerError(e));
+        Ok(_) => {
+            let log_path = resolve(&app_data, &path)?;
+            let id = meta::request_id(&req).map_err(error::ErrorBadRequest)?;
+            let clock = app_data.config.clock(&log_path.bucket);
+            let now = clock.now();
+            let dir = Path::new(&app_data.dir);
+            let location = app_data.config.layout.locate(
+                dir,
+                &log_path.bucket,
+                &log_path.device_id,
+                &clock,
+                &now,
+            );
+            // what goes to files is neither redacted nor encrypted, so
+            // buckets with "redact" or "encryption" keep bodies inline and
+            // take no attachments
+            let bucket = app_data.config.bucket(&log_path.bucket);
+            let inline_only = bucket.is_some_and(|b| b.redact.is_some() || b.encryption.is_some());
+            let overflow = bucket
+                .and_then(|b| b.max_inline_size)
+                .filter(|_| !inline_only)
+                .map(|limit| (limit, dir, &location));
+            let max = app_data.config.max_body_size(&log_path.bucket);
+            let content_type = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+                .to_ascii_lowercase();
+            let body = if content_type.starts_with("multipart/form-data") {
+                read_multipart(
+                    &req,
+                    payload,
+                    max,
+                    (!inline_only).then_some((dir, &location)),
+                )
+                .await?
+            } else {
+                read_body(&req, payload, max, overflow).await?
+            };
+            if body.size == 0 {
+                return Err(error::ErrorBadRequest("missing body"));
+            }
+            let pending = match claim(&app_data, &req, &log_path, &body.text, &body.digest)? {
+                Claimed::Fresh(pending) => pending,
+                Claimed::Replay(resp) => return Ok(resp),
+            };
+            let mapping = app_data
+                .config
+                .bucket(&log_path.bucket)
+                .and_then(|b| b.fields.clone());
+            let parsed = match (&body.fields, mapping) {
+                (Some(form), mapping) => mapping.unwrap_or_default().split(form.clone()),
+                (None, Some(mapping)) if body.file.is_none() => {
+                    match fields::parse(&content_type, &body.text) {
+                        Some(form) => mapping.split(form),
+                        None => Default::default(),
+                    }
                 }
-                Ok(String::from("ok"))
+                _ => Default::default(),
+            };
+            let mut record = record(&app_data, &req, log_path, now, body.size);
+            record.meta.extend(parsed.meta());
+            record.cat = query
+                .cat
+                .clone()
+                .or(parsed.cat)
+                .unwrap_or(String::from("unknown"));
+            record.from = query
+                .from
+                .clone()
+                .or(parsed.from)
+                .unwrap_or(String::from("unknown"));
+            record.body = parsed.text.unwrap_or(body.text);
+            record.meta.insert(String::from(REQUEST_ID), id.clone());
+            if let Some(file) = &body.file {
+                record
+                    .meta
+                    .insert(String::from("body_file"), file.rel().to_string());
+                record
+                    .meta
+                    .insert(String::from("body_size"), body.size.to_string());
             }
-            Err(e) => Err(error::ErrorInternalServerError(e)),
-        },
+            if !body.files.is_empty() {
+                let rels: Vec<&str> = body.files.iter().map(|f| f.rel()).collect();
+                record
+                    .meta
+                    .insert(String::from("attachments"), rels.join(","));
+            }
+            redact(&app_data, &mut record);
+            let sinks = app_data.sinks.write(&record).await.map_err(sink_error)?;
+            for file in body.file.into_iter().chain(body.files) {
+                file.keep()?;
+            }
+            let (content_type, body) = if wants_json(&req) {
+                let ack = Ack {
+                    id: id.clone(),
+                    stored_at: record.time,
+                    sinks,
+                };
+                ("application/json", serde_json::to_string(&ack)?)
+            } else {
+                ("text/plain; charset=utf-8", String::from("ok"))
+            };
+            Ok(reply(StatusCode::OK, pending, &id, content_type, body))
+        }
         Err(e) => {
             println!("{:?}", bt);
             Err(e)
@@ -55,41 +146,446 @@ pub async fn action(
     }
 }
 
-fn write_log_line(
-    dir: &str,
-    bucket: &str,
-    device_id: &str,
-    cat: &str,
-    from: &str,
-    body: &str,
-) -> Result<(), io::Error> {
-    let utc: DateTime<Utc> = Utc::now();
-    let date_str: String = utc.format("%Y%m%d").to_string();
-    let time_str: String = utc.format("%+").to_string();
-    let log_path = format!("{}/{}/{}", dir, bucket, device_id);
-    let log_file = format!("{}/{}.log", log_path, date_str);
+// Many records of a device in one post, a JSON array or NDJSON. Items are
+// written in order up to the first failure, not atomically, the answer says
+// what became of each. With an idempotency key a retry resumes after the
+// last written item, with the same ids. Item times go to "event_time".
+#[post("/log/{bucket}/{device_id}/batch")]
+pub async fn batch(
+    req: HttpRequest,
+    app_data: web::Data<AppData>,
+    path: web::Path<PathParams>,
+    payload: web::Payload,
+    authed: Result<AuthorizedUrl>,
+) -> Result<HttpResponse, Error> {
+    match authed {
+        Ok(_) => {
+            let log_path = resolve(&app_data, &path)?;
+            let max = app_data.config.max_body_size(&log_path.bucket);
+            let body = read_body(&req, payload, max, None).await?;
+            let items = parse_batch(&body.text).map_err(error::ErrorBadRequest)?;
+            let id = meta::request_id(&req).map_err(error::ErrorBadRequest)?;
 
-    // mkdir
-    fs::create_dir_all(log_path)?;
+            let mut results: Vec<ItemResult> = items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| ItemResult::new(index, item))
+                .collect();
+            let mut ack = BatchAck {
+                id: id.clone(),
+                stored_at: None,
+                accepted: 0,
+                items: Vec::new(),
+            };
+            if results.iter().any(|r| r.error.is_some()) {
+                ack.items = results;
+                return Ok(HttpResponse::BadRequest()
+                    .insert_header((REQUEST_ID_HEADER, id))
+                    .json(ack));
+            }
 
-    // write log
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(log_file)
-        .unwrap();
+            let mut pending = match claim(&app_data, &req, &log_path, &body.text, &body.digest)? {
+                Claimed::Fresh(pending) => pending,
+                Claimed::Replay(resp) => return Ok(resp),
+            };
+            let resume = pending.as_ref().map_or(0, |p| p.resume());
+            let mut id = id;
+            if let Some(pending) = pending.as_mut() {
+                match pending.resumed() {
+                    Some((first, parts)) if parts.len() == results.len() => {
+                        id = first.to_string();
+                        for (result, part) in results.iter_mut().zip(parts) {
+                            result.id = part.clone();
+                        }
+                    }
+                    _ => pending.start(&id, results.iter().map(|r| r.id.clone()).collect()),
+                }
+            }
+            ack.id = id.clone();
+            let now = app_data.config.clock(&log_path.bucket).now();
+            let base = record(&app_data, &req, log_path, now, body.size);
+            ack.stored_at = Some(base.time);
+            let mut status = StatusCode::OK;
+            for (index, (item, result)) in items.into_iter().zip(results.iter_mut()).enumerate() {
+                let item = item.unwrap();
+                if index < resume {
+                    // written by an earlier try
+                    result.status = "ok";
+                    ack.accepted += 1;
+                    continue;
+                }
+                if status != StatusCode::OK {
+                    result.status = "skipped";
+                    continue;
+                }
+                let mut record = base.clone();
+                record.cat = item.cat.unwrap_or(String::from("unknown"));
+                record.from = item.from.unwrap_or(String::from("unknown"));
+                record.body = item.body;
+                record
+                    .meta
+                    .insert(String::from(REQUEST_ID), result.id.clone());
+                record.meta.insert(String::from("batch_id"), id.clone());
+                if let Some(time) = item.time {
+                    record
+                        .meta
+                        .insert(String::from("event_time"), time.to_rfc3339());
+                }
+                redact(&app_data, &mut record);
+                match app_data.sinks.write(&record).await {
+                    Ok(sinks) => {
+                        result.status = "ok";
+                        result.sinks = sinks;
+                        ack.accepted += 1;
+                        if let Some(pending) = pending.as_mut() {
+                            pending
+                                .progress(index + 1)
+                                .await
+                                .map_err(error::ErrorInternalServerError)?;
+                        }
+                    }
+                    Err(e) => {
+                        status = match e {
+                            SinkError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                            SinkError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                        };
+                        result.status = "failed";
+                        result.error = Some(e.to_string());
+                    }
+                }
+            }
+            ack.items = results;
+            let body = serde_json::to_string(&ack)?;
+            Ok(reply(status, pending, &id, "application/json", body))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Items of a batch body, each parsed on its own so errors point at one.
+fn parse_batch(text: &str) -> Result<Vec<Result<Item, String>>, String> {
+    let values: Vec<serde_json::Value> = if text.trim_start().starts_with('[') {
+        serde_json::from_str(text).map_err(|e| e.to_string())?
+    } else {
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))
+            })
+            .collect::<Result<_, _>>()?
+    };
+    if values.is_empty() {
+        return Err(String::from("empty batch"));
+    }
+    if values.len() > MAX_BATCH {
+        return Err(format!(
+            "{} items, at most {} per batch",
+            values.len(),
+            MAX_BATCH
+        ));
+    }
+    Ok(values
+        .into_iter()
+        .map(|value| {
+            let item: Item = serde_json::from_value(value).map_err(|e| e.to_string())?;
+            if item.body.is_empty() {
+                return Err(String::from("missing body"));
+            }
+            if item
+                .id
+                .as_ref()
+                .is_some_and(|id| id.is_empty() || id.len() > 128)
+            {
+                return Err(String::from("bad id, 1 to 128 chars"));
+            }
+            Ok(item)
+        })
+        .collect())
+}
 
-    // escape data
-    let re = Regex::new(r"[\r\n]").unwrap();
-    let data = re.replace_all(body, "||");
+// A request body as read, only its start when the rest went to a file.
+// Multipart bodies come as their fields, `text` is them as a JSON object.
+struct Body {
+    text: String,
+    size: u64,
+    // hex SHA-256 of all of it
+    digest: String,
+    file: Option<Sidecar>,
+    fields: Option<Vec<(String, String)>>,
+    // file parts of a multipart body
+    files: Vec<Sidecar>,
+}
+
+fn too_large(max: u64) -> Error {
+    error::ErrorPayloadTooLarge(format!("body over {} bytes", max))
+}
+
+// Refuse a body by its Content-Length before reading any of it.
+fn check_length(req: &HttpRequest, max: u64) -> Result<(), Error> {
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match declared {
+        Some(n) if n > max => Err(too_large(max)),
+        _ => Ok(()),
+    }
+}
 
-    // log line fmt
-    writeln!(
+// Read the body as it streams in, refused with 413 beyond `max` bytes. With
+// `overflow`, a body over its inline limit goes to a sidecar file as a whole
+// and only the first bytes up to the limit stay in memory.
+async fn read_body(
+    req: &HttpRequest,
+    mut payload: web::Payload,
+    max: u64,
+    overflow: Option<(u64, &Path, &Location)>,
+) -> Result<Body, Error> {
+    check_length(req, max)?;
+    let mut inline = Vec::new();
+    let mut size = 0;
+    let mut hasher = Sha256::new();
+    let mut file: Option<Sidecar> = None;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if size > max {
+            return Err(too_large(max));
+        }
+        hasher.update(&chunk);
+        match (&mut file, overflow) {
+            (Some(file), _) => file.write(chunk).await?,
+            (None, Some((limit, dir, location))) if size > limit => {
+                let mut sidecar = Sidecar::create(dir, location, "body").await?;
+                sidecar.write(web::Bytes::copy_from_slice(&inline)).await?;
+                sidecar.write(chunk.clone()).await?;
+                let rest = limit as usize - inline.len();
+                inline.extend_from_slice(&chunk[..rest]);
+                file = Some(sidecar);
+            }
+            _ => inline.extend_from_slice(&chunk),
+        }
+    }
+
+    let text = match file {
+        // cut at a char boundary
+        Some(_) => {
+            let valid = match std::str::from_utf8(&inline) {
+                Ok(_) => inline.len(),
+                Err(e) => e.valid_up_to(),
+            };
+            inline.truncate(valid);
+            String::from_utf8(inline).unwrap()
+        }
+        None => String::from_utf8(inline).map_err(error::ErrorInternalServerError)?,
+    };
+    Ok(Body {
+        text,
+        size,
+        digest: hex::encode(hasher.finalize()),
         file,
-        "[{}]\t{}|{}\t####\t{}\t{}\t{}",
-        time_str, device_id, bucket, cat, from, data
-    )
+        fields: None,
+        files: Vec::new(),
+    })
+}
+
+// Read a multipart body, file parts go to attachments as they stream in and
+// the other parts become fields. Without `attach`, file parts are refused.
+async fn read_multipart(
+    req: &HttpRequest,
+    payload: web::Payload,
+    max: u64,
+    attach: Option<(&Path, &Location)>,
+) -> Result<Body, Error> {
+    check_length(req, max)?;
+    let mut multipart = Multipart::new(req.headers(), payload);
+    let mut size = 0;
+    let mut hasher = Sha256::new();
+    let mut fields = Vec::new();
+    let mut files = Vec::new();
+    while let Some(part) = multipart.next().await {
+        let mut part = part?;
+        let disposition = part.content_disposition();
+        let name = disposition.get_name().unwrap_or("").to_string();
+        let mut file = match (disposition.get_filename(), attach) {
+            (Some(filename), Some((dir, location))) => {
+                Some(Sidecar::create(dir, location, filename).await?)
+            }
+            (Some(_), None) => {
+                return Err(error::ErrorBadRequest(
+                    "no attachments for buckets with redaction or encryption",
+                ))
+            }
+            (None, _) => None,
+        };
+        let mut value = Vec::new();
+        while let Some(chunk) = part.next().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            if size > max {
+                return Err(too_large(max));
+            }
+            hasher.update(&chunk);
+            match &mut file {
+                Some(file) => file.write(chunk).await?,
+                None => value.extend_from_slice(&chunk),
+            }
+        }
+        match file {
+            Some(file) => files.push(file),
+            None => fields.push((
+                name,
+                String::from_utf8(value).map_err(error::ErrorBadRequest)?,
+            )),
+        }
+    }
+
+    let mut object = serde_json::Map::new();
+    for (name, value) in fields.iter() {
+        if !object.contains_key(name) {
+            object.insert(name.clone(), value.clone().into());
+        }
+    }
+    Ok(Body {
+        text: serde_json::Value::Object(object).to_string(),
+        size,
+        digest: hex::encode(hasher.finalize()),
+        file: None,
+        fields: Some(fields),
+        files,
+    })
+}
+
+fn resolve(app_data: &AppData, path: &PathParams) -> Result<LogPath, Error> {
+    app_data
+        .paths
+        .resolve(path.bucket.as_str(), path.device_id.as_str())
+        .and_then(|p| app_data.config.layout.check(&p).map(|_| p))
+        .map_err(error::ErrorBadRequest)
+}
+
+enum Claimed<'a> {
+    Fresh(Option<Pending<'a>>),
+    Replay(HttpResponse),
+}
+
+// Claim the idempotency key of the request, if it has one. A key seen
+// before gets its first response back.
+fn claim<'a>(
+    app_data: &'a AppData,
+    req: &HttpRequest,
+    log_path: &LogPath,
+    text: &str,
+    digest: &str,
+) -> Result<Claimed<'a>, Error> {
+    let store = match &app_data.idempotency {
+        Some(store) => store,
+        None => return Ok(Claimed::Fresh(None)),
+    };
+    let key = match store.key(req, text).map_err(error::ErrorBadRequest)? {
+        Some(key) => key,
+        None => return Ok(Claimed::Fresh(None)),
+    };
+    let scope = format!("{}/{}", log_path.bucket, log_path.device_id);
+    match store
+        .claim(&scope, &key, digest, Utc::now())
+        .map_err(error::ErrorInternalServerError)?
+    {
+        Claim::New(pending) => Ok(Claimed::Fresh(Some(pending))),
+        Claim::Replay(stored) => Ok(Claimed::Replay(
+            HttpResponse::Ok()
+                .insert_header((REQUEST_ID_HEADER, stored.request_id))
+                .insert_header((REPLAYED_HEADER, "true"))
+                .content_type(stored.content_type)
+                .body(stored.body),
+        )),
+        Claim::InFlight => Err(error::ErrorConflict(
+            "a request with this idempotency key is in progress",
+        )),
+        Claim::Mismatch => Err(error::ErrorUnprocessableEntity(
+            "idempotency key used with another body",
+        )),
+    }
+}
+
+// A record stamped `now`, with the request metadata its bucket asks for.
+fn record(
+    app_data: &AppData,
+    req: &HttpRequest,
+    log_path: LogPath,
+    now: DateTime<Tz>,
+    body_size: u64,
+) -> Record {
+    let meta = match app_data
+        .config
+        .bucket(&log_path.bucket)
+        .and_then(|b| b.capture.as_ref())
+    {
+        Some(capture) => capture.capture(
+            req,
+            body_size,
+            &key_id(&app_data.secret),
+            &app_data.instance,
+        ),
+        None => Default::default(),
+    };
+    Record {
+        time: now.with_timezone(&now.offset().fix()),
+        bucket: log_path.bucket,
+        device_id: log_path.device_id,
+        cat: String::new(),
+        from: String::new(),
+        body: String::new(),
+        meta,
+    }
+}
+
+// Scrub what the bucket asks for, see `redact`.
+fn redact(app_data: &AppData, record: &mut Record) {
+    if let Some(redactor) = app_data
+        .config
+        .bucket(&record.bucket)
+        .and_then(|b| b.redact.as_ref())
+    {
+        redactor.apply(record);
+    }
+}
+
+// Only successful responses are kept for the idempotency key, a failed
+// request may be retried.
+fn reply(
+    status: StatusCode,
+    pending: Option<Pending>,
+    id: &str,
+    content_type: &str,
+    body: String,
+) -> HttpResponse {
+    if let (Some(pending), true) = (pending, status.is_success()) {
+        if let Err(e) = pending.save(id, content_type, &body) {
+            warn!("response for idempotency key not kept: {}", e);
+        }
+    }
+    HttpResponse::build(status)
+        .insert_header((REQUEST_ID_HEADER, id))
+        .content_type(content_type)
+        .body(body)
+}
+
+fn wants_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"))
+}
+
+// Unavailable sinks ask the device to retry later.
+fn sink_error(e: SinkError) -> Error {
+    match e {
+        SinkError::Unavailable(_) => error::ErrorServiceUnavailable(e),
+        SinkError::Failed(_) => error::ErrorInternalServerError(e),
+    }
 }
 
 #[cfg(test)]
@@ -101,18 +597,41 @@ mod tests {
         http::{self, header::USER_AGENT},
         test, App,
     };
+    use std::path::Path;
+    use web_hook::sink::Sinks;
     use web_hook::AppData;
 
+    fn app_data() -> AppData {
+        app_data_in("./logs/web_hook_test", Default::default())
+    }
+
+    fn app_data_in(dir: &str, config: web_hook::config::Config) -> AppData {
+        AppData {
+            sinks: Sinks::build(&config, Path::new(dir), Default::default()).unwrap(),
+            dir: dir.to_string(),
+            secret: String::from("12345"),
+            ua: String::from("foobar"),
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn log_lines(dir: &str, bucket: &str) -> Vec<String> {
+        let path = std::fs::read_dir(Path::new(dir).join(bucket).join("100"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == "log"))
+            .unwrap();
+        let text = std::fs::read_to_string(path).unwrap();
+        text.lines().map(String::from).collect()
+    }
+
     #[actix_web::test]
     async fn test_page_log_action_error() {
         // Start `action` service
         let app = test::init_service(
             App::new()
-                .app_data(web::Data::new(AppData {
-                    dir: String::from("./logs/web_hook_test"),
-                    secret: String::from("12345"),
-                    ua: String::from("foobar"),
-                }))
+                .app_data(web::Data::new(app_data()))
                 .service(action),
         )
         .await;
@@ -168,6 +687,30 @@ mod tests {
             assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
         }
 
+        {
+            // 400 - bad bucket
+            let req = test::TestRequest::post()
+                .uri("/log/%2E%2E/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+                .insert_header((USER_AGENT, "foobar"))
+                .set_payload(String::from("hi"))
+                .to_request();
+
+            let resp = app.call(req).await.unwrap();
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        }
+
+        {
+            // 400 - bad device_id
+            let req = test::TestRequest::post()
+                .uri("/log/sms/a%2Fb?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+                .insert_header((USER_AGENT, "foobar"))
+                .set_payload(String::from("hi"))
+                .to_request();
+
+            let resp = app.call(req).await.unwrap();
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        }
+
         {
             // 400 - no request body
             let req = test::TestRequest::post()
@@ -185,11 +728,7 @@ mod tests {
         // Start `action` service
         let app = test::init_service(
             App::new()
-                .app_data(web::Data::new(AppData {
-                    dir: String::from("./logs/web_hook_test"),
-                    secret: String::from("12345"),
-                    ua: String::from("foobar"),
-                }))
+                .app_data(web::Data::new(app_data()))
                 .service(action),
         )
         .await;
@@ -208,5 +747,461 @@ mod tests {
             let body_bytes = to_bytes(resp.into_body()).await.unwrap();
             assert_eq!(body_bytes, r##"ok"##);
         }
+
+        {
+            // 200 - JSON ack with the id the client chose
+            let req = test::TestRequest::post()
+                .uri("/log/sms/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+                .insert_header((USER_AGENT, "foobar"))
+                .insert_header((http::header::ACCEPT, "application/json"))
+                .insert_header(("X-Request-Id", "abc-123"))
+                .set_payload(String::from("hi"))
+                .to_request();
+
+            let resp = app.call(req).await.unwrap();
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-123");
+
+            let body_bytes = to_bytes(resp.into_body()).await.unwrap();
+            let ack: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+            assert_eq!(ack["id"], "abc-123");
+            assert!(ack["stored_at"].is_string());
+            assert_eq!(ack["sinks"][0]["sink"], "file");
+            assert_eq!(ack["sinks"][0]["spooled"], false);
+        }
+
+        {
+            // 200 - a new ULID otherwise
+            let req = test::TestRequest::post()
+                .uri("/log/sms/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+                .insert_header((USER_AGENT, "foobar"))
+                .set_payload(String::from("hi"))
+                .to_request();
+
+            let resp = app.call(req).await.unwrap();
+            let id = resp.headers().get(REQUEST_ID_HEADER).unwrap();
+            assert_eq!(id.len(), 26);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_page_log_action_capture() {
+        let dir = String::from("./logs/web_hook_test/capture");
+        let _ = std::fs::remove_dir_all(&dir);
+        let config: web_hook::config::Config = serde_json::from_value(serde_json::json!({
+            "buckets": {
+                "sms": {
+                    "capture": {
+                        "real_ip": true,
+                        "proxies": ["10.0.0.0/8"],
+                        "headers": ["X-App-Version"],
+                        "body_size": true,
+                        "key_id": true,
+                        "instance": true,
+                    }
+                }
+            }
+        }))
+        .unwrap();
+        let data = AppData {
+            instance: String::from("web-1"),
+            ..app_data_in(&dir, config)
+        };
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(data)).service(action)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/log/sms/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header((USER_AGENT, "foobar"))
+            .insert_header(("X-Forwarded-For", "1.1.1.1"))
+            .insert_header(("X-App-Version", "3.1"))
+            .insert_header(("X-Request-Id", "r-1"))
+            .set_payload(String::from("hi"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let log = std::fs::read_dir(Path::new(&dir).join("sms/100"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let text = std::fs::read_to_string(log).unwrap();
+        let expected = format!(
+            "\t{{\"body_size\":\"2\",\"header.x-app-version\":\"3.1\",\"instance\":\"web-1\",\
+             \"key_id\":\"{}\",\"real_ip\":\"1.1.1.1\",\"request_id\":\"r-1\"}}\thi\n",
+            key_id("12345")
+        );
+        assert!(text.ends_with(&expected), "{}", text);
+    }
+
+    #[actix_web::test]
+    async fn test_page_log_action_idempotent() {
+        let dir = String::from("./logs/web_hook_test/idempotent");
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = web_hook::idempotency::IdempotencyConfig {
+            dir: None,
+            ttl: 60,
+            field: None,
+        };
+        let data = AppData {
+            idempotency: Some(std::sync::Arc::new(
+                web_hook::idempotency::Store::open(&config, Path::new(&dir)).unwrap(),
+            )),
+            ..app_data_in(&dir, Default::default())
+        };
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(data)).service(action)).await;
+        let post = |body: &str| {
+            test::TestRequest::post()
+                .uri("/log/sms/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+                .insert_header((USER_AGENT, "foobar"))
+                .insert_header((http::header::ACCEPT, "application/json"))
+                .insert_header(("Idempotency-Key", "k1"))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        let resp = app.call(post("hi")).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let id = resp.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+        let first = to_bytes(resp.into_body()).await.unwrap();
+
+        // the retry gets the same answer, nothing is written
+        let resp = app.call(post("hi")).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), id);
+        assert_eq!(resp.headers().get(REPLAYED_HEADER).unwrap(), "true");
+        assert_eq!(to_bytes(resp.into_body()).await.unwrap(), first);
+
+        let resp = app.call(post("bye")).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+
+        let log = std::fs::read_dir(Path::new(&dir).join("sms/100"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert_eq!(std::fs::read_to_string(log).unwrap().lines().count(), 1);
+    }
+
+    // Refuses bodies saying "boom" while down, keeps the others.
+    #[derive(Debug, Default)]
+    struct Picky {
+        up: std::sync::atomic::AtomicBool,
+        bodies: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl web_hook::sink::LogSink for Picky {
+        async fn write(&self, record: &Record) -> Result<(), SinkError> {
+            if record.body == "boom" && !self.up.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(SinkError::Unavailable(String::from("boom")));
+            }
+            self.bodies.lock().unwrap().push(record.body.clone());
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_page_log_batch() {
+        let dir = String::from("./logs/web_hook_test/batch");
+        let _ = std::fs::remove_dir_all(&dir);
+        let config: web_hook::config::Config = serde_json::from_value(serde_json::json!({
+            "buckets": { "otp": { "sinks": [{ "name": "picky" }] } }
+        }))
+        .unwrap();
+        let idempotency = web_hook::idempotency::IdempotencyConfig {
+            dir: None,
+            ttl: 60,
+            field: None,
+        };
+        let mut data = AppData {
+            idempotency: Some(std::sync::Arc::new(
+                web_hook::idempotency::Store::open(&idempotency, Path::new(&dir)).unwrap(),
+            )),
+            ..app_data_in(&dir, config)
+        };
+        let picky = std::sync::Arc::new(Picky::default());
+        data.sinks.insert("picky", picky.clone());
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(data)).service(batch)).await;
+        let post = |bucket: &str, body: &str| {
+            test::TestRequest::post()
+                .uri(&format!(
+                    "/log/{}/100/batch?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns",
+                    bucket
+                ))
+                .insert_header((USER_AGENT, "foobar"))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+        let json = |bytes: web::Bytes| serde_json::from_slice::<serde_json::Value>(&bytes).unwrap();
+        let log = || {
+            let path = std::fs::read_dir(Path::new(&dir).join("sms/100"))
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .path();
+            std::fs::read_to_string(path).unwrap()
+        };
+
+        // NDJSON, in order
+        let body = "{\"cat\":\"text\",\"from\":\"10086\",\"body\":\"1\",\"time\":\"2022-04-01T08:00:00+08:00\"}\n\
+                    {\"id\":\"m-2\",\"body\":\"2\"}\n";
+        let resp = app.call(post("sms", body)).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let ack = json(to_bytes(resp.into_body()).await.unwrap());
+        assert_eq!(ack["accepted"], 2);
+        assert_eq!(ack["items"][0]["status"], "ok");
+        assert_eq!(ack["items"][0]["id"].as_str().unwrap().len(), 26);
+        assert_eq!(ack["items"][1]["id"], "m-2");
+        let text = log();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\ttext\t10086\t"));
+        assert!(lines[0].contains("\"event_time\":\"2022-04-01T08:00:00+08:00\""));
+        assert!(lines[0].ends_with("\t1"));
+        assert!(lines[1].contains("\"request_id\":\"m-2\""));
+
+        // one bad item, nothing written
+        let resp = app
+            .call(post("sms", "[{\"body\":\"3\"}, {\"cat\":\"text\"}]"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let ack = json(to_bytes(resp.into_body()).await.unwrap());
+        assert_eq!(ack["items"][0]["status"], "skipped");
+        assert_eq!(ack["items"][1]["status"], "invalid");
+        assert_eq!(log().lines().count(), 2);
+
+        let resp = app.call(post("sms", "[{")).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+        // a failing item stops the batch
+        let resp = app
+            .call(post(
+                "otp",
+                "[{\"body\":\"a\"}, {\"body\":\"boom\"}, {\"body\":\"c\"}]",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        let ack = json(to_bytes(resp.into_body()).await.unwrap());
+        assert_eq!(ack["accepted"], 1);
+        let statuses: Vec<&str> = ack["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(statuses, vec!["ok", "failed", "skipped"]);
+
+        // a retry with the key of a batch that failed halfway resumes it
+        let keyed = |body: &str| {
+            test::TestRequest::post()
+                .uri("/log/otp/100/batch?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+                .insert_header((USER_AGENT, "foobar"))
+                .insert_header(("Idempotency-Key", "b-1"))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+        let body = "[{\"body\":\"x\"}, {\"body\":\"boom\"}, {\"body\":\"y\"}]";
+        let resp = app.call(keyed(body)).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        let first = json(to_bytes(resp.into_body()).await.unwrap());
+        picky.up.store(true, std::sync::atomic::Ordering::SeqCst);
+        let resp = app.call(keyed(body)).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let ack = json(to_bytes(resp.into_body()).await.unwrap());
+        assert_eq!(ack["accepted"], 3);
+        // the ids of the first try, as stored with "x"
+        assert_eq!(ack["id"], first["id"]);
+        assert_eq!(ack["items"][0]["id"], first["items"][0]["id"]);
+        assert_eq!(ack["items"][2]["id"], first["items"][2]["id"]);
+        let resp = app.call(keyed(body)).await.unwrap();
+        assert_eq!(resp.headers().get(REPLAYED_HEADER).unwrap(), "true");
+        assert_eq!(*picky.bodies.lock().unwrap(), vec!["a", "x", "boom", "y"]);
+    }
+
+    #[actix_web::test]
+    async fn test_page_log_action_body_size() {
+        let dir = String::from("./logs/web_hook_test/body_size");
+        let _ = std::fs::remove_dir_all(&dir);
+        let config: web_hook::config::Config = serde_json::from_value(serde_json::json!({
+            "buckets": {
+                "otp": { "max_body_size": 8 },
+                "sms": { "max_inline_size": 4 },
+            }
+        }))
+        .unwrap();
+        let data = app_data_in(&dir, config);
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(data)).service(action)).await;
+        let post = |bucket: &str, body: &str| {
+            test::TestRequest::post()
+                .uri(&format!(
+                    "/log/{}/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns",
+                    bucket
+                ))
+                .insert_header((USER_AGENT, "foobar"))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        let resp = app.call(post("otp", "123456789")).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::PAYLOAD_TOO_LARGE);
+        let resp = app.call(post("otp", "12345678")).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        // the start stays inline, all of it goes next to the log
+        let resp = app.call(post("sms", "你好 world")).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let log = std::fs::read_dir(Path::new(&dir).join("sms/100"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == "log"))
+            .unwrap();
+        let line = std::fs::read_to_string(log).unwrap();
+        assert!(line.ends_with("}\t你\n"), "{}", line);
+        let meta: serde_json::Value =
+            serde_json::from_str(line.split('\t').nth(5).unwrap()).unwrap();
+        assert_eq!(meta["body_size"], "12");
+        let file = Path::new(&dir).join(meta["body_file"].as_str().unwrap());
+        assert_eq!(std::fs::read_to_string(file).unwrap(), "你好 world");
+        let resp = app.call(post("sms", "hi")).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_page_log_action_fields() {
+        let dir = String::from("./logs/web_hook_test/fields");
+        let _ = std::fs::remove_dir_all(&dir);
+        let config: web_hook::config::Config = serde_json::from_value(serde_json::json!({
+            "buckets": {
+                "sms": { "fields": { "cat": "type", "from": "sender", "text": "content" } }
+            }
+        }))
+        .unwrap();
+        let data = app_data_in(&dir, config);
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(data)).service(action)).await;
+        let post = |bucket: &str, content_type: &str, body: &str| {
+            test::TestRequest::post()
+                .uri(&format!(
+                    "/log/{}/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns",
+                    bucket
+                ))
+                .insert_header((USER_AGENT, "foobar"))
+                .insert_header((http::header::CONTENT_TYPE, content_type))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+        let form = "type=text&sender=10086&content=hi+there&sim=2";
+        let resp = app
+            .call(post("sms", "application/x-www-form-urlencoded", form))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let json = r#"{"sender": "10010", "content": "yo"}"#;
+        let resp = app
+            .call(post("sms", "application/json", json))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let lines = log_lines(&dir, "sms");
+        let fields: Vec<&str> = lines[0].split('\t').collect();
+        assert_eq!(&fields[3..5], &["text", "10086"]);
+        assert!(fields[5].contains("\"field.sim\":\"2\""));
+        assert_eq!(fields[6], "hi there");
+        assert!(lines[1].contains("\tunknown\t10010\t"));
+        assert!(lines[1].ends_with("\tyo"));
+
+        // file parts are kept next to the log
+        let multipart = "--XyZ\r\n\
+            Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+            hello\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"a.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\r\n\
+            JPEG\r\n\
+            --XyZ--\r\n";
+        let resp = app
+            .call(post("gps", "multipart/form-data; boundary=XyZ", multipart))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let line = &log_lines(&dir, "gps")[0];
+        assert!(line.ends_with("\t{\"note\":\"hello\"}"), "{}", line);
+        let meta: serde_json::Value =
+            serde_json::from_str(line.split('\t').nth(5).unwrap()).unwrap();
+        let attachment = meta["attachments"].as_str().unwrap();
+        assert!(attachment.ends_with("-a.jpg"));
+        let file = Path::new(&dir).join(attachment);
+        assert_eq!(std::fs::read_to_string(file).unwrap(), "JPEG");
+    }
+
+    #[actix_web::test]
+    async fn test_page_log_action_redact() {
+        let dir = String::from("./logs/web_hook_test/redact");
+        let _ = std::fs::remove_dir_all(&dir);
+        let config: web_hook::config::Config = serde_json::from_value(serde_json::json!({
+            "buckets": {
+                "sms": {
+                    "max_inline_size": 16,
+                    "redact": { "rules": [{ "detector": "phone" }, { "detector": "cn_id" }] }
+                }
+            }
+        }))
+        .unwrap();
+        let data = app_data_in(&dir, config);
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(data)).service(action)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/log/sms/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns&from=13812345678")
+            .insert_header((USER_AGENT, "foobar"))
+            .set_payload(String::from("身份证11010519491231002X"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+
+        let line = &log_lines(&dir, "sms")[0];
+        let fields: Vec<&str> = line.split('\t').collect();
+        assert_eq!(fields[4], "138****5678");
+        assert_eq!(fields[6], "身份证110***********002X");
+        let meta: serde_json::Value = serde_json::from_str(fields[5]).unwrap();
+        assert_eq!(meta["redactions"], "2");
+
+        // over the inline size the body still stays in the record, redacted
+        let req = test::TestRequest::post()
+            .uri("/log/sms/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+            .insert_header((USER_AGENT, "foobar"))
+            .set_payload(format!("{} call 13912345678", "x".repeat(32)))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let line = &log_lines(&dir, "sms")[1];
+        assert!(line.ends_with("call 139****5678"), "{}", line);
+        assert!(!line.contains("body_file"));
+
+        // attachments can not be redacted
+        let multipart = "--XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"a.jpg\"\r\n\r\n\
+            13812345678\r\n\
+            --XyZ--\r\n";
+        let req = test::TestRequest::post()
+            .uri("/log/sms/100?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+            .insert_header((USER_AGENT, "foobar"))
+            .insert_header((CONTENT_TYPE, "multipart/form-data; boundary=XyZ"))
+            .set_payload(multipart)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(log_lines(&dir, "sms").len(), 2);
     }
 }
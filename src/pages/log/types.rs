@@ -1,4 +1,7 @@
+use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+use web_hook::sink::Delivery;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PathParams {
@@ -11,3 +14,67 @@ pub struct QueryParams {
     pub cat: Option<String>,
     pub from: Option<String>,
 }
+
+// JSON reply for clients that accept it, instead of "ok".
+#[derive(Debug, Serialize)]
+pub struct Ack {
+    pub id: String,
+    pub stored_at: DateTime<FixedOffset>,
+    pub sinks: Vec<Delivery>,
+}
+
+// One record of a batch.
+#[derive(Debug, Deserialize)]
+pub struct Item {
+    // request id of the record, a new ULID if missing
+    pub id: Option<String>,
+    pub cat: Option<String>,
+    pub from: Option<String>,
+    pub body: String,
+    // when it happened on the device
+    pub time: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ItemResult {
+    pub index: usize,
+    pub id: String,
+    // ok, failed, skipped or invalid
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sinks: Vec<Delivery>,
+}
+
+impl ItemResult {
+    pub fn new(index: usize, item: &Result<Item, String>) -> Self {
+        let (id, error) = match item {
+            Ok(item) => (
+                item.id.clone().unwrap_or_else(|| Ulid::new().to_string()),
+                None,
+            ),
+            Err(e) => (String::new(), Some(e.clone())),
+        };
+        ItemResult {
+            index,
+            id,
+            status: if error.is_some() {
+                "invalid"
+            } else {
+                "skipped"
+            },
+            error,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchAck {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_at: Option<DateTime<FixedOffset>>,
+    pub accepted: usize,
+    pub items: Vec<ItemResult>,
+}
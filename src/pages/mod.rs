@@ -1,2 +1,3 @@
 pub mod hello;
 pub mod log;
+pub mod status;
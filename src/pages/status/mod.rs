@@ -0,0 +1,66 @@
+use actix_web::{get, web, Error, HttpResponse, Result};
+use web_hook::{AppData, AuthorizedUrl};
+
+// Health of every sink and the depth of the spool.
+#[get("/status")]
+pub async fn get(
+    app_data: web::Data<AppData>,
+    authed: Result<AuthorizedUrl>,
+) -> Result<HttpResponse, Error> {
+    match authed {
+        Ok(_) => Ok(HttpResponse::Ok().json(app_data.sinks.status().await)),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        body::to_bytes,
+        dev::Service,
+        http::{self, header::USER_AGENT},
+        test, App,
+    };
+    use std::path::Path;
+    use web_hook::config::Config;
+    use web_hook::sink::Sinks;
+
+    #[actix_web::test]
+    async fn test_page_status() {
+        let dir = String::from("./logs/web_hook_test/status");
+        let config: Config = serde_json::from_str(r#"{ "spool": { "max_size": 1024 } }"#).unwrap();
+        let app_data = AppData {
+            sinks: Sinks::build(&config, Path::new(&dir), Default::default()).unwrap(),
+            dir,
+            secret: String::from("12345"),
+            ua: String::from("foobar"),
+            ..Default::default()
+        };
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(app_data)).service(get)).await;
+
+        {
+            // 400 - not authorized
+            let req = test::TestRequest::get().uri("/status").to_request();
+            let resp = app.call(req).await.unwrap();
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        }
+
+        {
+            // 200 - with correct UA
+            let req = test::TestRequest::get()
+                .uri("/status?ts=123&code=sGUTG_BJFh9DRUcxsnMb0DyOq6iO09uCHonwLyvWGns")
+                .insert_header((USER_AGENT, "foobar"))
+                .to_request();
+            let resp = app.call(req).await.unwrap();
+            assert_eq!(resp.status(), http::StatusCode::OK);
+
+            let body_bytes = to_bytes(resp.into_body()).await.unwrap();
+            let status: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+            assert_eq!(status["sinks"]["file"]["ok"], true);
+            assert_eq!(status["spool"]["depth"], 0);
+            assert_eq!(status["spool"]["max_size"], 1024);
+        }
+    }
+}
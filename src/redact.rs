@@ -0,0 +1,386 @@
+use crate::fields::FIELD_PREFIX;
+use crate::sink::Record;
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::convert::TryFrom;
+use std::env;
+
+/**
+ * Personal data scrubbed from records before any sink sees them. Rules of a
+ * bucket run over `from`, the body and the "field.*" meta:
+ *
+ *   "buckets": {
+ *     "sms": {
+ *       "redact": {
+ *         "rules": [
+ *           { "detector": "phone" },
+ *           { "detector": "cn_id", "mode": "hash" },
+ *           { "name": "order", "pattern": "ORD-[0-9]+", "mode": "drop" }
+ *         ]
+ *       }
+ *     }
+ *   }
+ *
+ * Modes: "mask" stars out the match but for a few chars at either end,
+ * "hash" puts `[name:hmac]` in its place, the same for the same value so
+ * records still join, and "drop" cuts it out. The hash key is "key" or
+ * $WEB_HOOK_REDACT_KEY. When rules overlap the earlier one wins. Records
+ * keep the number of redactions as the "redactions" meta. Nothing of such a
+ * bucket goes to files: bodies over "max_inline_size" stay in the record and
+ * attachments are refused.
+ */
+pub const KEY_ENV: &str = "WEB_HOOK_REDACT_KEY";
+pub const REDACTIONS: &str = "redactions";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Detector {
+    // mainland mobile numbers, with or without the +86 country code
+    Phone,
+    // 18 char resident ID numbers, checksum verified
+    CnId,
+    Email,
+    // 13 to 19 digits passing the Luhn check, spaces or dashes between
+    BankCard,
+}
+
+impl Detector {
+    fn name(&self) -> &'static str {
+        match self {
+            Detector::Phone => "phone",
+            Detector::CnId => "cn_id",
+            Detector::Email => "email",
+            Detector::BankCard => "bank_card",
+        }
+    }
+
+    // ASCII boundaries, so numbers right after CJK text are found too.
+    fn pattern(&self) -> &'static str {
+        match self {
+            Detector::Phone => r"(?:\+86|(?-u:\b)(?:86)?)1[3-9][0-9]{9}(?-u:\b)",
+            Detector::CnId => {
+                r"(?-u:\b)[1-9][0-9]{5}(?:19|20)[0-9]{2}(?:0[1-9]|1[0-2])(?:0[1-9]|[12][0-9]|3[01])[0-9]{3}[0-9Xx](?-u:\b)"
+            }
+            Detector::Email => r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+            Detector::BankCard => r"(?-u:\b)[0-9](?:[ -]?[0-9]){12,18}(?-u:\b)",
+        }
+    }
+
+    fn check(&self, found: &str) -> bool {
+        match self {
+            Detector::CnId => cn_id_checksum(found),
+            Detector::BankCard => luhn(found),
+            _ => true,
+        }
+    }
+
+    // chars left in clear by "mask", at the start and the end
+    fn keep(&self) -> (usize, usize) {
+        match self {
+            Detector::Phone | Detector::CnId => (3, 4),
+            Detector::Email => (1, 0),
+            Detector::BankCard => (4, 4),
+        }
+    }
+}
+
+fn cn_id_checksum(id: &str) -> bool {
+    const WEIGHTS: [u32; 17] = [7, 9, 10, 5, 8, 4, 2, 1, 6, 3, 7, 9, 10, 5, 8, 4, 2];
+    const CHECK: &[u8; 11] = b"10X98765432";
+    let bytes = id.as_bytes();
+    let sum: u32 = WEIGHTS
+        .iter()
+        .zip(bytes)
+        .map(|(w, b)| w * (b - b'0') as u32)
+        .sum();
+    bytes[17].to_ascii_uppercase() == CHECK[(sum % 11) as usize]
+}
+
+fn luhn(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| match i % 2 {
+            1 if *d * 2 > 9 => *d * 2 - 9,
+            1 => *d * 2,
+            _ => *d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Mask,
+    Hash,
+    Drop,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleConfig {
+    // a built-in detector, or else `name` and `pattern`
+    pub detector: Option<Detector>,
+    pub name: Option<String>,
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub mode: Mode,
+    // chars "mask" leaves, 0 for patterns, per detector otherwise
+    pub keep_start: Option<usize>,
+    pub keep_end: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedactConfig {
+    pub key: Option<String>,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    regex: Regex,
+    detector: Option<Detector>,
+    mode: Mode,
+    keep: (usize, usize),
+}
+
+impl Rule {
+    fn replace(&self, found: &str, key: &[u8]) -> String {
+        match self.mode {
+            Mode::Mask => match (self.detector, found.split_once('@')) {
+                // the domain stays readable
+                (Some(Detector::Email), Some((local, domain))) => {
+                    format!("{}@{}", mask(local, self.keep), domain)
+                }
+                // so is the country code
+                (Some(Detector::Phone), _) if found.len() > 11 => {
+                    let (code, number) = found.split_at(found.len() - 11);
+                    format!("{}{}", code, mask(number, self.keep))
+                }
+                _ => mask(found, self.keep),
+            },
+            Mode::Hash => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+                mac.update(found.as_bytes());
+                let hash = hex::encode(mac.finalize().into_bytes());
+                format!("[{}:{}]", self.name, &hash[..12])
+            }
+            Mode::Drop => String::new(),
+        }
+    }
+}
+
+fn mask(s: &str, (start, end): (usize, usize)) -> String {
+    let chars: Vec<char> = s.chars().collect();
+    if chars.len() <= start + end {
+        return "*".repeat(chars.len());
+    }
+    let mut masked: String = chars[..start].iter().collect();
+    for c in chars[start..chars.len() - end].iter() {
+        // keep the grouping of card numbers
+        masked.push(if *c == ' ' || *c == '-' { *c } else { '*' });
+    }
+    masked.extend(chars[chars.len() - end..].iter());
+    masked
+}
+
+// The rules of a bucket, compiled when the config loads.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RedactConfig")]
+pub struct Redactor {
+    rules: Vec<Rule>,
+    key: Vec<u8>,
+}
+
+impl TryFrom<RedactConfig> for Redactor {
+    type Error = String;
+
+    fn try_from(config: RedactConfig) -> Result<Self, Self::Error> {
+        let mut rules = Vec::new();
+        for rule in config.rules {
+            let (name, pattern, keep) = match (rule.detector, &rule.pattern) {
+                (Some(detector), None) => (
+                    rule.name.unwrap_or_else(|| detector.name().to_string()),
+                    detector.pattern().to_string(),
+                    detector.keep(),
+                ),
+                (None, Some(pattern)) => (
+                    rule.name
+                        .ok_or_else(|| format!("redact pattern {} needs a name", pattern))?,
+                    pattern.clone(),
+                    (0, 0),
+                ),
+                _ => return Err(String::from("a redact rule needs a detector or a pattern")),
+            };
+            let regex = Regex::new(&pattern).map_err(|e| format!("redact rule {}: {}", name, e))?;
+            rules.push(Rule {
+                name,
+                regex,
+                detector: rule.detector,
+                mode: rule.mode,
+                keep: (
+                    rule.keep_start.unwrap_or(keep.0),
+                    rule.keep_end.unwrap_or(keep.1),
+                ),
+            });
+        }
+        let key = config
+            .key
+            .or_else(|| env::var(KEY_ENV).ok())
+            .filter(|k| !k.is_empty());
+        let key = match key {
+            Some(key) => key.into_bytes(),
+            None if rules.iter().any(|r| r.mode == Mode::Hash) => {
+                return Err(format!("redact key missing, set \"key\" or ${}", KEY_ENV))
+            }
+            None => Vec::new(),
+        };
+        Ok(Redactor { rules, key })
+    }
+}
+
+impl Redactor {
+    // The text with every match replaced, and how many there were.
+    pub fn redact(&self, text: &str) -> (String, usize) {
+        // (start, end, rule), earlier rules first
+        let mut found: Vec<(usize, usize, &Rule)> = Vec::new();
+        for rule in self.rules.iter() {
+            for m in rule.regex.find_iter(text) {
+                let checked = rule.detector.is_none_or(|d| d.check(m.as_str()));
+                let overlaps = found
+                    .iter()
+                    .any(|(start, end, _)| m.start() < *end && *start < m.end());
+                if checked && !overlaps && !m.as_str().is_empty() {
+                    found.push((m.start(), m.end(), rule));
+                }
+            }
+        }
+        if found.is_empty() {
+            return (text.to_string(), 0);
+        }
+        found.sort_by_key(|(start, _, _)| *start);
+        let mut redacted = String::with_capacity(text.len());
+        let mut at = 0;
+        for (start, end, rule) in found.iter() {
+            redacted.push_str(&text[at..*start]);
+            redacted.push_str(&rule.replace(&text[*start..*end], &self.key));
+            at = *end;
+        }
+        redacted.push_str(&text[at..]);
+        (redacted, found.len())
+    }
+
+    // Scrub a record in place, returns the number of redactions.
+    pub fn apply(&self, record: &mut Record) -> usize {
+        let mut count = 0;
+        let mut scrub = |text: &mut String| {
+            let (redacted, n) = self.redact(text);
+            if n > 0 {
+                *text = redacted;
+                count += n;
+            }
+        };
+        scrub(&mut record.from);
+        scrub(&mut record.body);
+        for (name, value) in record.meta.iter_mut() {
+            if name.starts_with(FIELD_PREFIX) {
+                scrub(value);
+            }
+        }
+        record
+            .meta
+            .insert(String::from(REDACTIONS), count.to_string());
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(rules: serde_json::Value) -> Redactor {
+        serde_json::from_value(serde_json::json!({ "key": "k3y", "rules": rules })).unwrap()
+    }
+
+    #[test]
+    fn test_redact_detectors() {
+        let redactor = redactor(serde_json::json!([
+            { "detector": "phone" },
+            { "detector": "cn_id" },
+            { "detector": "email" },
+            { "detector": "bank_card" },
+        ]));
+        let (text, n) = redactor.redact(
+            "手机13812345678, id 11010519491231002X, mail alice@example.com, card 6222 0212 3456 7894",
+        );
+        assert_eq!(
+            text,
+            "手机138****5678, id 110***********002X, mail a****@example.com, card 6222 **** **** 7894"
+        );
+        assert_eq!(n, 4);
+
+        // country codes as devices report senders
+        let (text, n) = redactor.redact("+8613812345678 8613912345678");
+        assert_eq!(text, "+86138****5678 86139****5678");
+        assert_eq!(n, 2);
+
+        // longer digit runs, bad checksums and card numbers failing Luhn stay
+        let text = "138123456789 110105194912310021 6222021234567890";
+        assert_eq!(redactor.redact(text), (text.to_string(), 0));
+    }
+
+    #[test]
+    fn test_redact_modes() {
+        let redactor = redactor(serde_json::json!([
+            { "detector": "phone", "mode": "hash" },
+            { "name": "order", "pattern": "ORD-[0-9]+", "mode": "drop" },
+            { "name": "code", "pattern": "[0-9]{6}", "keep_end": 2 },
+        ]));
+        let (a, _) = redactor.redact("from 13812345678");
+        let (b, _) = redactor.redact("to 13812345678");
+        assert!(a.starts_with("from [phone:"));
+        assert_eq!(a.strip_prefix("from "), b.strip_prefix("to "));
+        assert!(!a.contains("5678"));
+        // the phone rule comes first, the code rule may not cut into it
+        assert_eq!(redactor.redact("13812345678").1, 1);
+        assert_eq!(redactor.redact("ORD-42 code 123456").0, " code ****56");
+
+        let mut record = Record {
+            time: "2022-04-01T08:00:00+08:00".parse().unwrap(),
+            bucket: String::from("sms"),
+            device_id: String::from("100"),
+            cat: String::from("text"),
+            from: String::from("13812345678"),
+            body: String::from("code 123456"),
+            meta: Default::default(),
+        };
+        record
+            .meta
+            .insert(String::from("field.note"), String::from("ORD-1"));
+        assert_eq!(redactor.apply(&mut record), 3);
+        assert_eq!(record.body, "code ****56");
+        assert_eq!(record.meta.get("field.note").unwrap(), "");
+        assert_eq!(record.meta.get(REDACTIONS).unwrap(), "3");
+
+        // hashing needs a key
+        let config: RedactConfig = serde_json::from_value(serde_json::json!({
+            "rules": [{ "detector": "phone", "mode": "hash" }]
+        }))
+        .unwrap();
+        if env::var(KEY_ENV).is_err() {
+            assert!(Redactor::try_from(config).is_err());
+        }
+        let config: RedactConfig = serde_json::from_value(serde_json::json!({
+            "rules": [{ "pattern": "x" }]
+        }))
+        .unwrap();
+        assert!(Redactor::try_from(config).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+use super::http::client;
+use super::{Health, LogSink, Record, SinkError, Template};
+use actix_web::rt;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use log::warn;
+use serde::Deserialize;
+use serde_json::json;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+//   { "type": "elasticsearch", "url": "https://es.internal:9200",
+//     "index": "webhook-{bucket}-{yyyy.MM.dd}", "username": "hook", "password": "..." }
+// "opensearch" is the same sink.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ElasticConfig {
+    pub url: String,
+    // lowercased, dates are those of the record in its bucket's timezone
+    #[serde(default = "default_index")]
+    pub index: Template,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // sent as "Authorization: ApiKey ..." instead of basic auth
+    pub api_key: Option<String>,
+    // records per _bulk request, sent at least every `batch_wait` seconds
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_batch_wait")]
+    pub batch_wait: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_index() -> Template {
+    "webhook-{bucket}-{yyyy.MM.dd}".parse().unwrap()
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_batch_wait() -> u64 {
+    1
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+enum BulkError {
+    Retry(String),
+    // 413, worth another try in smaller parts
+    TooLarge,
+    Refused(String),
+}
+
+// Indexes records with `_bulk`. What the cluster can not take yet is spooled
+// below `.elastic/{name}`, what it refuses goes to `dead.jsonl`.
+#[derive(Debug)]
+pub struct ElasticSink {
+    config: ElasticConfig,
+    dir: PathBuf,
+    buffer: Mutex<Vec<Record>>,
+    pushing: tokio::sync::Mutex<()>,
+    seq: AtomicU64,
+    // records in the spool, counted on open and kept up since
+    spooled_len: AtomicUsize,
+}
+
+impl ElasticSink {
+    pub fn new(name: &str, dir: &Path, config: ElasticConfig) -> io::Result<Self> {
+        let dir = dir.join(".elastic").join(name);
+        fs::create_dir_all(dir.join("spool"))?;
+        let mut sink = ElasticSink {
+            config,
+            dir,
+            buffer: Mutex::new(Vec::new()),
+            pushing: tokio::sync::Mutex::new(()),
+            seq: AtomicU64::new(0),
+            spooled_len: AtomicUsize::new(0),
+        };
+        let mut spooled = 0;
+        for path in sink.spooled()? {
+            spooled += fs::read_to_string(path)?.lines().count();
+        }
+        sink.spooled_len = AtomicUsize::new(spooled);
+        Ok(sink)
+    }
+
+    pub fn index(&self, record: &Record) -> String {
+        self.config.index.render(record).to_lowercase()
+    }
+
+    fn body(&self, records: &[Record]) -> String {
+        let mut body = String::new();
+        for record in records {
+            let action = json!({ "index": { "_index": self.index(record) } });
+            body.push_str(&action.to_string());
+            body.push('\n');
+            body.push_str(&serde_json::to_string(record).unwrap());
+            body.push('\n');
+        }
+        body
+    }
+
+    // Index a batch, returning the items to try again. Items refused for
+    // good go to the dead-letter file. A request too large is split in
+    // halves until it fits. Fails if the cluster did not take any of it.
+    async fn bulk(&self, records: &[Record]) -> Result<Vec<Record>, String> {
+        let mut retry = Vec::new();
+        let mut parts = vec![records];
+        while let Some(part) = parts.pop() {
+            match self.send(part).await {
+                Ok(again) => retry.extend(again),
+                Err(BulkError::TooLarge) if part.len() > 1 => {
+                    let (first, second) = part.split_at(part.len() / 2);
+                    parts.push(second);
+                    parts.push(first);
+                }
+                Err(BulkError::TooLarge) => self
+                    .dead_letter(&part[0], &json!("413 Payload Too Large"))
+                    .map_err(|e| e.to_string())?,
+                Err(BulkError::Refused(e)) => {
+                    for record in part {
+                        self.dead_letter(record, &json!(e))
+                            .map_err(|e| e.to_string())?;
+                    }
+                }
+                Err(BulkError::Retry(e)) if part.len() == records.len() => return Err(e),
+                // what went through stays done
+                Err(BulkError::Retry(_)) => {
+                    retry.extend_from_slice(part);
+                    for part in parts.into_iter().rev() {
+                        retry.extend_from_slice(part);
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(retry)
+    }
+
+    async fn send(&self, records: &[Record]) -> Result<Vec<Record>, BulkError> {
+        let url = format!("{}/_bulk", self.config.url.trim_end_matches('/'));
+        let mut request = client()
+            .post(&url)
+            .timeout(Duration::from_secs(self.config.timeout))
+            .insert_header(("Content-Type", "application/x-ndjson"));
+        if let Some(key) = &self.config.api_key {
+            request = request.insert_header(("Authorization", format!("ApiKey {}", key)));
+        } else if let Some(username) = &self.config.username {
+            request = request.basic_auth(
+                username,
+                self.config.password.as_deref().unwrap_or_default(),
+            );
+        }
+
+        let mut response = request
+            .send_body(self.body(records))
+            .await
+            .map_err(|e| BulkError::Retry(e.to_string()))?;
+        let status = response.status();
+        match status.as_u16() {
+            200..=299 => {}
+            413 => return Err(BulkError::TooLarge),
+            429 => return Err(BulkError::Retry(status.to_string())),
+            400..=499 => return Err(BulkError::Refused(status.to_string())),
+            _ => return Err(BulkError::Retry(status.to_string())),
+        }
+        let result: serde_json::Value = response
+            .json()
+            .limit(64 << 20)
+            .await
+            .map_err(|e| BulkError::Retry(e.to_string()))?;
+        if result["errors"] != json!(true) {
+            return Ok(Vec::new());
+        }
+        let items = result["items"].as_array().cloned().unwrap_or_default();
+        if items.len() != records.len() {
+            return Err(BulkError::Retry(format!(
+                "{} items in the reply to {} records",
+                items.len(),
+                records.len()
+            )));
+        }
+
+        let mut retry = Vec::new();
+        for (record, item) in records.iter().zip(items) {
+            // keyed by the action, "index"
+            let item = item
+                .as_object()
+                .and_then(|o| o.values().next().cloned())
+                .unwrap_or_default();
+            match item["status"].as_u64().unwrap_or_default() {
+                200..=299 => {}
+                429 | 500..=599 => retry.push(record.clone()),
+                _ => self
+                    .dead_letter(record, &item["error"])
+                    .map_err(|e| BulkError::Retry(e.to_string()))?,
+            }
+        }
+        Ok(retry)
+    }
+
+    fn dead_letter(&self, record: &Record, error: &serde_json::Value) -> io::Result<()> {
+        warn!(
+            "{} refused {}/{}: {}",
+            self.config.url, record.bucket, record.device_id, error
+        );
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("dead.jsonl"))?;
+        writeln!(file, "{}", json!({ "record": record, "error": error }))
+    }
+
+    // Spooled batches, oldest first.
+    pub fn spooled(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(self.dir.join("spool"))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "jsonl") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn spool(&self, records: &[Record]) -> io::Result<()> {
+        let now = Utc::now();
+        let name = format!(
+            "{}{:09}-{:06}.jsonl",
+            now.timestamp(),
+            now.timestamp_subsec_nanos(),
+            self.seq.fetch_add(1, Ordering::Relaxed) % 1_000_000
+        );
+        save(&self.dir.join("spool").join(name), records)?;
+        self.spooled_len.fetch_add(records.len(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Move the buffer to the spool, the cluster is not taking records.
+    fn spool_buffer(&self) -> io::Result<()> {
+        let records = std::mem::take(&mut *self.buffer.lock().unwrap());
+        for batch in records.chunks(self.config.batch_size.max(1)) {
+            self.spool(batch)?;
+        }
+        Ok(())
+    }
+
+    // Send the spool, then the buffer, batch by batch. Stops at the first
+    // batch not fully indexed, the buffer goes to the spool then.
+    pub async fn push(&self) -> Result<(), SinkError> {
+        let _pushing = self.pushing.lock().await;
+        for path in self.spooled()? {
+            let records = load(&path)?;
+            let error = match self.bulk(&records).await {
+                Ok(retry) if retry.is_empty() => {
+                    fs::remove_file(&path)?;
+                    self.spooled_len.fetch_sub(records.len(), Ordering::SeqCst);
+                    continue;
+                }
+                Ok(retry) => {
+                    save(&path, &retry)?;
+                    self.spooled_len
+                        .fetch_sub(records.len() - retry.len(), Ordering::SeqCst);
+                    format!("{} records to retry", retry.len())
+                }
+                Err(e) => e,
+            };
+            self.spool_buffer()?;
+            return Err(SinkError::Unavailable(error));
+        }
+        loop {
+            let batch: Vec<Record> = {
+                let mut buffer = self.buffer.lock().unwrap();
+                let n = buffer.len().min(self.config.batch_size.max(1));
+                buffer.drain(..n).collect()
+            };
+            if batch.is_empty() {
+                return Ok(());
+            }
+            let error = match self.bulk(&batch).await {
+                Ok(retry) if retry.is_empty() => continue,
+                Ok(retry) => {
+                    self.spool(&retry)?;
+                    format!("{} records to retry", retry.len())
+                }
+                Err(e) => {
+                    self.spool(&batch)?;
+                    e
+                }
+            };
+            self.spool_buffer()?;
+            return Err(SinkError::Unavailable(error));
+        }
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+}
+
+fn load(path: &Path) -> io::Result<Vec<Record>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| {
+            serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+fn save(path: &Path, records: &[Record]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut data = String::new();
+    for record in records {
+        data.push_str(&serde_json::to_string(record)?);
+        data.push('\n');
+    }
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+#[async_trait(?Send)]
+impl LogSink for ElasticSink {
+    // Buffered counts as written. A full buffer is sent right away, or
+    // spooled if the spool is not empty, the cluster is likely down then.
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        let full = {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.push(record.clone());
+            buffer.len() >= self.config.batch_size
+        };
+        if !full {
+            return Ok(());
+        }
+        if !self.spooled()?.is_empty() {
+            self.spool_buffer()?;
+        } else if let Err(e) = self.push().await {
+            warn!("bulk to {} failed: {}", self.config.url, e);
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        self.push().await
+    }
+
+    async fn health(&self) -> Health {
+        match self.spooled_len.load(Ordering::SeqCst) {
+            0 => Health::ok(),
+            n => Health {
+                ok: true,
+                detail: Some(format!("{} spooled", n)),
+            },
+        }
+    }
+
+    fn start(self: Arc<Self>) {
+        rt::spawn(async move {
+            let every = Duration::from_secs(self.config.batch_wait.max(1));
+            let mut interval = rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.push().await {
+                    warn!("bulk to {} failed: {}", self.config.url, e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    type Calls = web::Data<Mutex<Vec<String>>>;
+
+    // Down for the first request. Refuses the body "bad" and asks to retry
+    // "busy" the first time. Requests with "huge" are too large, those with
+    // "malformed" are refused as a whole.
+    async fn bulk(body: String, calls: Calls) -> HttpResponse {
+        let mut calls = calls.lock().unwrap();
+        let busy = calls.iter().any(|c| c.contains("busy"));
+        calls.push(body.clone());
+        if calls.len() == 1 {
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+        if body.contains("\"huge\"") {
+            return HttpResponse::PayloadTooLarge().finish();
+        }
+        if body.contains("\"malformed\"") {
+            return HttpResponse::BadRequest().finish();
+        }
+        let items: Vec<_> = body
+            .lines()
+            .skip(1)
+            .step_by(2)
+            .map(|doc| {
+                let record: Record = serde_json::from_str(doc).unwrap();
+                match record.body.as_str() {
+                    "bad" => json!({ "index": { "status": 400,
+                        "error": { "type": "mapper_parsing_exception" } } }),
+                    "busy" if !busy => json!({ "index": { "status": 429 } }),
+                    _ => json!({ "index": { "status": 201 } }),
+                }
+            })
+            .collect();
+        let errors = items.iter().any(|i| i["index"]["status"] != 201);
+        HttpResponse::Ok().json(json!({ "errors": errors, "items": items }))
+    }
+
+    #[actix_web::test]
+    async fn test_elastic_bulk() {
+        let calls: Calls = web::Data::new(Mutex::new(Vec::new()));
+        let data = calls.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/_bulk", web::post().to(bulk))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        rt::spawn(server.run());
+
+        let dir = Path::new("./logs/web_hook_test/elastic");
+        let _ = fs::remove_dir_all(dir);
+        let config: ElasticConfig = serde_json::from_value(json!({
+            "url": format!("http://127.0.0.1:{}", port),
+            "batch_size": 2,
+        }))
+        .unwrap();
+        let sink = ElasticSink::new("es", dir, config).unwrap();
+        let with_body = |body: &str| {
+            let mut record = record("SMS");
+            record.body = body.to_string();
+            record
+        };
+
+        // the cluster is down, the batch is spooled
+        sink.write(&with_body("1")).await.unwrap();
+        sink.write(&with_body("2")).await.unwrap();
+        assert_eq!(sink.spooled().unwrap().len(), 1);
+        // the next batch waits behind it
+        sink.write(&with_body("bad")).await.unwrap();
+        sink.write(&with_body("busy")).await.unwrap();
+        assert_eq!(sink.spooled().unwrap().len(), 2);
+        assert_eq!(sink.buffered(), 0);
+        assert_eq!(sink.health().await.detail.as_deref(), Some("4 spooled"));
+        assert_eq!(calls.lock().unwrap().len(), 1);
+
+        // "busy" stays spooled, "bad" is refused for good
+        assert!(matches!(sink.flush().await, Err(SinkError::Unavailable(_))));
+        assert_eq!(sink.health().await.detail.as_deref(), Some("1 spooled"));
+        sink.flush().await.unwrap();
+        assert!(sink.spooled().unwrap().is_empty());
+
+        {
+            let calls = calls.lock().unwrap();
+            assert_eq!(calls.len(), 4);
+            assert_eq!(
+                calls[1].lines().next().unwrap(),
+                "{\"index\":{\"_index\":\"webhook-sms-2022.04.01\"}}"
+            );
+            assert!(calls[3].contains("busy"));
+        }
+        let dead = fs::read_to_string(dir.join(".elastic/es/dead.jsonl")).unwrap();
+        assert_eq!(dead.lines().count(), 1);
+        assert!(dead.contains("mapper_parsing_exception"));
+
+        // too large is split, refused is dead-lettered, neither blocks the spool
+        sink.write(&with_body("huge")).await.unwrap();
+        sink.write(&with_body("3")).await.unwrap();
+        sink.write(&with_body("malformed")).await.unwrap();
+        sink.write(&with_body("4")).await.unwrap();
+        assert!(sink.spooled().unwrap().is_empty());
+        assert!(sink.health().await.detail.is_none());
+        let dead = fs::read_to_string(dir.join(".elastic/es/dead.jsonl")).unwrap();
+        assert_eq!(dead.lines().count(), 4);
+        let calls = calls.lock().unwrap();
+        // huge+3, huge, 3, malformed+4
+        assert_eq!(calls.len(), 8);
+        assert!(calls[6].contains("\"3\"") && !calls[6].contains("huge"));
+    }
+}
@@ -0,0 +1,111 @@
+use super::{LogSink, Record, SinkError};
+use crate::config::Config;
+use crate::storage::{Cipher, Rotation, SegmentWriter};
+use actix_web::web;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// The log files below the work dir, written on the blocking pool.
+#[derive(Debug)]
+pub struct FileSink(Arc<Files>);
+
+#[derive(Debug)]
+struct Files {
+    dir: PathBuf,
+    config: Config,
+    rotation: Rotation,
+    segments: SegmentWriter,
+    // by bucket config name, "*" included
+    ciphers: HashMap<String, Cipher>,
+}
+
+impl FileSink {
+    pub fn new(dir: &Path, config: Config, rotation: Rotation) -> io::Result<Self> {
+        let mut ciphers = HashMap::new();
+        for (name, bucket) in config.buckets.iter() {
+            if let Some(encryption) = &bucket.encryption {
+                ciphers.insert(name.clone(), Cipher::new(encryption)?);
+            }
+        }
+        let segments = match config.chain {
+            Some(_) => SegmentWriter::chained(),
+            None => SegmentWriter::default(),
+        };
+        Ok(FileSink(Arc::new(Files {
+            dir: dir.to_path_buf(),
+            config,
+            rotation,
+            segments,
+            ciphers,
+        })))
+    }
+
+    pub fn append(&self, record: &Record) -> Result<PathBuf, SinkError> {
+        self.0.append(record)
+    }
+}
+
+impl Files {
+    fn cipher(&self, bucket: &str) -> Option<&Cipher> {
+        match self.config.buckets.contains_key(bucket) {
+            true => self.ciphers.get(bucket),
+            false => self.ciphers.get("*"),
+        }
+    }
+
+    fn append(&self, record: &Record) -> Result<PathBuf, SinkError> {
+        let clock = self.config.clock(&record.bucket);
+        let mut time = clock.at(record.time.with_timezone(&Utc));
+        let now = clock.now();
+        // a replayed record goes to the open partition, past ones may be
+        // compacted, sealed or uploaded already
+        let late = self.config.layout.key(&clock, &time) < self.config.layout.key(&clock, &now);
+        let mut owned;
+        let record = match late {
+            true => {
+                owned = record.clone();
+                owned
+                    .meta
+                    .insert(String::from("record_time"), record.time.to_rfc3339());
+                owned.time = now.with_timezone(&now.offset().fix());
+                time = now;
+                &owned
+            }
+            false => record,
+        };
+        let location =
+            self.config
+                .layout
+                .locate(&self.dir, &record.bucket, &record.device_id, &clock, &time);
+
+        let (header, line) = match self.cipher(&record.bucket) {
+            Some(cipher) => {
+                let mut stored = record.clone();
+                stored.body = String::new();
+                // the line up to the body is authenticated with it
+                stored.body = cipher.encrypt(&stored.line(), &record.body)?;
+                (Some(cipher.header()), stored.line())
+            }
+            None => (None, record.line()),
+        };
+
+        // write log, rolling to the next segment when needed
+        Ok(self
+            .segments
+            .append(&location, &self.rotation, header.as_deref(), &line)?)
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSink for FileSink {
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        let files = self.0.clone();
+        let record = record.clone();
+        web::block(move || files.append(&record)).await??;
+        Ok(())
+    }
+}
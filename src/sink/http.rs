@@ -0,0 +1,476 @@
+use super::{Health, LogSink, Record, SinkError, Template};
+use crate::storage::s3::uri_encode;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::rt;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+thread_local! {
+    // awc clients are per thread, this keeps connections alive between records
+    static CLIENT: awc::Client = awc::Client::builder().disable_timeout().finish();
+}
+
+pub(crate) fn client() -> awc::Client {
+    CLIENT.with(|c| c.clone())
+}
+
+//   { "type": "http", "url": "https://svc.internal/sms/{bucket}",
+//     "headers": { "X-Device": "{device_id}" },
+//     "body": "{\"text\": {body:json}, \"from\": {from:json}}",
+//     "secret": "..." }
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpConfig {
+    pub url: Template,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: BTreeMap<String, Template>,
+    // defaults to the record as JSON
+    pub body: Option<Template>,
+    // signs "{timestamp}.{body}" with HMAC-SHA256
+    pub secret: Option<String>,
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    // seconds per request
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // attempts before a record goes to the dead-letter file
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    // seconds before the first retry, doubled on each failure
+    #[serde(default = "default_backoff")]
+    pub backoff: i64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: i64,
+    // seconds between looks at the retry queue
+    #[serde(default = "default_retry_interval")]
+    pub retry_interval: u64,
+}
+
+fn default_method() -> String {
+    String::from("POST")
+}
+
+fn default_signature_header() -> String {
+    String::from("X-Webhook-Signature")
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+fn default_backoff() -> i64 {
+    5
+}
+
+fn default_max_backoff() -> i64 {
+    3600
+}
+
+fn default_retry_interval() -> u64 {
+    5
+}
+
+// One record waiting in the retry queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Queued {
+    pub record: Record,
+    pub attempts: u32,
+    pub next_try: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+enum SendError {
+    Retry(String),
+    Permanent(String),
+}
+
+// Relays records to an HTTP endpoint. Undelivered ones wait in order below
+// `.forward/{name}`, given up ones go to `dead.jsonl`.
+#[derive(Debug)]
+pub struct HttpSink {
+    config: HttpConfig,
+    method: awc::http::Method,
+    dir: PathBuf,
+    seq: AtomicU64,
+    // length of the queue, counted on open and kept up since
+    queue_len: AtomicUsize,
+}
+
+impl HttpSink {
+    pub fn new(name: &str, dir: &Path, config: HttpConfig) -> io::Result<Self> {
+        let method = config
+            .method
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid http method"))?;
+        let dir = dir.join(".forward").join(name);
+        fs::create_dir_all(dir.join("queue"))?;
+        let mut sink = HttpSink {
+            config,
+            method,
+            dir,
+            seq: AtomicU64::new(0),
+            queue_len: AtomicUsize::new(0),
+        };
+        sink.queue_len = AtomicUsize::new(sink.queued()?.len());
+        Ok(sink)
+    }
+
+    fn backoff(&self, attempts: u32) -> chrono::Duration {
+        let wait = self
+            .config
+            .backoff
+            .saturating_mul(1 << attempts.saturating_sub(1).min(20))
+            .min(self.config.max_backoff);
+        chrono::Duration::seconds(wait)
+    }
+
+    // Values are percent encoded, a body with '/', '?' or '#' stays one value.
+    pub fn url(&self, record: &Record) -> String {
+        self.config.url.render_with(record, uri_encode)
+    }
+
+    async fn send(&self, record: &Record) -> Result<(), SendError> {
+        let body = match &self.config.body {
+            Some(template) => template.render(record),
+            None => serde_json::to_string(record).unwrap(),
+        };
+        let mut request = client()
+            .request(self.method.clone(), self.url(record))
+            .timeout(Duration::from_secs(self.config.timeout))
+            .insert_header(("Content-Type", "application/json"));
+        // a value the record makes invalid is not going to get better
+        for (name, value) in self.config.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| SendError::Permanent(format!("header {}: {}", name, e)))?;
+            let value = HeaderValue::from_str(&value.render(record))
+                .map_err(|e| SendError::Permanent(format!("header {}: {}", name, e)))?;
+            request = request.insert_header((name, value));
+        }
+        if let Some(secret) = &self.config.secret {
+            let timestamp = Utc::now().timestamp().to_string();
+            request = request
+                .insert_header(("X-Webhook-Timestamp", timestamp.as_str()))
+                .insert_header((
+                    self.config.signature_header.as_str(),
+                    format!("sha256={}", sign(secret, &timestamp, &body)),
+                ));
+        }
+
+        let response = request
+            .send_body(body)
+            .await
+            .map_err(|e| SendError::Retry(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || matches!(status.as_u16(), 408 | 429) {
+            Err(SendError::Retry(status.to_string()))
+        } else {
+            Err(SendError::Permanent(status.to_string()))
+        }
+    }
+
+    // Queued records, oldest first.
+    pub fn queued(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(self.dir.join("queue"))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn enqueue(&self, item: &Queued) -> io::Result<()> {
+        let now = Utc::now();
+        let name = format!(
+            "{}{:09}-{:06}.json",
+            now.timestamp(),
+            now.timestamp_subsec_nanos(),
+            self.seq.fetch_add(1, Ordering::Relaxed) % 1_000_000
+        );
+        save(&self.dir.join("queue").join(name), item)?;
+        self.queue_len.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn dead_letter(&self, item: &Queued) -> io::Result<()> {
+        warn!(
+            "forward of {}/{} given up: {}",
+            item.record.bucket,
+            item.record.device_id,
+            item.error.as_deref().unwrap_or_default()
+        );
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("dead.jsonl"))?;
+        writeln!(file, "{}", serde_json::to_string(item)?)
+    }
+
+    // Deliver the queue in order, up to the first record not due yet or
+    // failing again. Returns how many records left the queue, unreadable
+    // ones are moved to `bad/`.
+    pub async fn retry(&self, now: DateTime<Utc>) -> io::Result<usize> {
+        let mut done = 0;
+        for path in self.queued()? {
+            let mut item: Queued = match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(item) => item,
+                Err(e) => {
+                    warn!("forward queue: {} unreadable: {}", path.display(), e);
+                    let bad = self.dir.join("bad");
+                    fs::create_dir_all(&bad)?;
+                    fs::rename(&path, bad.join(path.file_name().unwrap()))?;
+                    self.queue_len.fetch_sub(1, Ordering::SeqCst);
+                    done += 1;
+                    continue;
+                }
+            };
+            if item.next_try.is_some_and(|t| t > now) {
+                break;
+            }
+            item.attempts += 1;
+            match self.send(&item.record).await {
+                Ok(_) => {}
+                Err(SendError::Retry(e)) if item.attempts < self.config.max_attempts => {
+                    item.error = Some(e);
+                    item.next_try = Some(now + self.backoff(item.attempts));
+                    save(&path, &item)?;
+                    break;
+                }
+                Err(SendError::Retry(e)) | Err(SendError::Permanent(e)) => {
+                    item.error = Some(e);
+                    self.dead_letter(&item)?;
+                }
+            }
+            fs::remove_file(&path)?;
+            self.queue_len.fetch_sub(1, Ordering::SeqCst);
+            done += 1;
+        }
+        Ok(done)
+    }
+}
+
+fn save(path: &Path, item: &Queued) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(item)?)?;
+    fs::rename(&tmp, path)
+}
+
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[async_trait(?Send)]
+impl LogSink for HttpSink {
+    // Queued counts as written, the record is on disk.
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        let mut item = Queued {
+            record: record.clone(),
+            attempts: 0,
+            next_try: None,
+            error: None,
+        };
+        // keep the order, nothing overtakes the queue
+        if self.queue_len.load(Ordering::SeqCst) > 0 {
+            self.enqueue(&item)?;
+            return Ok(());
+        }
+        item.attempts = 1;
+        match self.send(record).await {
+            Ok(_) => Ok(()),
+            Err(SendError::Retry(e)) => {
+                item.next_try = Some(Utc::now() + self.backoff(1));
+                item.error = Some(e);
+                self.enqueue(&item)?;
+                Ok(())
+            }
+            Err(SendError::Permanent(e)) => {
+                item.error = Some(e.clone());
+                self.dead_letter(&item)?;
+                Err(SinkError::Failed(e))
+            }
+        }
+    }
+
+    async fn health(&self) -> Health {
+        match self.queue_len.load(Ordering::SeqCst) {
+            0 => Health::ok(),
+            n => Health {
+                ok: true,
+                detail: Some(format!("{} queued", n)),
+            },
+        }
+    }
+
+    fn start(self: Arc<Self>) {
+        rt::spawn(async move {
+            let every = Duration::from_secs(self.config.retry_interval.max(1));
+            let mut interval = rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                match self.retry(Utc::now()).await {
+                    Ok(0) => {}
+                    Ok(n) => info!("forward queue: {} records done", n),
+                    Err(e) => warn!("forward queue failed: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::Mutex;
+
+    type Seen = web::Data<Mutex<Vec<(String, String, String)>>>;
+
+    // Down for the first request, refuses the "bad" bucket.
+    async fn hook(req: HttpRequest, body: String, seen: Seen) -> HttpResponse {
+        let mut seen = seen.lock().unwrap();
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .map(|v| v.to_str().unwrap().to_string())
+                .unwrap_or_default()
+        };
+        seen.push((req.path().to_string(), header("X-Device"), body.clone()));
+        if seen.len() == 1 {
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+        if req.path().ends_with("/bad") {
+            return HttpResponse::BadRequest().finish();
+        }
+        let expected = sign("s3cr3t", &header("X-Webhook-Timestamp"), &body);
+        assert_eq!(
+            header("X-Webhook-Signature"),
+            format!("sha256={}", expected)
+        );
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_http_sink_retry() {
+        let seen: Seen = web::Data::new(Mutex::new(Vec::new()));
+        let data = seen.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .default_service(web::to(hook))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        rt::spawn(server.run());
+
+        let dir = Path::new("./logs/web_hook_test/forward");
+        let _ = fs::remove_dir_all(dir);
+        let config: HttpConfig = serde_json::from_value(serde_json::json!({
+            "url": format!("http://127.0.0.1:{}/hook/{{bucket}}", port),
+            "headers": { "X-Device": "{device_id}" },
+            "body": "{\"text\": {body:json}}",
+            "secret": "s3cr3t",
+        }))
+        .unwrap();
+        let sink = HttpSink::new("relay", dir, config).unwrap();
+
+        // the first fails and is queued, the second waits behind it
+        let mut second = record("sms");
+        second.body = String::from("second");
+        sink.write(&record("sms")).await.unwrap();
+        sink.write(&second).await.unwrap();
+        assert_eq!(sink.queued().unwrap().len(), 2);
+        assert_eq!(sink.health().await.detail.as_deref(), Some("2 queued"));
+        // a restart counts what is queued
+        let reopened = HttpSink::new("relay", dir, sink.config.clone()).unwrap();
+        assert_eq!(reopened.health().await.detail.as_deref(), Some("2 queued"));
+
+        let now = Utc::now();
+        assert_eq!(sink.retry(now).await.unwrap(), 0);
+        let later = now + chrono::Duration::minutes(1);
+        assert_eq!(sink.retry(later).await.unwrap(), 2);
+        assert!(sink.queued().unwrap().is_empty());
+
+        let bodies: Vec<String> = seen.lock().unwrap().iter().map(|s| s.2.clone()).collect();
+        assert_eq!(
+            bodies,
+            vec![
+                "{\"text\": \"中文\\n你好\"}",
+                "{\"text\": \"中文\\n你好\"}",
+                "{\"text\": \"second\"}"
+            ]
+        );
+        assert_eq!(seen.lock().unwrap()[1].0, "/hook/sms");
+        assert_eq!(seen.lock().unwrap()[1].1, "100");
+
+        // refused for good, goes to the dead-letter file
+        let result = sink.write(&record("bad")).await;
+        assert!(matches!(result, Err(SinkError::Failed(_))));
+        let dead = fs::read_to_string(dir.join(".forward/relay/dead.jsonl")).unwrap();
+        assert_eq!(dead.lines().count(), 1);
+
+        // so is a header the record makes invalid, without a try
+        let mut newline = record("sms");
+        newline.device_id = String::from("1\n2");
+        let sent = seen.lock().unwrap().len();
+        assert!(sink.write(&newline).await.is_err());
+        assert_eq!(seen.lock().unwrap().len(), sent);
+        let dead = fs::read_to_string(dir.join(".forward/relay/dead.jsonl")).unwrap();
+        assert_eq!(dead.lines().count(), 2);
+
+        // an unreadable queue file is put aside, the queue goes on
+        let queue = dir.join(".forward/relay/queue");
+        fs::write(queue.join("0-broken.json"), "{").unwrap();
+        sink.enqueue(&Queued {
+            record: second.clone(),
+            attempts: 0,
+            next_try: None,
+            error: None,
+        })
+        .unwrap();
+        let sink = HttpSink::new("relay", dir, sink.config.clone()).unwrap();
+        assert_eq!(sink.retry(later).await.unwrap(), 2);
+        assert!(sink.queued().unwrap().is_empty());
+        assert!(dir.join(".forward/relay/bad/0-broken.json").exists());
+
+        let mut odd = record("sms");
+        odd.device_id = String::from("a/b?c=1#d");
+        let config: HttpConfig = serde_json::from_value(serde_json::json!({
+            "url": "http://127.0.0.1/hook/{bucket}?device={device_id}&x=1",
+        }))
+        .unwrap();
+        let sink = HttpSink::new("odd", dir, config).unwrap();
+        assert_eq!(
+            sink.url(&odd),
+            "http://127.0.0.1/hook/sms?device=a%2Fb%3Fc%3D1%23d&x=1"
+        );
+    }
+}
@@ -0,0 +1,516 @@
+use super::http::client;
+use super::{Health, LogSink, Payload, Record, SinkError};
+use actix_web::rt;
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use futures_util::future::select;
+use log::warn;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+// device_id label of devices beyond `max_devices`, cat label of cats beyond
+// `max_cats`
+pub const OTHER_DEVICES: &str = "_other";
+pub const OTHER_CATS: &str = "_other";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    // JSON body
+    None,
+    // JSON body, gzip encoded
+    Gzip,
+    // protobuf body, snappy block encoded
+    Snappy,
+}
+
+//   { "type": "loki", "url": "http://loki:3100", "labels": { "job": "webhook" },
+//     "device_label": true, "max_devices": 100, "max_cats": 20,
+//     "compression": "snappy" }
+#[derive(Debug, Clone, Deserialize)]
+pub struct LokiConfig {
+    // base url, the push path is added
+    pub url: String,
+    // static labels, bucket and cat are always added
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    // device_id as a label too, for the first `max_devices` devices seen
+    #[serde(default)]
+    pub device_label: bool,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    // cat comes from the client, only the first `max_cats` seen are labels,
+    // the line keeps the real one
+    #[serde(default = "default_max_cats")]
+    pub max_cats: usize,
+    #[serde(default = "default_compression")]
+    pub compression: Compression,
+    #[serde(default)]
+    pub payload: Payload,
+    // X-Scope-OrgID for multi tenant setups
+    pub tenant: Option<String>,
+    // records per push, pushed at least every `batch_wait` seconds
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_batch_wait")]
+    pub batch_wait: u64,
+    // records kept while Loki is down, more are refused with 503
+    #[serde(default = "default_max_buffer")]
+    pub max_buffer: usize,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    // milliseconds before the first retry of a push, doubled each time
+    #[serde(default = "default_backoff")]
+    pub backoff: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_max_devices() -> usize {
+    100
+}
+
+fn default_max_cats() -> usize {
+    20
+}
+
+fn default_compression() -> Compression {
+    Compression::Gzip
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_batch_wait() -> u64 {
+    1
+}
+
+fn default_max_buffer() -> usize {
+    10000
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_backoff() -> u64 {
+    500
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+enum PushError {
+    Retry(String),
+    Permanent(String),
+}
+
+// Buffers records for a background pusher, batches Loki refuses for good go
+// to `.loki/{name}/dead.jsonl`.
+#[derive(Debug)]
+pub struct LokiSink {
+    config: LokiConfig,
+    dir: PathBuf,
+    buffer: Mutex<Vec<Record>>,
+    devices: Mutex<HashSet<String>>,
+    cats: Mutex<HashSet<String>>,
+    pushing: tokio::sync::Mutex<()>,
+    // a batch is full, wakes the pusher
+    full: Notify,
+}
+
+impl LokiSink {
+    pub fn new(name: &str, dir: &Path, config: LokiConfig) -> io::Result<Self> {
+        let dir = dir.join(".loki").join(name);
+        fs::create_dir_all(&dir)?;
+        Ok(LokiSink {
+            config,
+            dir,
+            buffer: Mutex::new(Vec::new()),
+            devices: Mutex::new(HashSet::new()),
+            cats: Mutex::new(HashSet::new()),
+            pushing: tokio::sync::Mutex::new(()),
+            full: Notify::new(),
+        })
+    }
+
+    pub fn labels(&self, record: &Record) -> BTreeMap<String, String> {
+        let mut labels = self.config.labels.clone();
+        labels.insert(String::from("bucket"), record.bucket.clone());
+        let cat = capped(&self.cats, &record.cat, self.config.max_cats, OTHER_CATS);
+        labels.insert(String::from("cat"), cat);
+        if self.config.device_label {
+            let device = capped(
+                &self.devices,
+                &record.device_id,
+                self.config.max_devices,
+                OTHER_DEVICES,
+            );
+            labels.insert(String::from("device_id"), device);
+        }
+        labels
+    }
+
+    // Records grouped by label set, each stream in time order.
+    fn streams(
+        &self,
+        records: &[Record],
+    ) -> BTreeMap<BTreeMap<String, String>, Vec<(i64, String)>> {
+        let mut streams: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for record in records {
+            let ns = record.time.timestamp() * 1_000_000_000
+                + record.time.timestamp_subsec_nanos() as i64;
+            streams
+                .entry(self.labels(record))
+                .or_default()
+                .push((ns, self.config.payload.render(record)));
+        }
+        for values in streams.values_mut() {
+            values.sort_by_key(|v| v.0);
+        }
+        streams
+    }
+
+    pub fn encode(&self, records: &[Record]) -> (Vec<u8>, &'static str) {
+        let streams = self.streams(records);
+        match self.config.compression {
+            Compression::Snappy => {
+                let mut request = Vec::new();
+                for (labels, values) in streams.iter() {
+                    let mut stream = Vec::new();
+                    proto_bytes(&mut stream, 1, selector(labels).as_bytes());
+                    for (ns, line) in values {
+                        let mut timestamp = Vec::new();
+                        proto_varint_field(&mut timestamp, 1, (ns / 1_000_000_000) as u64);
+                        proto_varint_field(&mut timestamp, 2, (ns % 1_000_000_000) as u64);
+                        let mut entry = Vec::new();
+                        proto_bytes(&mut entry, 1, &timestamp);
+                        proto_bytes(&mut entry, 2, line.as_bytes());
+                        proto_bytes(&mut stream, 2, &entry);
+                    }
+                    proto_bytes(&mut request, 1, &stream);
+                }
+                let body = snap::raw::Encoder::new().compress_vec(&request).unwrap();
+                (body, "application/x-protobuf")
+            }
+            _ => {
+                let streams: Vec<_> = streams
+                    .iter()
+                    .map(|(labels, values)| {
+                        let values: Vec<_> = values
+                            .iter()
+                            .map(|(ns, line)| json!([ns.to_string(), line]))
+                            .collect();
+                        json!({ "stream": labels, "values": values })
+                    })
+                    .collect();
+                let body = serde_json::to_vec(&json!({ "streams": streams })).unwrap();
+                (body, "application/json")
+            }
+        }
+    }
+
+    async fn send(&self, records: &[Record]) -> Result<(), PushError> {
+        let (body, content_type) = self.encode(records);
+        let body = match self.config.compression {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => body,
+        };
+        let url = format!("{}/loki/api/v1/push", self.config.url.trim_end_matches('/'));
+
+        let mut backoff = Duration::from_millis(self.config.backoff);
+        let mut attempt = 0;
+        loop {
+            let mut request = client()
+                .post(&url)
+                .timeout(Duration::from_secs(self.config.timeout))
+                .insert_header(("Content-Type", content_type));
+            if self.config.compression == Compression::Gzip {
+                request = request.insert_header(("Content-Encoding", "gzip"));
+            }
+            if let Some(tenant) = &self.config.tenant {
+                request = request.insert_header(("X-Scope-OrgID", tenant.as_str()));
+            }
+            let error = match request.send_body(body.clone()).await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response)
+                    if !response.status().is_server_error()
+                        && response.status().as_u16() != 429 =>
+                {
+                    return Err(PushError::Permanent(response.status().to_string()))
+                }
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            attempt += 1;
+            if attempt > self.config.max_retries {
+                return Err(PushError::Retry(error));
+            }
+            rt::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    // Push the buffer batch by batch, one pusher at a time.
+    pub async fn push(&self) -> Result<(), SinkError> {
+        let _pushing = self.pushing.lock().await;
+        loop {
+            let batch: Vec<Record> = {
+                let mut buffer = self.buffer.lock().unwrap();
+                let n = buffer.len().min(self.config.batch_size.max(1));
+                buffer.drain(..n).collect()
+            };
+            if batch.is_empty() {
+                return Ok(());
+            }
+            match self.send(&batch).await {
+                Ok(_) => {}
+                Err(PushError::Retry(e)) => {
+                    let mut buffer = self.buffer.lock().unwrap();
+                    buffer.splice(0..0, batch);
+                    return Err(SinkError::Unavailable(e));
+                }
+                Err(PushError::Permanent(e)) => {
+                    if let Err(io) = self.dead_letter(&batch, &e) {
+                        let mut buffer = self.buffer.lock().unwrap();
+                        buffer.splice(0..0, batch);
+                        return Err(SinkError::Failed(io.to_string()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn dead_letter(&self, records: &[Record], error: &str) -> io::Result<()> {
+        warn!("loki refused {} records: {}", records.len(), error);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("dead.jsonl"))?;
+        for record in records {
+            writeln!(file, "{}", json!({ "record": record, "error": error }))?;
+        }
+        Ok(())
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+}
+
+// The value as a label while fewer than `max` values were seen, else `other`.
+fn capped(seen: &Mutex<HashSet<String>>, value: &str, max: usize, other: &str) -> String {
+    let mut seen = seen.lock().unwrap();
+    if seen.contains(value) || seen.len() < max {
+        seen.insert(value.to_string());
+        value.to_string()
+    } else {
+        other.to_string()
+    }
+}
+
+// `{a="1", b="2"}` as Loki wants labels in protobuf pushes.
+fn selector(labels: &BTreeMap<String, String>) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, serde_json::to_string(v).unwrap()))
+        .collect();
+    format!("{{{}}}", pairs.join(", "))
+}
+
+fn proto_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn proto_varint_field(buf: &mut Vec<u8>, field: u64, n: u64) {
+    proto_varint(buf, field << 3);
+    proto_varint(buf, n);
+}
+
+fn proto_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    proto_varint(buf, (field << 3) | 2);
+    proto_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+#[async_trait(?Send)]
+impl LogSink for LokiSink {
+    // Buffered counts as written, a full batch wakes the pusher.
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        let full = {
+            let mut buffer = self.buffer.lock().unwrap();
+            if buffer.len() >= self.config.max_buffer {
+                return Err(SinkError::Unavailable(String::from("loki buffer full")));
+            }
+            buffer.push(record.clone());
+            buffer.len() >= self.config.batch_size
+        };
+        if full {
+            self.full.notify_one();
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        self.push().await
+    }
+
+    async fn health(&self) -> Health {
+        match self.buffered() {
+            n if n >= self.config.max_buffer => Health::failing("buffer full"),
+            0 => Health::ok(),
+            n => Health {
+                ok: true,
+                detail: Some(format!("{} buffered", n)),
+            },
+        }
+    }
+
+    fn start(self: Arc<Self>) {
+        rt::spawn(async move {
+            let mut interval =
+                rt::time::interval(Duration::from_secs(self.config.batch_wait.max(1)));
+            loop {
+                select(pin!(interval.tick()), pin!(self.full.notified())).await;
+                if let Err(e) = self.push().await {
+                    warn!("loki push failed: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use futures_util::FutureExt;
+
+    type Pushes = web::Data<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    // Down for the first push, refuses entries of the "old" bucket.
+    async fn push(req: HttpRequest, body: web::Bytes, pushes: Pushes) -> HttpResponse {
+        let mut pushes = pushes.lock().unwrap();
+        let encoding = req
+            .headers()
+            .get("Content-Encoding")
+            .map(|v| v.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        pushes.push((encoding, body.to_vec()));
+        if pushes.len() == 1 {
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+        if String::from_utf8_lossy(&body).contains("\"old\"") {
+            return HttpResponse::BadRequest().body("entry too far behind");
+        }
+        HttpResponse::NoContent().finish()
+    }
+
+    fn config(port: u16, compression: &str) -> LokiConfig {
+        serde_json::from_value(json!({
+            "url": format!("http://127.0.0.1:{}", port),
+            "labels": { "job": "webhook" },
+            "device_label": true,
+            "max_devices": 1,
+            "compression": compression,
+            "batch_size": 2,
+            "max_retries": 0,
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_loki_push() {
+        let pushes: Pushes = web::Data::new(Mutex::new(Vec::new()));
+        let data = pushes.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/loki/api/v1/push", web::post().to(push))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        rt::spawn(server.run());
+
+        let dir = Path::new("./logs/web_hook_test/loki");
+        let _ = fs::remove_dir_all(dir);
+        let sink = LokiSink::new("loki", dir, config(port, "gzip")).unwrap();
+        let mut other = record("sms");
+        other.device_id = String::from("200");
+        sink.write(&record("sms")).await.unwrap();
+        assert_eq!(sink.buffered(), 1);
+        // the full batch wakes the pusher, the request does not wait for it
+        sink.write(&other).await.unwrap();
+        assert!(sink.full.notified().now_or_never().is_some());
+        assert!(pushes.lock().unwrap().is_empty());
+        // a failed push stays buffered, then goes out on flush
+        assert!(sink.flush().await.is_err());
+        assert_eq!(sink.buffered(), 2);
+        sink.flush().await.unwrap();
+        assert_eq!(sink.buffered(), 0);
+
+        let (encoding, body) = pushes.lock().unwrap()[1].clone();
+        assert_eq!(encoding, "gzip");
+        // actix decodes the gzip body on the way in
+        let push: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let streams = push["streams"].as_array().unwrap();
+        assert_eq!(streams.len(), 2);
+        // the second device is over the cardinality limit
+        assert_eq!(streams[0]["stream"]["device_id"], "100");
+        assert_eq!(streams[0]["stream"]["job"], "webhook");
+        assert_eq!(streams[0]["values"][0][0], "1648771200000000000");
+        assert_eq!(streams[1]["stream"]["device_id"], OTHER_DEVICES);
+
+        // refused for good, the batch goes to the dead-letter file
+        sink.write(&record("old")).await.unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(sink.buffered(), 0);
+        let dead = fs::read_to_string(dir.join(".loki/loki/dead.jsonl")).unwrap();
+        assert_eq!(dead.lines().count(), 1);
+        assert!(dead.contains("\"bucket\":\"old\""));
+
+        let sink = LokiSink::new("loki", dir, config(port, "snappy")).unwrap();
+        sink.write(&record("sms")).await.unwrap();
+        sink.flush().await.unwrap();
+        let (_, body) = pushes.lock().unwrap()[3].clone();
+        let proto = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        let proto = String::from_utf8_lossy(&proto);
+        assert!(
+            proto.contains("{bucket=\"sms\", cat=\"text\", device_id=\"100\", job=\"webhook\"}")
+        );
+        assert!(proto.contains("\"body\":\"中文\\n你好\""));
+
+        // cats are capped like devices
+        let mut config = config(port, "none");
+        config.max_cats = 1;
+        let sink = LokiSink::new("loki", dir, config).unwrap();
+        let mut spam = record("sms");
+        spam.cat = String::from("x1");
+        assert_eq!(sink.labels(&record("sms"))["cat"], "text");
+        assert_eq!(sink.labels(&spam)["cat"], OTHER_CATS);
+        assert_eq!(sink.labels(&record("sms"))["cat"], "text");
+    }
+}
@@ -0,0 +1,702 @@
+use crate::config::{Config, Policy};
+use crate::storage::Rotation;
+use actix_web::error::BlockingError;
+use actix_web::rt;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use futures_util::future::join_all;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod elastic;
+pub mod file;
+pub mod http;
+pub mod loki;
+pub mod nats;
+pub mod postgres;
+pub mod redis;
+pub mod spool;
+pub mod sqlite;
+pub mod syslog;
+pub mod template;
+
+pub use elastic::{ElasticConfig, ElasticSink};
+pub use file::FileSink;
+pub use http::{HttpConfig, HttpSink};
+pub use loki::{LokiConfig, LokiSink};
+pub use nats::{NatsConfig, NatsSink};
+pub use postgres::PostgresSink;
+pub use redis::{RedisConfig, RedisSink};
+pub use spool::{Spool, SpoolConfig, SpoolStatus};
+pub use sqlite::SqliteSink;
+pub use syslog::{SyslogConfig, SyslogSink};
+pub use template::Template;
+
+/**
+ * Every accepted hook becomes a `Record`, handed to the sinks of its bucket.
+ * Sinks are named in the config file and routed per bucket:
+ *
+ *   {
+ *     "sinks": {
+ *       "copy":  { "type": "file", "dir": "/var/lib/hooks/copy" },
+ *       "db":    { "type": "sqlite", "path": "hooks.db", "table_per_bucket": true },
+ *       "pg":    { "type": "postgres", "url": "postgres://hook@localhost/hooks" },
+ *       "relay": { "type": "http", "url": "https://svc.internal/sms/{bucket}" },
+ *       "sys":   { "type": "syslog", "transport": "unix" },
+ *       "live":  { "type": "redis", "address": "127.0.0.1:6379", "payload": "line" },
+ *       "loki":  { "type": "loki", "url": "http://loki:3100" },
+ *       "es":    { "type": "opensearch", "url": "http://es:9200" }
+ *     },
+ *     "buckets": { "sms": { "sinks": [{ "name": "file", "policy": "required" },
+ *                                     { "name": "db", "policy": "best_effort" }] } }
+ *   }
+ *
+ * Without routes a bucket goes to the built-in "file" sink. With a "spool"
+ * section, see `spool`, records required sinks fail to take are spooled and
+ * replayed instead of failing the request.
+ */
+pub const DEFAULT_SINK: &str = "file";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub time: DateTime<FixedOffset>,
+    pub bucket: String,
+    pub device_id: String,
+    pub cat: String,
+    pub from: String,
+    pub body: String,
+    #[serde(default)]
+    pub meta: BTreeMap<String, String>,
+}
+
+impl Record {
+    // The line format of the log files, one record per line. Metadata goes
+    // in as a JSON object before the body, `{}` when there is none, so every
+    // line has the same columns and the body stays last.
+    pub fn line(&self) -> String {
+        let data = self.body.replace(['\r', '\n'], "||");
+        let meta = serde_json::to_string(&self.meta).unwrap();
+        format!(
+            "[{}]\t{}|{}\t####\t{}\t{}\t{}\t{}",
+            self.time.format("%+"),
+            self.device_id,
+            self.bucket,
+            self.cat,
+            self.from,
+            meta,
+            data
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SinkError {
+    // the backend can not take records right now, worth a retry later
+    Unavailable(String),
+    Failed(String),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SinkError::Unavailable(e) => write!(f, "sink unavailable: {}", e),
+            SinkError::Failed(e) => write!(f, "sink failed: {}", e),
+        }
+    }
+}
+
+impl Error for SinkError {}
+
+impl From<io::Error> for SinkError {
+    fn from(e: io::Error) -> Self {
+        SinkError::Failed(e.to_string())
+    }
+}
+
+impl From<BlockingError> for SinkError {
+    fn from(e: BlockingError) -> Self {
+        SinkError::Failed(e.to_string())
+    }
+}
+
+// How streaming sinks encode a record.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Payload {
+    #[default]
+    Json,
+    // the log file line
+    Line,
+}
+
+impl Payload {
+    pub fn render(&self, record: &Record) -> String {
+        match self {
+            Payload::Json => serde_json::to_string(record).unwrap(),
+            Payload::Line => record.line(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Health {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Health {
+    pub fn ok() -> Self {
+        Health {
+            ok: true,
+            detail: None,
+        }
+    }
+
+    pub fn failing(detail: impl ToString) -> Self {
+        Health {
+            ok: false,
+            detail: Some(detail.to_string()),
+        }
+    }
+}
+
+// SQL identifier for table and index names built from config and buckets.
+pub(crate) fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Shared by all workers, the futures run on the worker's thread.
+#[async_trait(?Send)]
+pub trait LogSink: fmt::Debug + Send + Sync {
+    async fn write(&self, record: &Record) -> Result<(), SinkError>;
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    async fn health(&self) -> Health {
+        Health::ok()
+    }
+
+    // Background work of the sink, called once from the main system.
+    fn start(self: Arc<Self>) {}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    // log files below `dir`, which must not be the work dir. Only the
+    // work dir is compacted, retained, sealed and uploaded.
+    File {
+        dir: Option<String>,
+    },
+    // a SQLite database, `path` relative to the work dir
+    Sqlite {
+        path: String,
+        #[serde(default)]
+        table_per_bucket: bool,
+        // seconds between WAL checkpoints
+        #[serde(default = "default_checkpoint_interval")]
+        checkpoint_interval: u64,
+    },
+    // a PostgreSQL table partitioned by day
+    Postgres {
+        url: String,
+        #[serde(default = "default_table")]
+        table: String,
+        #[serde(default = "default_pool_size")]
+        pool_size: usize,
+        // max rows per INSERT
+        #[serde(default = "default_batch_size")]
+        batch_size: usize,
+        // records queued beyond this are refused with 503
+        #[serde(default = "default_max_pending")]
+        max_pending: usize,
+        // seconds to wait for a connection
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    // relay to an HTTP endpoint, with a retry queue
+    Http(HttpConfig),
+    // RFC 5424 or 3164 messages over UDP, TCP or a unix socket
+    Syslog(SyslogConfig),
+    // XADD to a Redis stream
+    Redis(RedisConfig),
+    // publish on a NATS subject
+    Nats(NatsConfig),
+    // batched pushes to Grafana Loki
+    Loki(LokiConfig),
+    // _bulk indexing into Elasticsearch or OpenSearch, spooled while down
+    #[serde(alias = "opensearch")]
+    Elasticsearch(ElasticConfig),
+}
+
+fn default_checkpoint_interval() -> u64 {
+    300
+}
+
+fn default_table() -> String {
+    String::from("records")
+}
+
+fn default_pool_size() -> usize {
+    8
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_max_pending() -> usize {
+    10000
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+impl SinkConfig {
+    // Only the file sink encrypts, the others store or send records as
+    // they come.
+    pub fn plaintext(&self) -> bool {
+        !matches!(self, SinkConfig::File { .. })
+    }
+
+    pub fn build(
+        &self,
+        name: &str,
+        dir: &Path,
+        config: &Config,
+        rotation: &Rotation,
+    ) -> io::Result<Arc<dyn LogSink>> {
+        match self {
+            SinkConfig::File { dir: own } => {
+                let dir = own.as_ref().map_or(dir, Path::new);
+                Ok(Arc::new(FileSink::new(
+                    dir,
+                    config.clone(),
+                    rotation.clone(),
+                )?))
+            }
+            SinkConfig::Sqlite {
+                path,
+                table_per_bucket,
+                checkpoint_interval,
+            } => {
+                fs::create_dir_all(dir)?;
+                let sink = SqliteSink::open(
+                    &dir.join(path),
+                    *table_per_bucket,
+                    Duration::from_secs(*checkpoint_interval),
+                )
+                .map_err(io::Error::other)?;
+                Ok(Arc::new(sink))
+            }
+            SinkConfig::Postgres {
+                url,
+                table,
+                pool_size,
+                batch_size,
+                max_pending,
+                timeout,
+            } => {
+                let sink = PostgresSink::new(
+                    url,
+                    table,
+                    *pool_size,
+                    *batch_size,
+                    *max_pending,
+                    Duration::from_secs(*timeout),
+                )
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Ok(Arc::new(sink))
+            }
+            SinkConfig::Http(http) => Ok(Arc::new(HttpSink::new(name, dir, http.clone())?)),
+            SinkConfig::Syslog(syslog) => Ok(Arc::new(SyslogSink::new(syslog.clone())?)),
+            SinkConfig::Redis(redis) => Ok(Arc::new(RedisSink::new(redis.clone()))),
+            SinkConfig::Nats(nats) => Ok(Arc::new(NatsSink::new(nats.clone()))),
+            SinkConfig::Loki(loki) => Ok(Arc::new(LokiSink::new(name, dir, loki.clone())?)),
+            SinkConfig::Elasticsearch(elastic) => {
+                Ok(Arc::new(ElasticSink::new(name, dir, elastic.clone())?))
+            }
+        }
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// Outcome of one sink for one record.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub sink: String,
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // in the spool, the sink gets it on replay
+    pub spooled: bool,
+}
+
+// Sink health and spool depth, for the status page.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub sinks: BTreeMap<String, Health>,
+    pub spool: Option<SpoolStatus>,
+}
+
+// The named sinks and the routes of each bucket.
+#[derive(Debug, Default, Clone)]
+pub struct Sinks {
+    sinks: HashMap<String, Arc<dyn LogSink>>,
+    config: Config,
+    spool: Option<Arc<Spool>>,
+}
+
+impl Sinks {
+    pub fn build(config: &Config, dir: &Path, rotation: Rotation) -> io::Result<Sinks> {
+        let mut sinks: HashMap<String, Arc<dyn LogSink>> = HashMap::new();
+        sinks.insert(
+            String::from(DEFAULT_SINK),
+            Arc::new(FileSink::new(dir, config.clone(), rotation.clone())?),
+        );
+        // one writer per dir, the work dir has the built-in one
+        let mut files: HashMap<PathBuf, Arc<dyn LogSink>> = HashMap::new();
+        for (name, sink) in config.sinks.iter() {
+            let sink = match sink {
+                SinkConfig::File { dir: own } => {
+                    let own = own.as_ref().map_or(dir, Path::new);
+                    if same_dir(own, dir) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("sink {}: the work dir is the \"file\" sink", name),
+                        ));
+                    }
+                    match files.iter().find(|(other, _)| same_dir(other, own)) {
+                        Some((_, shared)) => shared.clone(),
+                        None => {
+                            let built = sink.build(name, dir, config, &rotation)?;
+                            files.insert(own.to_path_buf(), built.clone());
+                            built
+                        }
+                    }
+                }
+                _ => sink.build(name, dir, config, &rotation)?,
+            };
+            sinks.insert(name.clone(), sink);
+        }
+        let spool = match &config.spool {
+            Some(spool) => Some(Arc::new(Spool::open(spool, dir)?)),
+            None => None,
+        };
+        Ok(Sinks {
+            sinks,
+            config: config.clone(),
+            spool,
+        })
+    }
+
+    pub fn insert(&mut self, name: &str, sink: Arc<dyn LogSink>) {
+        self.sinks.insert(name.to_string(), sink);
+    }
+
+    // Every route must name a known sink.
+    pub fn check(&self) -> io::Result<()> {
+        for bucket in self.config.buckets.values() {
+            for route in bucket.sinks.iter() {
+                if !self.sinks.contains_key(&route.name) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown sink: {}", route.name),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn routes(&self, bucket: &str) -> Vec<(String, Policy)> {
+        match self.config.bucket(bucket) {
+            Some(b) if !b.sinks.is_empty() => {
+                b.sinks.iter().map(|r| (r.name.clone(), r.policy)).collect()
+            }
+            _ => vec![(String::from(DEFAULT_SINK), Policy::Required)],
+        }
+    }
+
+    // A required sink with spooled records waits for the replay.
+    fn behind(&self, name: &str, policy: Policy) -> bool {
+        policy == Policy::Required && self.spool.as_ref().is_some_and(|s| s.pending(name))
+    }
+
+    // Fan the record out to the sinks of its bucket. Fails if a required
+    // sink fails and the record can not be spooled for it, best-effort
+    // failures are only logged and reported.
+    pub async fn write(&self, record: &Record) -> Result<Vec<Delivery>, SinkError> {
+        let routes = self.routes(&record.bucket);
+        let writes = routes.iter().map(|(name, policy)| async move {
+            if self.behind(name, *policy) {
+                return None;
+            }
+            Some(match self.sinks.get(name) {
+                Some(sink) => sink.write(record).await,
+                None => Err(SinkError::Failed(format!("unknown sink: {}", name))),
+            })
+        });
+        let results = join_all(writes).await;
+
+        let mut deliveries = Vec::new();
+        let mut failed = None;
+        let mut owed = Vec::new();
+        for ((name, policy), result) in routes.into_iter().zip(results) {
+            let required = policy == Policy::Required;
+            let tried = result.is_some();
+            let error = match result {
+                Some(Ok(_)) => None,
+                Some(Err(e)) => {
+                    warn!("sink {} failed for {}: {}", name, record.bucket, e);
+                    let message = e.to_string();
+                    // unavailable wins, so callers can ask for a retry
+                    if required && !matches!(failed, Some(SinkError::Unavailable(_))) {
+                        failed = Some(e);
+                    }
+                    Some(message)
+                }
+                None => None,
+            };
+            if required && (!tried || error.is_some()) {
+                owed.push(name.clone());
+            }
+            deliveries.push(Delivery {
+                sink: name,
+                required,
+                error,
+                spooled: false,
+            });
+        }
+
+        if let (Some(spool), false) = (&self.spool, owed.is_empty()) {
+            let entry = spool::Entry {
+                record: record.clone(),
+                sinks: owed.clone(),
+                error: failed.as_ref().map(|e| e.to_string()),
+            };
+            match spool.push(&entry) {
+                Ok(_) => {
+                    for delivery in deliveries.iter_mut() {
+                        delivery.spooled = owed.contains(&delivery.sink);
+                    }
+                    return Ok(deliveries);
+                }
+                Err(e) => {
+                    warn!("spool failed for {}: {}", record.bucket, e);
+                    // sinks waiting behind the spool were not even tried
+                    if failed.is_none() {
+                        failed = Some(SinkError::Unavailable(e.to_string()));
+                    }
+                }
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(deliveries),
+        }
+    }
+
+    // Write spooled records to their sinks, see `Spool::replay`.
+    pub async fn replay(&self) -> io::Result<usize> {
+        match &self.spool {
+            Some(spool) => spool.replay(&self.sinks).await,
+            None => Ok(0),
+        }
+    }
+
+    pub fn start(&self) {
+        for sink in self.sinks.values() {
+            sink.clone().start();
+        }
+        if let Some(spool) = &self.spool {
+            let every = Duration::from_secs(spool.replay_interval().max(1));
+            let sinks = self.clone();
+            rt::spawn(async move {
+                let mut interval = rt::time::interval(every);
+                loop {
+                    interval.tick().await;
+                    match sinks.replay().await {
+                        Ok(0) => {}
+                        Ok(n) => info!("spool: {} records replayed", n),
+                        Err(e) => warn!("spool replay failed: {}", e),
+                    }
+                }
+            });
+        }
+    }
+
+    pub async fn flush(&self) {
+        for (name, sink) in self.sinks.iter() {
+            if let Err(e) = sink.flush().await {
+                warn!("flush {} failed: {}", name, e);
+            }
+        }
+    }
+
+    pub async fn health(&self) -> BTreeMap<String, Health> {
+        let mut health = BTreeMap::new();
+        for (name, sink) in self.sinks.iter() {
+            health.insert(name.clone(), sink.health().await);
+        }
+        health
+    }
+
+    pub async fn status(&self) -> Status {
+        Status {
+            sinks: self.health().await,
+            spool: self.spool.as_ref().map(|s| s.status()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BucketConfig, Route};
+
+    #[derive(Debug)]
+    struct Broken(bool);
+
+    #[async_trait(?Send)]
+    impl LogSink for Broken {
+        async fn write(&self, _: &Record) -> Result<(), SinkError> {
+            if self.0 {
+                Err(SinkError::Unavailable(String::from("down")))
+            } else {
+                Err(SinkError::Failed(String::from("broken")))
+            }
+        }
+    }
+
+    pub(crate) fn record(bucket: &str) -> Record {
+        Record {
+            time: "2022-04-01T08:00:00+08:00".parse().unwrap(),
+            bucket: bucket.to_string(),
+            device_id: String::from("100"),
+            cat: String::from("text"),
+            from: String::from("10086"),
+            body: String::from("中文\n你好"),
+            meta: BTreeMap::new(),
+        }
+    }
+
+    fn route(name: &str, policy: Policy) -> Route {
+        Route {
+            name: name.to_string(),
+            policy,
+        }
+    }
+
+    #[test]
+    fn test_record_line() {
+        assert_eq!(
+            record("sms").line(),
+            "[2022-04-01T08:00:00+08:00]\t100|sms\t####\ttext\t10086\t{}\t中文||你好"
+        );
+        let mut record = record("sms");
+        record
+            .meta
+            .insert(String::from("real_ip"), String::from("1.1.1.1"));
+        assert_eq!(
+            record.line(),
+            "[2022-04-01T08:00:00+08:00]\t100|sms\t####\ttext\t10086\t{\"real_ip\":\"1.1.1.1\"}\t中文||你好"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_sinks_policy() {
+        let mut config = Config::default();
+        config.buckets.insert(
+            String::from("sms"),
+            BucketConfig {
+                sinks: vec![
+                    route("file", Policy::Required),
+                    route("broken", Policy::BestEffort),
+                ],
+                ..Default::default()
+            },
+        );
+        config.buckets.insert(
+            String::from("otp"),
+            BucketConfig {
+                sinks: vec![
+                    route("broken", Policy::Required),
+                    route("down", Policy::Required),
+                ],
+                ..Default::default()
+            },
+        );
+        let dir = Path::new("./logs/web_hook_test/sink");
+        let mut sinks = Sinks::build(&config, dir, Default::default()).unwrap();
+        assert!(sinks.check().is_err());
+        sinks.insert("broken", Arc::new(Broken(false)));
+        sinks.insert("down", Arc::new(Broken(true)));
+        assert!(sinks.check().is_ok());
+
+        let deliveries = sinks.write(&record("sms")).await.unwrap();
+        assert_eq!(deliveries.len(), 2);
+        assert!(deliveries[0].error.is_none());
+        assert!(deliveries[1].error.is_some());
+
+        let result = sinks.write(&record("otp")).await;
+        assert!(matches!(result, Err(SinkError::Unavailable(_))));
+
+        // unrouted buckets go to the file sink
+        let deliveries = sinks.write(&record("gps")).await.unwrap();
+        assert_eq!(deliveries[0].sink, DEFAULT_SINK);
+    }
+
+    #[test]
+    fn test_sinks_file_dirs() {
+        let dir = Path::new("./logs/web_hook_test/sink_dirs");
+        let mut config = Config::default();
+        config
+            .sinks
+            .insert(String::from("local"), SinkConfig::File { dir: None });
+        assert!(Sinks::build(&config, dir, Default::default()).is_err());
+
+        let copy = String::from("./logs/web_hook_test/sink_dirs_copy");
+        config.sinks.clear();
+        for name in ["a", "b"] {
+            let sink = SinkConfig::File {
+                dir: Some(copy.clone()),
+            };
+            config.sinks.insert(name.to_string(), sink);
+        }
+        let sinks = Sinks::build(&config, dir, Default::default()).unwrap();
+        assert!(Arc::ptr_eq(&sinks.sinks["a"], &sinks.sinks["b"]));
+    }
+
+    #[test]
+    fn test_file_late_record() {
+        let dir = Path::new("./logs/web_hook_test/sink_late");
+        let _ = fs::remove_dir_all(dir);
+        let sink = FileSink::new(dir, Config::default(), Default::default()).unwrap();
+
+        // a replayed record of a closed day goes to today, as it came in
+        let path = sink.append(&record("sms")).unwrap();
+        let today = Utc::now().format("%Y%m%d").to_string();
+        assert_eq!(path, dir.join(format!("sms/100/{}.log", today)));
+        let line = fs::read_to_string(&path).unwrap();
+        assert!(line.contains("\"record_time\":\"2022-04-01T08:00:00+08:00\""));
+    }
+}
@@ -0,0 +1,298 @@
+use super::{Health, LogSink, Payload, Record, SinkError, Template};
+use actix_web::web;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+//   { "type": "nats", "address": "127.0.0.1:4222",
+//     "subject": "hooks.{bucket}.{device_id}.{cat}", "payload": "line" }
+#[derive(Debug, Clone, Deserialize)]
+pub struct NatsConfig {
+    pub address: String,
+    #[serde(default = "default_subject")]
+    pub subject: Template,
+    #[serde(default)]
+    pub payload: Payload,
+    pub token: Option<String>,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    // seconds for connect, write and the server's PONG
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_subject() -> Template {
+    "{bucket}.{device_id}.{cat}".parse().unwrap()
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+// A value as one subject token.
+fn token(value: &str) -> String {
+    if value.is_empty() {
+        return String::from("_");
+    }
+    value
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Connect<'a> {
+    verbose: bool,
+    pedantic: bool,
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_token: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pass: Option<&'a str>,
+}
+
+#[derive(Debug)]
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Conn {
+    // Wait for the PONG to a PING, which also flushes earlier PUBs.
+    fn pong(&mut self) -> io::Result<()> {
+        self.writer.write_all(b"PING\r\n")?;
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "nats closed"));
+            }
+            let line = line.trim_end();
+            match line.split(' ').next().unwrap_or_default() {
+                "PONG" => return Ok(()),
+                "PING" => self.writer.write_all(b"PONG\r\n")?,
+                "-ERR" => return Err(io::Error::other(line.to_string())),
+                // INFO updates and +OK
+                _ => {}
+            }
+        }
+    }
+}
+
+// Publishes each record on a subject built from it, '.', wildcards and
+// whitespace in values become '_'.
+#[derive(Debug)]
+pub struct NatsSink(Arc<Nats>);
+
+#[derive(Debug)]
+struct Nats {
+    config: NatsConfig,
+    conn: Mutex<Option<Conn>>,
+}
+
+impl NatsSink {
+    pub fn new(config: NatsConfig) -> Self {
+        NatsSink(Arc::new(Nats {
+            config,
+            conn: Mutex::new(None),
+        }))
+    }
+
+    pub fn subject(&self, record: &Record) -> String {
+        self.0.subject(record)
+    }
+}
+
+impl Nats {
+    fn subject(&self, record: &Record) -> String {
+        self.config.subject.render_with(record, token)
+    }
+
+    fn connect(&self) -> io::Result<Conn> {
+        let timeout = Duration::from_secs(self.config.timeout);
+        let addr = self
+            .config
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_read_timeout(Some(timeout))?;
+        let mut conn = Conn {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        // the server speaks first
+        let mut info = String::new();
+        conn.reader.read_line(&mut info)?;
+        if !info.starts_with("INFO") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("nats: {}", info.trim_end()),
+            ));
+        }
+        let connect = Connect {
+            verbose: false,
+            pedantic: false,
+            name: env!("CARGO_PKG_NAME"),
+            auth_token: self.config.token.as_deref(),
+            user: self.config.user.as_deref(),
+            pass: self.config.pass.as_deref(),
+        };
+        let line = format!("CONNECT {}\r\n", serde_json::to_string(&connect)?);
+        conn.writer.write_all(line.as_bytes())?;
+        conn.pong()?;
+        Ok(conn)
+    }
+
+    fn publish(&self, record: &Record) -> Result<(), SinkError> {
+        let payload = self.config.payload.render(record);
+        let mut message =
+            format!("PUB {} {}\r\n", self.subject(record), payload.len()).into_bytes();
+        message.extend_from_slice(payload.as_bytes());
+        message.extend_from_slice(b"\r\n");
+
+        let mut conn = self.conn.lock().unwrap();
+        // one reconnect, the server may have dropped us
+        let mut last = None;
+        for _ in 0..2 {
+            if conn.is_none() {
+                *conn = Some(
+                    self.connect()
+                        .map_err(|e| SinkError::Unavailable(e.to_string()))?,
+                );
+            }
+            let c = conn.as_mut().unwrap();
+            match c.writer.write_all(&message).and_then(|_| c.pong()) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    *conn = None;
+                    last = Some(e);
+                }
+            }
+        }
+        Err(SinkError::Unavailable(last.unwrap().to_string()))
+    }
+
+    fn health(&self) -> Health {
+        let mut conn = self.conn.lock().unwrap();
+        let result = match conn.as_mut() {
+            Some(conn) => conn.pong(),
+            None => self.connect().map(|_| ()),
+        };
+        match result {
+            Ok(_) => Health::ok(),
+            Err(e) => {
+                *conn = None;
+                Health::failing(e)
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSink for NatsSink {
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        let nats = self.0.clone();
+        let record = record.clone();
+        web::block(move || nats.publish(&record)).await?
+    }
+
+    async fn health(&self) -> Health {
+        let nats = self.0.clone();
+        web::block(move || nats.health())
+            .await
+            .unwrap_or_else(Health::failing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Takes a CONNECT and one PUB, answering each PING.
+    fn serve(listener: TcpListener) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer
+                .write_all(b"INFO {\"server_id\":\"test\"}\r\n")
+                .unwrap();
+            let mut lines = Vec::new();
+            while lines.len() < 5 {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                if line == "PING" {
+                    writer.write_all(b"PONG\r\n").unwrap();
+                }
+                lines.push(line);
+            }
+            lines
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_nats_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = serve(listener);
+        let sink = NatsSink::new(NatsConfig {
+            address,
+            subject: "hooks.{bucket}.{device_id}.{cat}".parse().unwrap(),
+            payload: Payload::Json,
+            token: Some(String::from("t0ken")),
+            user: None,
+            pass: None,
+            timeout: 5,
+        });
+        sink.write(&record("sms")).await.unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(
+            lines[0],
+            "CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"web_hook\",\"auth_token\":\"t0ken\"}"
+        );
+        assert_eq!(lines[1], "PING");
+        let payload = serde_json::to_string(&record("sms")).unwrap();
+        assert_eq!(
+            lines[2],
+            format!("PUB hooks.sms.100.text {}", payload.len())
+        );
+        assert_eq!(lines[3], payload);
+        assert_eq!(lines[4], "PING");
+
+        let mut odd = record("sms");
+        odd.device_id = String::from("a.b");
+        odd.cat = String::new();
+        odd.from = String::from("*> x");
+        let t = NatsSink::new(NatsConfig {
+            address: String::new(),
+            subject: "hooks.{device_id}.{cat}.{from}".parse().unwrap(),
+            payload: Payload::Line,
+            token: None,
+            user: None,
+            pass: None,
+            timeout: 5,
+        });
+        assert_eq!(t.subject(&odd), "hooks.a_b._.___x");
+    }
+}
@@ -0,0 +1,434 @@
+use super::{quote, Health, LogSink, Record, SinkError};
+use async_trait::async_trait;
+use chrono::prelude::*;
+use chrono::Duration as Days;
+use deadpool_postgres::{
+    Client, Manager, ManagerConfig, Pool, PoolError, RecyclingMethod, Runtime,
+};
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::NoTls;
+
+// Rows go to a table partitioned by day (UTC). The first writer to find the
+// queue idle inserts everything queued, the others wait for it.
+pub struct PostgresSink {
+    pool: Pool,
+    table: String,
+    batch_size: usize,
+    max_pending: usize,
+    queue: Mutex<Queue>,
+    // the table itself and the days whose partition exists
+    created: Mutex<HashSet<String>>,
+}
+
+// columns of a row, and the bind parameters one statement may have
+const COLUMNS: usize = 7;
+const MAX_PARAMS: usize = 65535;
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<Pending>,
+    writing: bool,
+}
+
+struct Pending {
+    record: Record,
+    done: oneshot::Sender<Result<(), SinkError>>,
+}
+
+// Hands the queue back if the writing request goes away mid batch, records
+// it had not taken yet are failed so their requests do not hang. A writer
+// that finished hands it back itself, along with finding the queue empty.
+struct Writing<'a> {
+    queue: &'a Mutex<Queue>,
+    finished: bool,
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        queue.writing = false;
+        for pending in queue.pending.drain(..) {
+            let _ = pending.done.send(Err(SinkError::Unavailable(String::from(
+                "postgres writer cancelled",
+            ))));
+        }
+    }
+}
+
+impl fmt::Debug for PostgresSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PostgresSink")
+            .field("table", &self.table)
+            .field("batch_size", &self.batch_size)
+            .field("max_pending", &self.max_pending)
+            .finish()
+    }
+}
+
+impl From<tokio_postgres::Error> for SinkError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        let unavailable = match e.code() {
+            // connection exceptions, insufficient resources, shutting down
+            Some(code) => {
+                code.code().starts_with("08")
+                    || code.code().starts_with("53")
+                    || code.code().starts_with("57P")
+            }
+            None => true,
+        };
+        if unavailable {
+            SinkError::Unavailable(e.to_string())
+        } else {
+            SinkError::Failed(e.to_string())
+        }
+    }
+}
+
+impl From<PoolError> for SinkError {
+    fn from(e: PoolError) -> Self {
+        SinkError::Unavailable(e.to_string())
+    }
+}
+
+impl PostgresSink {
+    pub fn new(
+        url: &str,
+        table: &str,
+        pool_size: usize,
+        batch_size: usize,
+        max_pending: usize,
+        timeout: Duration,
+    ) -> Result<Self, String> {
+        let mut pg: tokio_postgres::Config = url.parse().map_err(|e| format!("{}", e))?;
+        pg.connect_timeout(timeout);
+        let manager = Manager::from_config(
+            pg,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager)
+            .max_size(pool_size)
+            .runtime(Runtime::Tokio1)
+            .wait_timeout(Some(timeout))
+            .create_timeout(Some(timeout))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(PostgresSink {
+            pool,
+            table: table.to_string(),
+            batch_size: batch_size.clamp(1, MAX_PARAMS / COLUMNS),
+            max_pending,
+            queue: Mutex::new(Queue::default()),
+            created: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn partition(&self, day: NaiveDate) -> String {
+        format!("{}_{}", self.table, day.format("%Y%m%d"))
+    }
+
+    // Queue depth, records waiting for the current batch to finish.
+    pub fn pending(&self) -> usize {
+        self.queue.lock().unwrap().pending.len()
+    }
+
+    async fn drain(&self) {
+        let mut writing = Writing {
+            queue: &self.queue,
+            finished: false,
+        };
+        loop {
+            let batch: Vec<Pending> = {
+                let mut queue = self.queue.lock().unwrap();
+                let n = queue.pending.len().min(self.batch_size);
+                if n == 0 {
+                    // under the same lock, a record queued now finds no writer
+                    queue.writing = false;
+                    writing.finished = true;
+                    return;
+                }
+                queue.pending.drain(..n).collect()
+            };
+            let records: Vec<&Record> = batch.iter().map(|p| &p.record).collect();
+            let results = self.insert(&records).await;
+            for (pending, result) in batch.into_iter().zip(results) {
+                let _ = pending.done.send(result);
+            }
+        }
+    }
+
+    // One result per record. A batch refused for its data, say a NUL in a
+    // body, is retried row by row so only the bad records fail.
+    async fn insert(&self, records: &[&Record]) -> Vec<Result<(), SinkError>> {
+        let all = |result: Result<(), SinkError>| vec![result; records.len()];
+        let client = match self.pool.get().await {
+            Ok(client) => client,
+            Err(e) => return all(Err(e.into())),
+        };
+        if let Err(e) = self.create(&client, records).await {
+            return all(Err(e));
+        }
+        match execute(&client, &self.table, records).await {
+            Err(e) if records.len() > 1 && is_data_error(&e) => {
+                let mut results = Vec::new();
+                for record in records {
+                    let result = execute(&client, &self.table, &[record]).await;
+                    results.push(result.map_err(SinkError::from));
+                }
+                results
+            }
+            result => all(result.map_err(SinkError::from)),
+        }
+    }
+
+    // Table and partitions for the days of a batch, once per process.
+    async fn create(&self, client: &Client, records: &[&Record]) -> Result<(), SinkError> {
+        let days: BTreeSet<NaiveDate> = records
+            .iter()
+            .map(|r| r.time.with_timezone(&Utc).date_naive())
+            .collect();
+        let (table, missing) = {
+            let created = self.created.lock().unwrap();
+            let missing: Vec<NaiveDate> = days
+                .into_iter()
+                .filter(|d| !created.contains(&self.partition(*d)))
+                .collect();
+            (!created.contains(&self.table), missing)
+        };
+
+        if table {
+            let t = quote(&self.table);
+            ignore_exists(
+                client
+                    .batch_execute(&format!(
+                        "CREATE TABLE IF NOT EXISTS {t} (
+                            id BIGSERIAL,
+                            time TIMESTAMPTZ NOT NULL,
+                            bucket TEXT NOT NULL,
+                            device_id TEXT NOT NULL,
+                            cat TEXT NOT NULL,
+                            \"from\" TEXT NOT NULL,
+                            body TEXT NOT NULL,
+                            meta JSONB NOT NULL DEFAULT '{{}}'
+                        ) PARTITION BY RANGE (time);
+                        CREATE INDEX IF NOT EXISTS {device} ON {t} (bucket, device_id, time);",
+                        t = t,
+                        device = quote(&format!("{}_device", self.table)),
+                    ))
+                    .await,
+            )?;
+            self.created.lock().unwrap().insert(self.table.clone());
+        }
+
+        for day in missing {
+            let partition = self.partition(day);
+            ignore_exists(
+                client
+                    .batch_execute(&format!(
+                        "CREATE TABLE IF NOT EXISTS {} PARTITION OF {} \
+                         FOR VALUES FROM ('{} 00:00:00+00') TO ('{} 00:00:00+00')",
+                        quote(&partition),
+                        quote(&self.table),
+                        day,
+                        day + Days::days(1),
+                    ))
+                    .await,
+            )?;
+            self.created.lock().unwrap().insert(partition);
+        }
+        Ok(())
+    }
+}
+
+// One multi-row INSERT.
+async fn execute(
+    client: &Client,
+    table: &str,
+    records: &[&Record],
+) -> Result<(), tokio_postgres::Error> {
+    let metas: Vec<serde_json::Value> = records
+        .iter()
+        .map(|r| serde_json::to_value(&r.meta).unwrap())
+        .collect();
+    let mut rows = Vec::new();
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    for (record, meta) in records.iter().zip(metas.iter()) {
+        let n = params.len();
+        rows.push(format!(
+            "(${}, ${}, ${}, ${}, ${}, ${}, ${})",
+            n + 1,
+            n + 2,
+            n + 3,
+            n + 4,
+            n + 5,
+            n + 6,
+            n + 7
+        ));
+        params.push(&record.time);
+        params.push(&record.bucket);
+        params.push(&record.device_id);
+        params.push(&record.cat);
+        params.push(&record.from);
+        params.push(&record.body);
+        params.push(meta);
+    }
+    let sql = format!(
+        "INSERT INTO {} (time, bucket, device_id, cat, \"from\", body, meta) VALUES {}",
+        quote(table),
+        rows.join(", ")
+    );
+    client.execute(sql.as_str(), &params).await?;
+    Ok(())
+}
+
+// Class 22, the values of a row were refused.
+fn is_data_error(e: &tokio_postgres::Error) -> bool {
+    e.code().is_some_and(|code| code.code().starts_with("22"))
+}
+
+// Another instance may create the same table in between.
+fn ignore_exists(result: Result<(), tokio_postgres::Error>) -> Result<(), SinkError> {
+    match result {
+        Err(e) if e.code() != Some(&SqlState::DUPLICATE_TABLE) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSink for PostgresSink {
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        let (done, result) = oneshot::channel();
+        let lead = {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.pending.len() >= self.max_pending {
+                return Err(SinkError::Unavailable(String::from("postgres queue full")));
+            }
+            queue.pending.push_back(Pending {
+                record: record.clone(),
+                done,
+            });
+            !std::mem::replace(&mut queue.writing, true)
+        };
+        if lead {
+            self.drain().await;
+        }
+        result.await.unwrap_or_else(|_| {
+            Err(SinkError::Unavailable(String::from(
+                "postgres writer cancelled",
+            )))
+        })
+    }
+
+    async fn health(&self) -> Health {
+        let client = match self.pool.get().await {
+            Ok(client) => client,
+            Err(e) => return Health::failing(e),
+        };
+        match client.simple_query("SELECT 1").await {
+            Ok(_) => Health::ok(),
+            Err(e) => Health::failing(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+    use futures_util::future::join_all;
+    use std::env;
+
+    fn sink(url: &str, table: &str) -> PostgresSink {
+        PostgresSink::new(url, table, 2, 2, 10, Duration::from_secs(2)).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_postgres_unavailable() {
+        let sink = sink("postgres://postgres@127.0.0.1:1/postgres", "records");
+        let result = sink.write(&record("sms")).await;
+        assert!(matches!(result, Err(SinkError::Unavailable(_))));
+        assert_eq!(sink.pending(), 0);
+        assert!(!sink.health().await.ok);
+
+        // one statement stays within the bind parameters postgres takes
+        let big = PostgresSink::new(
+            "postgres://postgres@127.0.0.1:1/postgres",
+            "records",
+            2,
+            100_000,
+            10,
+            Duration::from_secs(2),
+        )
+        .unwrap();
+        assert!(big.batch_size * COLUMNS <= MAX_PARAMS);
+    }
+
+    // Set WEB_HOOK_TEST_POSTGRES to a database url to run against it.
+    #[actix_web::test]
+    async fn test_postgres_sink() {
+        let url = match env::var("WEB_HOOK_TEST_POSTGRES") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let sink = sink(&url, "web_hook_test");
+        let client = sink.pool.get().await.unwrap();
+        client
+            .batch_execute("DROP TABLE IF EXISTS web_hook_test")
+            .await
+            .unwrap();
+
+        let mut next_day = record("gps");
+        next_day.time = "2022-04-02T08:00:00+08:00".parse().unwrap();
+        let records = [record("sms"), record("sms"), record("otp"), next_day];
+        let results = join_all(records.iter().map(|r| sink.write(r))).await;
+        assert!(results.iter().all(|r| r.is_ok()));
+
+        let count = |table: &'static str| {
+            let client = &client;
+            async move {
+                let row = client
+                    .query_one(format!("SELECT COUNT(*) FROM {}", table).as_str(), &[])
+                    .await
+                    .unwrap();
+                row.get::<_, i64>(0)
+            }
+        };
+        assert_eq!(count("web_hook_test").await, 4);
+        assert_eq!(count("web_hook_test_20220401").await, 3);
+        assert_eq!(count("web_hook_test_20220402").await, 1);
+
+        let row = client
+            .query_one(
+                "SELECT body, time FROM web_hook_test WHERE bucket = 'otp'",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>(0), "中文\n你好");
+        assert_eq!(
+            row.get::<_, DateTime<Utc>>(1),
+            "2022-04-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert!(sink.health().await.ok);
+
+        // a NUL fails its own record, not the batch
+        let mut bad = record("sms");
+        bad.body = String::from("a\u{0}b");
+        let records = [record("sms"), bad];
+        let results = join_all(records.iter().map(|r| sink.write(r))).await;
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(SinkError::Failed(_))));
+        assert_eq!(count("web_hook_test").await, 5);
+    }
+}
@@ -0,0 +1,322 @@
+use super::{Health, LogSink, Payload, Record, SinkError, Template};
+use actix_web::web;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+//   { "type": "redis", "address": "127.0.0.1:6379", "stream": "hooks:{bucket}",
+//     "maxlen": 100000, "payload": "json" }
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisConfig {
+    pub address: String,
+    #[serde(default = "default_stream")]
+    pub stream: Template,
+    // trims the stream to about this many entries, 0 keeps all
+    #[serde(default = "default_maxlen")]
+    pub maxlen: u64,
+    #[serde(default)]
+    pub payload: Payload,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    // seconds for connect, write and reply
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_stream() -> Template {
+    "hooks:{bucket}".parse().unwrap()
+}
+
+fn default_maxlen() -> u64 {
+    100000
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+#[derive(Debug)]
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+// XADD of each record, with bucket, device_id and cat next to the payload.
+#[derive(Debug)]
+pub struct RedisSink(Arc<Redis>);
+
+#[derive(Debug)]
+struct Redis {
+    config: RedisConfig,
+    conn: Mutex<Option<Conn>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+}
+
+impl Conn {
+    fn call(&mut self, args: &[&str]) -> io::Result<Reply> {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(command.as_bytes())?;
+        self.read()
+    }
+
+    fn read(&mut self) -> io::Result<Reply> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "redis closed"));
+        }
+        let line = line.trim_end();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("redis: {}", line));
+        match line.split_at(1.min(line.len())) {
+            ("+", status) => Ok(Reply::Status(status.to_string())),
+            ("-", error) => Ok(Reply::Error(error.to_string())),
+            (":", n) => n.parse().map(Reply::Integer).map_err(|_| invalid()),
+            ("$", n) => {
+                let n: i64 = n.parse().map_err(|_| invalid())?;
+                if n < 0 {
+                    return Ok(Reply::Bulk(None));
+                }
+                let mut buf = vec![0; n as usize + 2];
+                self.reader.read_exact(&mut buf)?;
+                buf.truncate(n as usize);
+                Ok(Reply::Bulk(Some(String::from_utf8_lossy(&buf).to_string())))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl RedisSink {
+    pub fn new(config: RedisConfig) -> Self {
+        RedisSink(Arc::new(Redis {
+            config,
+            conn: Mutex::new(None),
+        }))
+    }
+
+    // The entry id given by the server.
+    pub async fn add(&self, record: &Record) -> Result<String, SinkError> {
+        let redis = self.0.clone();
+        let record = record.clone();
+        web::block(move || redis.add(&record)).await?
+    }
+}
+
+impl Redis {
+    fn connect(&self) -> io::Result<Conn> {
+        let timeout = Duration::from_secs(self.config.timeout);
+        let addr = self
+            .config
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_read_timeout(Some(timeout))?;
+        let mut conn = Conn {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        let mut setup = Vec::new();
+        if let Some(password) = &self.config.password {
+            setup.push(vec![String::from("AUTH"), password.clone()]);
+        }
+        if let Some(db) = self.config.db {
+            setup.push(vec![String::from("SELECT"), db.to_string()]);
+        }
+        for command in setup {
+            let args: Vec<&str> = command.iter().map(|s| s.as_str()).collect();
+            if let Reply::Error(e) = conn.call(&args)? {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+            }
+        }
+        Ok(conn)
+    }
+
+    fn add(&self, record: &Record) -> Result<String, SinkError> {
+        let stream = self.config.stream.render(record);
+        let payload = self.config.payload.render(record);
+        let maxlen = self.config.maxlen.to_string();
+        let mut args = vec!["XADD", stream.as_str()];
+        if self.config.maxlen > 0 {
+            args.extend(["MAXLEN", "~", maxlen.as_str()]);
+        }
+        args.extend([
+            "*",
+            "payload",
+            payload.as_str(),
+            "bucket",
+            record.bucket.as_str(),
+            "device_id",
+            record.device_id.as_str(),
+            "cat",
+            record.cat.as_str(),
+        ]);
+
+        let mut conn = self.conn.lock().unwrap();
+        // one reconnect, the server may have closed an idle connection
+        let mut last = None;
+        for _ in 0..2 {
+            if conn.is_none() {
+                *conn = Some(
+                    self.connect()
+                        .map_err(|e| SinkError::Unavailable(e.to_string()))?,
+                );
+            }
+            match conn.as_mut().unwrap().call(&args) {
+                Ok(Reply::Bulk(Some(id))) => return Ok(id),
+                // the server said no, retrying will not help
+                Ok(Reply::Error(e)) => return Err(SinkError::Failed(e)),
+                Ok(reply) => return Err(SinkError::Failed(format!("XADD: {:?}", reply))),
+                Err(e) => {
+                    *conn = None;
+                    last = Some(e);
+                }
+            }
+        }
+        Err(SinkError::Unavailable(last.unwrap().to_string()))
+    }
+
+    fn health(&self) -> Health {
+        let mut conn = self.conn.lock().unwrap();
+        let result = match conn.as_mut() {
+            Some(conn) => conn.call(&["PING"]),
+            None => self.connect().and_then(|mut c| c.call(&["PING"])),
+        };
+        match result {
+            Ok(Reply::Error(e)) => Health::failing(e),
+            Ok(_) => Health::ok(),
+            Err(e) => {
+                *conn = None;
+                Health::failing(e)
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSink for RedisSink {
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        self.add(record).await.map(|_| ())
+    }
+
+    async fn health(&self) -> Health {
+        let redis = self.0.clone();
+        web::block(move || redis.health())
+            .await
+            .unwrap_or_else(Health::failing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Reads one command, answers with an entry id or an error.
+    fn serve(listener: TcpListener) -> thread::JoinHandle<Vec<Vec<String>>> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut commands = Vec::new();
+            for i in 0..2 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let n: usize = line.trim()[1..].parse().unwrap();
+                let mut args = Vec::new();
+                for _ in 0..n {
+                    let mut len = String::new();
+                    reader.read_line(&mut len).unwrap();
+                    let len: usize = len.trim()[1..].parse().unwrap();
+                    let mut buf = vec![0; len + 2];
+                    reader.read_exact(&mut buf).unwrap();
+                    args.push(String::from_utf8_lossy(&buf[..len]).to_string());
+                }
+                commands.push(args);
+                let reply = match i {
+                    0 => "$15\r\n1648771200000-0\r\n",
+                    _ => "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+                };
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+            commands
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_redis_xadd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = serve(listener);
+        let sink = RedisSink::new(RedisConfig {
+            address,
+            stream: default_stream(),
+            maxlen: 1000,
+            payload: Payload::Line,
+            password: None,
+            db: None,
+            timeout: 5,
+        });
+
+        assert_eq!(sink.add(&record("sms")).await.unwrap(), "1648771200000-0");
+        let result = sink.write(&record("sms")).await;
+        assert!(matches!(result, Err(SinkError::Failed(_))));
+
+        let commands = server.join().unwrap();
+        assert_eq!(
+            commands[0],
+            vec![
+                "XADD",
+                "hooks:sms",
+                "MAXLEN",
+                "~",
+                "1000",
+                "*",
+                "payload",
+                "[2022-04-01T08:00:00+08:00]\t100|sms\t####\ttext\t10086\t{}\t中文||你好",
+                "bucket",
+                "sms",
+                "device_id",
+                "100",
+                "cat",
+                "text"
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_redis_unavailable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let sink = RedisSink::new(RedisConfig {
+            address,
+            stream: default_stream(),
+            maxlen: 0,
+            payload: Payload::Json,
+            password: None,
+            db: None,
+            timeout: 1,
+        });
+        let result = sink.write(&record("sms")).await;
+        assert!(matches!(result, Err(SinkError::Unavailable(_))));
+    }
+}
@@ -0,0 +1,394 @@
+use super::{LogSink, Record};
+use chrono::prelude::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+//   "spool": { "dir": "/var/spool/web_hook", "max_size": 268435456 }
+// Records required sinks could not take, written again later.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpoolConfig {
+    // defaults to `.spool` in the work dir, better on another disk
+    pub dir: Option<String>,
+    // bytes, records beyond are refused with 503
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    // seconds between replays
+    #[serde(default = "default_replay_interval")]
+    pub replay_interval: u64,
+}
+
+fn default_max_size() -> u64 {
+    256 << 20
+}
+
+fn default_replay_interval() -> u64 {
+    5
+}
+
+// One spooled record and the sinks still owed it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub record: Record,
+    pub sinks: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SpoolStatus {
+    pub depth: usize,
+    pub bytes: u64,
+    pub max_size: u64,
+    // spooled records per sink
+    pub sinks: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    depth: usize,
+    bytes: u64,
+    pending: HashMap<String, usize>,
+}
+
+// One file per record, replayed in order. Entries that do not load go to
+// `corrupt/`.
+#[derive(Debug)]
+pub struct Spool {
+    config: SpoolConfig,
+    dir: PathBuf,
+    seq: AtomicU64,
+    state: Mutex<State>,
+    replaying: tokio::sync::Mutex<()>,
+}
+
+impl Spool {
+    pub fn open(config: &SpoolConfig, dir: &Path) -> io::Result<Self> {
+        let dir = match &config.dir {
+            Some(own) => PathBuf::from(own),
+            None => dir.join(".spool"),
+        };
+        fs::create_dir_all(&dir)?;
+        let spool = Spool {
+            config: config.clone(),
+            dir,
+            seq: AtomicU64::new(0),
+            state: Mutex::new(State::default()),
+            replaying: tokio::sync::Mutex::new(()),
+        };
+        // pick up what an earlier run left behind, less its half-written saves
+        for entry in fs::read_dir(&spool.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "tmp") {
+                fs::remove_file(&path)?;
+            }
+        }
+        let mut state = State::default();
+        for path in spool.entries()? {
+            let entry = match load(&path) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    spool.quarantine(&path, &e)?;
+                    continue;
+                }
+            };
+            state.depth += 1;
+            state.bytes += fs::metadata(&path)?.len();
+            for sink in entry.sinks {
+                *state.pending.entry(sink).or_default() += 1;
+            }
+        }
+        *spool.state.lock().unwrap() = state;
+        Ok(spool)
+    }
+
+    pub fn replay_interval(&self) -> u64 {
+        self.config.replay_interval
+    }
+
+    // Spooled files, oldest first.
+    pub fn entries(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    pub fn pending(&self, sink: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .get(sink)
+            .is_some_and(|n| *n > 0)
+    }
+
+    pub fn push(&self, entry: &Entry) -> io::Result<()> {
+        let data = serde_json::to_vec(entry)?;
+        let mut state = self.state.lock().unwrap();
+        if state.bytes + data.len() as u64 > self.config.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("spool full, {} records", state.depth),
+            ));
+        }
+        let now = Utc::now();
+        let name = format!(
+            "{}{:09}-{:06}.json",
+            now.timestamp(),
+            now.timestamp_subsec_nanos(),
+            self.seq.fetch_add(1, Ordering::Relaxed) % 1_000_000
+        );
+        save(&self.dir.join(name), &data)?;
+        state.depth += 1;
+        state.bytes += data.len() as u64;
+        for sink in entry.sinks.iter() {
+            *state.pending.entry(sink.clone()).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    // Write spooled records to the sinks still owed them, oldest first. A
+    // sink failing again is skipped for the rest of the pass. Returns how
+    // many records left the spool.
+    pub async fn replay(&self, sinks: &HashMap<String, Arc<dyn LogSink>>) -> io::Result<usize> {
+        let _replaying = self.replaying.lock().await;
+        let mut blocked = HashSet::new();
+        let mut replayed = 0;
+        for path in self.entries()? {
+            let mut entry = match load(&path) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let size = fs::metadata(&path)?.len();
+                    self.quarantine(&path, &e)?;
+                    let mut state = self.state.lock().unwrap();
+                    state.depth = state.depth.saturating_sub(1);
+                    state.bytes = state.bytes.saturating_sub(size);
+                    continue;
+                }
+            };
+            let mut done = Vec::new();
+            for name in std::mem::take(&mut entry.sinks) {
+                if blocked.contains(&name) {
+                    entry.sinks.push(name);
+                    continue;
+                }
+                let result = match sinks.get(&name) {
+                    Some(sink) => sink.write(&entry.record).await,
+                    None => {
+                        warn!("spooled record for unknown sink {} dropped", name);
+                        Ok(())
+                    }
+                };
+                match result {
+                    Ok(_) => done.push(name),
+                    Err(e) => {
+                        entry.error = Some(e.to_string());
+                        blocked.insert(name.clone());
+                        entry.sinks.push(name);
+                    }
+                }
+            }
+            if !done.is_empty() {
+                self.update(&path, &entry, &done)?;
+            }
+            if entry.sinks.is_empty() {
+                replayed += 1;
+            }
+        }
+        Ok(replayed)
+    }
+
+    // Store what is left of an entry after a replay, `done` are the sinks
+    // that took the record.
+    fn update(&self, path: &Path, entry: &Entry, done: &[String]) -> io::Result<()> {
+        let before = fs::metadata(path)?.len();
+        let after = if entry.sinks.is_empty() {
+            fs::remove_file(path)?;
+            0
+        } else {
+            let data = serde_json::to_vec(entry)?;
+            save(path, &data)?;
+            data.len() as u64
+        };
+        let mut state = self.state.lock().unwrap();
+        state.bytes = (state.bytes + after).saturating_sub(before);
+        if entry.sinks.is_empty() {
+            state.depth = state.depth.saturating_sub(1);
+        }
+        for sink in done {
+            if let Some(n) = state.pending.get_mut(sink) {
+                *n = n.saturating_sub(1);
+            }
+        }
+        Ok(())
+    }
+
+    // Move an entry that does not load out of the way.
+    fn quarantine(&self, path: &Path, e: &io::Error) -> io::Result<()> {
+        let corrupt = self.dir.join("corrupt");
+        fs::create_dir_all(&corrupt)?;
+        warn!("spool entry {} moved to corrupt/: {}", path.display(), e);
+        fs::rename(path, corrupt.join(path.file_name().unwrap()))
+    }
+
+    pub fn status(&self) -> SpoolStatus {
+        let state = self.state.lock().unwrap();
+        SpoolStatus {
+            depth: state.depth,
+            bytes: state.bytes,
+            max_size: self.config.max_size,
+            sinks: state
+                .pending
+                .iter()
+                .filter(|(_, n)| **n > 0)
+                .map(|(k, n)| (k.clone(), *n))
+                .collect(),
+        }
+    }
+}
+
+fn load(path: &Path) -> io::Result<Entry> {
+    serde_json::from_slice(&fs::read(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn save(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::super::{SinkError, Sinks};
+    use super::*;
+    use crate::config::Config;
+    use async_trait::async_trait;
+    use std::sync::atomic::AtomicBool;
+
+    // Down until told otherwise, keeps the bodies it took.
+    #[derive(Debug, Default)]
+    struct Flaky {
+        up: AtomicBool,
+        bodies: Mutex<Vec<String>>,
+    }
+
+    #[async_trait(?Send)]
+    impl LogSink for Flaky {
+        async fn write(&self, record: &Record) -> Result<(), SinkError> {
+            if !self.up.load(Ordering::Relaxed) {
+                return Err(SinkError::Unavailable(String::from("down")));
+            }
+            self.bodies.lock().unwrap().push(record.body.clone());
+            Ok(())
+        }
+    }
+
+    fn sinks(dir: &Path, max_size: u64) -> (Sinks, Arc<Flaky>) {
+        let _ = fs::remove_dir_all(dir);
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "spool": { "max_size": max_size },
+            "buckets": { "sms": { "sinks": [{ "name": "flaky" }] } },
+        }))
+        .unwrap();
+        let mut sinks = Sinks::build(&config, dir, Default::default()).unwrap();
+        let flaky = Arc::new(Flaky::default());
+        sinks.insert("flaky", flaky.clone());
+        (sinks, flaky)
+    }
+
+    fn with_body(body: &str) -> Record {
+        let mut record = record("sms");
+        record.body = body.to_string();
+        record
+    }
+
+    #[actix_web::test]
+    async fn test_spool_replay() {
+        let dir = Path::new("./logs/web_hook_test/spool");
+        let (sinks, flaky) = sinks(dir, 1 << 20);
+
+        let deliveries = sinks.write(&with_body("1")).await.unwrap();
+        assert!(deliveries[0].spooled);
+        assert!(deliveries[0].error.is_some());
+        // waits behind the first, not even tried
+        let deliveries = sinks.write(&with_body("2")).await.unwrap();
+        assert!(deliveries[0].spooled);
+        assert!(deliveries[0].error.is_none());
+        // unrouted buckets are not held up
+        assert!(!sinks.write(&record("gps")).await.unwrap()[0].spooled);
+
+        let status = sinks.status().await.spool.unwrap();
+        assert_eq!(status.depth, 2);
+        assert_eq!(status.sinks.get("flaky"), Some(&2));
+        assert_eq!(sinks.replay().await.unwrap(), 0);
+
+        flaky.up.store(true, Ordering::Relaxed);
+        assert_eq!(sinks.replay().await.unwrap(), 2);
+        let status = sinks.status().await.spool.unwrap();
+        assert_eq!((status.depth, status.bytes), (0, 0));
+        assert!(status.sinks.is_empty());
+        assert!(!sinks.write(&with_body("3")).await.unwrap()[0].spooled);
+        assert_eq!(*flaky.bodies.lock().unwrap(), vec!["1", "2", "3"]);
+    }
+
+    #[actix_web::test]
+    async fn test_spool_full() {
+        let dir = Path::new("./logs/web_hook_test/spool_full");
+        let (sinks, _) = sinks(dir, 200);
+        sinks.write(&with_body("1")).await.unwrap();
+        let result = sinks.write(&with_body("2")).await;
+        assert!(matches!(result, Err(SinkError::Unavailable(_))));
+
+        // what is spooled survives a restart
+        let config = SpoolConfig {
+            dir: None,
+            max_size: 200,
+            replay_interval: 5,
+        };
+        let spool = Spool::open(&config, dir).unwrap();
+        assert_eq!(spool.status().depth, 1);
+        assert!(spool.pending("flaky"));
+    }
+
+    #[actix_web::test]
+    async fn test_spool_corrupt() {
+        let dir = Path::new("./logs/web_hook_test/spool_corrupt");
+        let (sinks, flaky) = sinks(dir, 1 << 20);
+        sinks.write(&with_body("1")).await.unwrap();
+        sinks.write(&with_body("2")).await.unwrap();
+        let spool = dir.join(".spool");
+        let config = SpoolConfig {
+            dir: None,
+            max_size: 1 << 20,
+            replay_interval: 5,
+        };
+
+        // a bad entry does not keep the spool from opening
+        fs::write(spool.join("0000-000000.json"), b"{\"record\":").unwrap();
+        fs::write(spool.join("1-000000.tmp"), b"{").unwrap();
+        let reopened = Spool::open(&config, dir).unwrap();
+        assert_eq!(reopened.status().depth, 2);
+        assert!(spool.join("corrupt/0000-000000.json").exists());
+        assert!(!spool.join("1-000000.tmp").exists());
+
+        // nor replays after it
+        let first = sinks.spool.as_ref().unwrap().entries().unwrap()[0].clone();
+        fs::write(&first, b"nope").unwrap();
+        flaky.up.store(true, Ordering::Relaxed);
+        assert_eq!(sinks.replay().await.unwrap(), 1);
+        assert_eq!(*flaky.bodies.lock().unwrap(), vec!["2"]);
+        assert_eq!(sinks.status().await.spool.unwrap().depth, 0);
+    }
+}
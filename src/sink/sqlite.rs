@@ -0,0 +1,235 @@
+use super::{quote, Health, LogSink, Record, SinkError};
+use actix_web::web;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, ErrorCode};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TABLE: &str = "records";
+
+// One row per record in a WAL mode database, checkpointed every
+// `checkpoint_every` and on flush.
+pub struct SqliteSink {
+    path: String,
+    table_per_bucket: bool,
+    checkpoint_every: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    conn: Connection,
+    tables: HashSet<String>,
+    checkpointed: Instant,
+}
+
+impl fmt::Debug for SqliteSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SqliteSink")
+            .field("path", &self.path)
+            .field("table_per_bucket", &self.table_per_bucket)
+            .finish()
+    }
+}
+
+impl From<rusqlite::Error> for SinkError {
+    fn from(e: rusqlite::Error) -> Self {
+        match &e {
+            rusqlite::Error::SqliteFailure(f, _)
+                if matches!(f.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) =>
+            {
+                SinkError::Unavailable(e.to_string())
+            }
+            _ => SinkError::Failed(e.to_string()),
+        }
+    }
+}
+
+impl SqliteSink {
+    pub fn open(
+        path: &Path,
+        table_per_bucket: bool,
+        checkpoint_every: Duration,
+    ) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(SqliteSink {
+            path: path.display().to_string(),
+            table_per_bucket,
+            checkpoint_every,
+            state: Arc::new(Mutex::new(State {
+                conn,
+                tables: HashSet::new(),
+                checkpointed: Instant::now(),
+            })),
+        })
+    }
+
+    pub fn table(&self, bucket: &str) -> String {
+        if self.table_per_bucket {
+            format!("{}_{}", DEFAULT_TABLE, bucket)
+        } else {
+            String::from(DEFAULT_TABLE)
+        }
+    }
+
+    pub async fn insert(&self, record: &Record) -> Result<i64, SinkError> {
+        let table = self.table(&record.bucket);
+        let state = self.state.clone();
+        let checkpoint_every = self.checkpoint_every;
+        let record = record.clone();
+        web::block(move || insert(&state, &table, checkpoint_every, &record)).await?
+    }
+}
+
+fn insert(
+    state: &Mutex<State>,
+    table: &str,
+    checkpoint_every: Duration,
+    record: &Record,
+) -> Result<i64, SinkError> {
+    let mut state = state.lock().unwrap();
+    if !state.tables.contains(table) {
+        create_table(&state.conn, table)?;
+        state.tables.insert(table.to_string());
+    }
+
+    let meta = serde_json::to_string(&record.meta).unwrap();
+    state.conn.execute(
+        &format!(
+            "INSERT INTO {} (time, ts, bucket, device_id, cat, \"from\", body, meta) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            quote(table)
+        ),
+        params![
+            record.time.to_rfc3339(),
+            record.time.timestamp_millis(),
+            record.bucket,
+            record.device_id,
+            record.cat,
+            record.from,
+            record.body,
+            meta,
+        ],
+    )?;
+    let id = state.conn.last_insert_rowid();
+
+    if state.checkpointed.elapsed() >= checkpoint_every {
+        checkpoint(&state.conn, "PASSIVE")?;
+        state.checkpointed = Instant::now();
+    }
+    Ok(id)
+}
+
+fn create_table(conn: &Connection, table: &str) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {t} (
+            id INTEGER PRIMARY KEY,
+            time TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            bucket TEXT NOT NULL,
+            device_id TEXT NOT NULL,
+            cat TEXT NOT NULL,
+            \"from\" TEXT NOT NULL,
+            body TEXT NOT NULL,
+            meta TEXT NOT NULL DEFAULT '{{}}'
+        );
+        CREATE INDEX IF NOT EXISTS {device} ON {t} (bucket, device_id, ts);
+        CREATE INDEX IF NOT EXISTS {ts} ON {t} (ts);",
+        t = quote(table),
+        device = quote(&format!("{}_device", table)),
+        ts = quote(&format!("{}_ts", table)),
+    ))
+}
+
+fn checkpoint(conn: &Connection, mode: &str) -> rusqlite::Result<()> {
+    // returns (busy, log frames, checkpointed frames)
+    conn.query_row(&format!("PRAGMA wal_checkpoint({})", mode), [], |_| Ok(()))
+}
+
+#[async_trait(?Send)]
+impl LogSink for SqliteSink {
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        self.insert(record).await.map(|_| ())
+    }
+
+    async fn flush(&self) -> Result<(), SinkError> {
+        let state = self.state.clone();
+        web::block(move || {
+            let mut state = state.lock().unwrap();
+            checkpoint(&state.conn, "TRUNCATE")?;
+            state.checkpointed = Instant::now();
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn health(&self) -> Health {
+        let state = self.state.clone();
+        web::block(move || {
+            let state = state.lock().unwrap();
+            match state.conn.query_row("SELECT 1", [], |_| Ok(())) {
+                Ok(_) => Health::ok(),
+                Err(e) => Health::failing(e),
+            }
+        })
+        .await
+        .unwrap_or_else(Health::failing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+    use std::fs;
+
+    #[actix_web::test]
+    async fn test_sqlite_sink() {
+        let dir = Path::new("./logs/web_hook_test/sqlite");
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("hooks.db");
+        let _ = fs::remove_file(&path);
+
+        let sink = SqliteSink::open(&path, true, Duration::ZERO).unwrap();
+        sink.write(&record("sms")).await.unwrap();
+        sink.write(&record("sms")).await.unwrap();
+        sink.write(&record("gps")).await.unwrap();
+        sink.flush().await.unwrap();
+        assert!(sink.health().await.ok);
+
+        let state = sink.state.lock().unwrap();
+        let count = |table: &str| -> i64 {
+            state
+                .conn
+                .query_row(&format!("SELECT COUNT(*) FROM {}", quote(table)), [], |r| {
+                    r.get(0)
+                })
+                .unwrap()
+        };
+        assert_eq!(count("records_sms"), 2);
+        assert_eq!(count("records_gps"), 1);
+
+        let (time, from, body): (String, String, String) = state
+            .conn
+            .query_row(
+                "SELECT time, \"from\", body FROM records_sms WHERE device_id = ?1",
+                ["100"],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(time, "2022-04-01T08:00:00+08:00");
+        assert_eq!(from, "10086");
+        assert_eq!(body, "中文\n你好");
+
+        let mode: String = state
+            .conn
+            .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+    }
+}
@@ -0,0 +1,370 @@
+use super::{Health, LogSink, Record, SinkError};
+use actix_web::web;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::Deserialize;
+use std::env;
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Structured data id, 32473 is the enterprise number for examples.
+const SD_ID: &str = "hook@32473";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    // octet counted frames, RFC 6587
+    Tcp,
+    Unix,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Rfc5424,
+    Rfc3164,
+}
+
+//   { "type": "syslog", "transport": "tcp", "address": "logs.internal:601",
+//     "format": "rfc5424", "facility": 16 }
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyslogConfig {
+    pub transport: Transport,
+    // "host:port", or the socket path for unix, defaults to /dev/log
+    pub address: Option<String>,
+    #[serde(default = "default_format")]
+    pub format: Format,
+    // 0-23, 1 is user, 16-23 are local0-local7
+    #[serde(default = "default_facility")]
+    pub facility: u8,
+    // 0-7, 6 is informational
+    #[serde(default = "default_severity")]
+    pub severity: u8,
+    // defaults to $HOSTNAME
+    pub hostname: Option<String>,
+    // seconds for connect and write
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_format() -> Format {
+    Format::Rfc5424
+}
+
+fn default_facility() -> u8 {
+    1
+}
+
+fn default_severity() -> u8 {
+    6
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+#[derive(Debug)]
+enum Socket {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+// One message per record, bucket as app name and cat as message id.
+#[derive(Debug)]
+pub struct SyslogSink {
+    config: SyslogConfig,
+    hostname: String,
+    link: Arc<Link>,
+}
+
+#[derive(Debug)]
+struct Link {
+    transport: Transport,
+    address: String,
+    timeout: Duration,
+    socket: Mutex<Option<Socket>>,
+}
+
+impl SyslogSink {
+    pub fn new(config: SyslogConfig) -> io::Result<Self> {
+        if config.facility > 23 || config.severity > 7 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "syslog facility must be 0-23 and severity 0-7",
+            ));
+        }
+        let address = match (&config.address, config.transport) {
+            (Some(address), _) => address.clone(),
+            (None, Transport::Unix) => String::from("/dev/log"),
+            (None, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "syslog address missing",
+                ))
+            }
+        };
+        if cfg!(not(unix)) && config.transport == Transport::Unix {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets need a unix system",
+            ));
+        }
+        let hostname = config
+            .hostname
+            .clone()
+            .or_else(|| env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| String::from("-"));
+        Ok(SyslogSink {
+            hostname: printable(&hostname, 255),
+            link: Arc::new(Link {
+                transport: config.transport,
+                address,
+                timeout: Duration::from_secs(config.timeout),
+                socket: Mutex::new(None),
+            }),
+            config,
+        })
+    }
+
+    pub fn message(&self, record: &Record) -> String {
+        let pri = self.config.facility as u32 * 8 + self.config.severity as u32;
+        let body = record
+            .body
+            .replace("\r\n", "||")
+            .replace(['\r', '\n'], "||");
+        match self.config.format {
+            Format::Rfc5424 => format!(
+                "<{}>1 {} {} {} {} {} [{} device_id=\"{}\" from=\"{}\"] {}",
+                pri,
+                record.time.to_rfc3339_opts(SecondsFormat::Millis, false),
+                self.hostname,
+                or_nil(&printable(&record.bucket, 48)),
+                process::id(),
+                or_nil(&printable(&record.cat, 32)),
+                SD_ID,
+                param(&record.device_id),
+                param(&record.from),
+                body
+            ),
+            Format::Rfc3164 => format!(
+                "<{}>{} {} {}[{}]: device_id={} cat={} from={} {}",
+                pri,
+                record.time.format("%b %e %H:%M:%S"),
+                self.hostname,
+                tag(&record.bucket),
+                process::id(),
+                record.device_id,
+                record.cat,
+                record.from,
+                body
+            ),
+        }
+    }
+}
+
+impl Link {
+    fn connect(&self) -> io::Result<Socket> {
+        let timeout = self.timeout;
+        match self.transport {
+            Transport::Udp => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(&self.address)?;
+                Ok(Socket::Udp(socket))
+            }
+            Transport::Tcp => {
+                let mut last = io::Error::new(io::ErrorKind::NotFound, "no address");
+                for addr in self.address.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, timeout) {
+                        Ok(stream) => {
+                            stream.set_write_timeout(Some(timeout))?;
+                            return Ok(Socket::Tcp(stream));
+                        }
+                        Err(e) => last = e,
+                    }
+                }
+                Err(last)
+            }
+            #[cfg(unix)]
+            Transport::Unix => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(&self.address)?;
+                socket.set_write_timeout(Some(timeout))?;
+                Ok(Socket::Unix(socket))
+            }
+            #[cfg(not(unix))]
+            Transport::Unix => unreachable!(),
+        }
+    }
+
+    fn send(socket: &mut Socket, message: &str) -> io::Result<()> {
+        match socket {
+            Socket::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Socket::Tcp(stream) => {
+                let frame = format!("{} {}", message.len(), message);
+                stream.write_all(frame.as_bytes())
+            }
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+        }
+    }
+
+    fn deliver(&self, message: &str) -> Result<(), SinkError> {
+        let mut socket = self.socket.lock().unwrap();
+        // one reconnect, the peer may have closed an idle connection
+        for _ in 0..2 {
+            if socket.is_none() {
+                *socket = Some(
+                    self.connect()
+                        .map_err(|e| SinkError::Unavailable(e.to_string()))?,
+                );
+            }
+            match Link::send(socket.as_mut().unwrap(), message) {
+                Ok(_) => return Ok(()),
+                Err(_) => *socket = None,
+            }
+        }
+        Err(SinkError::Unavailable(format!(
+            "syslog {} not writable",
+            self.address
+        )))
+    }
+
+    fn health(&self) -> Health {
+        let mut socket = self.socket.lock().unwrap();
+        if socket.is_some() {
+            return Health::ok();
+        }
+        match self.connect() {
+            Ok(connected) => {
+                *socket = Some(connected);
+                Health::ok()
+            }
+            Err(e) => Health::failing(e),
+        }
+    }
+}
+
+// Printable ASCII without spaces, as header fields require.
+fn printable(s: &str, max: usize) -> String {
+    s.chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect()
+}
+
+fn or_nil(s: &str) -> &str {
+    if s.is_empty() {
+        "-"
+    } else {
+        s
+    }
+}
+
+fn param(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+// RFC 3164 tags are up to 32 alphanumeric chars.
+fn tag(s: &str) -> String {
+    let tag: String = s
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .take(32)
+        .collect();
+    if tag.is_empty() {
+        String::from("webhook")
+    } else {
+        tag
+    }
+}
+
+#[async_trait(?Send)]
+impl LogSink for SyslogSink {
+    async fn write(&self, record: &Record) -> Result<(), SinkError> {
+        let message = self.message(record);
+        let link = self.link.clone();
+        web::block(move || link.deliver(&message)).await?
+    }
+
+    async fn health(&self) -> Health {
+        let link = self.link.clone();
+        web::block(move || link.health())
+            .await
+            .unwrap_or_else(Health::failing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+
+    fn config(transport: Transport, address: String, format: Format) -> SyslogConfig {
+        SyslogConfig {
+            transport,
+            address: Some(address),
+            format,
+            facility: 16,
+            severity: 6,
+            hostname: Some(String::from("box")),
+            timeout: 5,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_syslog_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let sink = SyslogSink::new(config(Transport::Udp, address, Format::Rfc5424)).unwrap();
+        sink.write(&record("sms")).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..n]),
+            format!(
+                "<134>1 2022-04-01T08:00:00.000+08:00 box sms {} text \
+                 [hook@32473 device_id=\"100\" from=\"10086\"] 中文||你好",
+                process::id()
+            )
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_syslog_tcp() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let sink = SyslogSink::new(config(Transport::Tcp, address, Format::Rfc3164)).unwrap();
+        sink.write(&record("sms")).await.unwrap();
+        sink.write(&record("otp")).await.unwrap();
+
+        let (stream, _) = server.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        for bucket in ["sms", "otp"] {
+            let mut len = Vec::new();
+            reader.read_until(b' ', &mut len).unwrap();
+            let len: usize = String::from_utf8_lossy(&len).trim().parse().unwrap();
+            let mut frame = vec![0; len];
+            reader.read_exact(&mut frame).unwrap();
+            assert_eq!(
+                String::from_utf8(frame).unwrap(),
+                format!(
+                    "<134>Apr  1 08:00:00 box {}[{}]: device_id=100 cat=text from=10086 中文||你好",
+                    bucket,
+                    process::id()
+                )
+            );
+        }
+    }
+}
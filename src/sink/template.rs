@@ -0,0 +1,205 @@
+use super::Record;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+
+// Text with record fields in braces, e.g. "/hooks/{bucket}?device={device_id}",
+// or meta.NAME, record, and dates like {yyyy.MM.dd}. ":json" quotes a value.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    template: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field, bool),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Time,
+    Bucket,
+    DeviceId,
+    Cat,
+    From,
+    Body,
+    Meta(String),
+    Record,
+    // strftime format
+    Date(String),
+}
+
+// The strftime format of a date pattern, None if it is not one.
+fn date_format(pattern: &str) -> Option<String> {
+    let mut format = String::new();
+    let mut rest = pattern;
+    while !rest.is_empty() {
+        let token = ["yyyy", "yy", "MM", "dd", "HH"]
+            .into_iter()
+            .find(|t| rest.starts_with(t));
+        match token {
+            Some(token) => {
+                format.push_str(match token {
+                    "yyyy" => "%Y",
+                    "yy" => "%y",
+                    "MM" => "%m",
+                    "dd" => "%d",
+                    _ => "%H",
+                });
+                rest = &rest[token.len()..];
+            }
+            None if rest.starts_with(['.', '-', '_']) => {
+                format.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+            None => return None,
+        }
+    }
+    format.contains('%').then_some(format)
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start..];
+            // braces around anything but a name are literal, JSON bodies
+            // need no escaping
+            let name = rest[1..]
+                .find('}')
+                .map(|end| &rest[1..end + 1])
+                .filter(|name| {
+                    !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "_.:-".contains(c))
+                });
+            let name = match name {
+                Some(name) => name,
+                None => {
+                    literal.push('{');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            rest = &rest[name.len() + 2..];
+
+            let (name, json) = match name.strip_suffix(":json") {
+                Some(name) => (name, true),
+                None => (name, false),
+            };
+            let field = match name {
+                "time" => Field::Time,
+                "bucket" => Field::Bucket,
+                "device_id" => Field::DeviceId,
+                "cat" => Field::Cat,
+                "from" => Field::From,
+                "body" => Field::Body,
+                "record" => Field::Record,
+                _ => match (name.strip_prefix("meta."), date_format(name)) {
+                    (Some(key), _) if !key.is_empty() => Field::Meta(key.to_string()),
+                    (_, Some(format)) => Field::Date(format),
+                    _ => return Err(format!("unknown placeholder in template: {{{}}}", name)),
+                },
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Field(field, json));
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { template, parts })
+    }
+}
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Template::try_from(s.to_string())
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+impl Template {
+    pub fn render(&self, record: &Record) -> String {
+        self.render_with(record, |value| value.to_string())
+    }
+
+    // Rendered with every field value passed through `escape` first, for
+    // templates whose values must not change the structure around them.
+    pub fn render_with(&self, record: &Record, escape: impl Fn(&str) -> String) -> String {
+        let mut out = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Field(field, json) => {
+                    let value = match field {
+                        Field::Time => record.time.to_rfc3339(),
+                        Field::Bucket => record.bucket.clone(),
+                        Field::DeviceId => record.device_id.clone(),
+                        Field::Cat => record.cat.clone(),
+                        Field::From => record.from.clone(),
+                        Field::Body => record.body.clone(),
+                        Field::Meta(key) => record.meta.get(key).cloned().unwrap_or_default(),
+                        Field::Record => serde_json::to_string(record).unwrap(),
+                        Field::Date(format) => record.time.format(format).to_string(),
+                    };
+                    let value = escape(&value);
+                    if *json {
+                        out.push_str(&serde_json::to_string(&value).unwrap());
+                    } else {
+                        out.push_str(&value);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        let mut record = record("sms");
+        record
+            .meta
+            .insert(String::from("ip"), String::from("10.0.0.1"));
+        let t: Template =
+            "{\"text\": {body:json}, \"to\": \"{bucket}/{device_id}\", \"ip\": \"{meta.ip}\"}"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            t.render(&record),
+            "{\"text\": \"中文\\n你好\", \"to\": \"sms/100\", \"ip\": \"10.0.0.1\"}"
+        );
+        assert!("{nope}".parse::<Template>().is_err());
+        let t: Template = "webhook-{bucket}-{yyyy.MM.dd}".parse().unwrap();
+        assert_eq!(t.render(&record), "webhook-sms-2022.04.01");
+        assert!("{yyyy.mm}".parse::<Template>().is_err());
+        let t: Template = "{{bucket}".parse().unwrap();
+        assert_eq!(t.render(&record), "{sms");
+        let t: Template = "a.{bucket}".parse().unwrap();
+        assert_eq!(t.render_with(&record, |v| v.to_uppercase()), "a.SMS");
+    }
+}
@@ -0,0 +1,231 @@
+use super::{Location, Segment};
+use actix_web::web::{self, Bytes};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use ulid::Ulid;
+
+/**
+ * Files that go with records, next to the log of the device they came in
+ * for:
+ *
+ *   {dir}/sms/100/20220401.log
+ *   {dir}/sms/100/20220401.files/01G0Z5W3B3N3YV1QKX4CQ4G9HD-body
+ *
+ * Records name them in their meta by paths relative to the work dir. A
+ * `.files` dir is never part of a segment path, so layouts skip them. It
+ * belongs to the partition, retention and upload move it along with the
+ * last segment of that partition.
+ */
+pub const FILES_SUFFIX: &str = ".files";
+
+pub fn files_dir(location: &Location) -> PathBuf {
+    let mut name = location.stem.clone().into_os_string();
+    name.push(FILES_SUFFIX);
+    PathBuf::from(name)
+}
+
+// The files dir of the partition a segment is part of, shared by all its
+// segments whatever their index or codec.
+pub fn segment_files_dir(segment: &Segment) -> PathBuf {
+    let name = segment
+        .path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let mut stem = name.as_str();
+    if let Some(codec) = segment.codec {
+        stem = stem
+            .strip_suffix(codec.extension())
+            .and_then(|s| s.strip_suffix('.'))
+            .unwrap_or(stem);
+    }
+    stem = stem.strip_suffix(".log").unwrap_or(stem);
+    if segment.index > 0 {
+        stem = stem
+            .strip_suffix(&format!(".{}", segment.index))
+            .unwrap_or(stem);
+    }
+    segment
+        .path
+        .with_file_name(format!("{}{}", stem, FILES_SUFFIX))
+}
+
+// Files in a files dir, none when there is no such dir.
+pub fn list_files(files: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(files) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// A name safe to put on disk, what a client sent may hold anything.
+fn safe_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .take(100)
+        .collect();
+    name.trim_start_matches('.').to_string()
+}
+
+// A file of a record, removed on drop unless kept.
+#[derive(Debug)]
+pub struct Sidecar {
+    // taken while a write runs on the blocking pool
+    file: Option<fs::File>,
+    path: PathBuf,
+    rel: String,
+    size: u64,
+    kept: bool,
+}
+
+impl Sidecar {
+    // `name` gets a ULID in front, names of one device never clash.
+    pub async fn create(dir: &Path, location: &Location, name: &str) -> io::Result<Self> {
+        let files = files_dir(location);
+        let name = match safe_name(name) {
+            safe if safe.is_empty() => Ulid::new().to_string(),
+            safe => format!("{}-{}", Ulid::new(), safe),
+        };
+        let path = files.join(name);
+        let open = path.clone();
+        let file = web::block(move || {
+            fs::create_dir_all(&files)?;
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&open)
+        })
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))??;
+        let rel = path
+            .strip_prefix(dir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        Ok(Sidecar {
+            file: Some(file),
+            path,
+            rel,
+            size: 0,
+            kept: false,
+        })
+    }
+
+    // Written on the blocking pool, requests stream to disk without holding
+    // up the arbiter.
+    pub async fn write(&mut self, data: Bytes) -> io::Result<()> {
+        let mut file = self
+            .file
+            .take()
+            .ok_or_else(|| io::Error::other("sidecar lost by an earlier write"))?;
+        let size = data.len() as u64;
+        let file = web::block(move || file.write_all(&data).map(|_| file))
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))??;
+        self.file = Some(file);
+        self.size += size;
+        Ok(())
+    }
+
+    // Path below the work dir, for the record.
+    pub fn rel(&self) -> &str {
+        &self.rel
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn keep(mut self) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush()?;
+        }
+        self.kept = true;
+        Ok(())
+    }
+}
+
+impl Drop for Sidecar {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Codec;
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_sidecar() {
+        let dir = Path::new("./logs/web_hook_test/attach");
+        let _ = fs::remove_dir_all(dir);
+        let location = Location {
+            bucket: String::from("sms"),
+            device_id: String::from("100"),
+            key: String::from("20220401"),
+            stem: dir.join("sms/100/20220401"),
+        };
+
+        let mut kept = Sidecar::create(dir, &location, "../a b.txt").await.unwrap();
+        kept.write(Bytes::from("hello ")).await.unwrap();
+        kept.write(Bytes::from("world")).await.unwrap();
+        let rel = kept.rel().to_string();
+        assert!(rel.starts_with("sms/100/20220401.files/"));
+        assert!(rel.ends_with("-_a_b.txt"));
+        assert_eq!(kept.size(), 11);
+        kept.keep().unwrap();
+        assert_eq!(fs::read_to_string(dir.join(&rel)).unwrap(), "hello world");
+
+        let mut dropped = Sidecar::create(dir, &location, "").await.unwrap();
+        dropped.write(Bytes::from("gone")).await.unwrap();
+        let path = dir.join(dropped.rel());
+        assert!(path.exists());
+        drop(dropped);
+        assert!(!path.exists());
+
+        // not a segment
+        let segments = crate::storage::Layout::default()
+            .list(dir, Default::default())
+            .unwrap();
+        assert!(segments.is_empty());
+        assert_eq!(list_files(&files_dir(&location)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_segment_files_dir() {
+        let segment = |name: &str, index, codec| Segment {
+            path: Path::new("logs/sms/100").join(name),
+            bucket: String::from("sms"),
+            device_id: String::from("100"),
+            date: String::from("20220401"),
+            index,
+            codec,
+        };
+        let files = Path::new("logs/sms/100/20220401.files");
+        assert_eq!(segment_files_dir(&segment("20220401.log", 0, None)), files);
+        assert_eq!(
+            segment_files_dir(&segment("20220401.2.log.zst", 2, Some(Codec::Zstd))),
+            files
+        );
+    }
+}
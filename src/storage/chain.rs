@@ -0,0 +1,573 @@
+use super::{open_segment, Filter, Segment};
+use crate::config::Config;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/**
+ * Tamper-evident logs. With "chain" in the config every line ends in a tab
+ * and the hex SHA-256 of the previous line's hash followed by the line:
+ *
+ *   h(1) = sha256(GENESIS + line 1), h(n) = sha256(h(n-1) + line n)
+ *
+ * Each segment starts its own chain. Once a day is over in the time zone of
+ * a bucket, the line count and last hash of each of its segments go into
+ * `.chain/{bucket}/{date}.seal`,
+ * signed with HMAC-SHA256 and the server key, so rewriting a whole chain
+ * shows up as well.
+ *
+ * Retention and upload take segments away on purpose. They leave a signed
+ * tombstone in `.chain/tombstones.jsonl` first, and a sealed segment with
+ * a tombstone is not missing.
+ */
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+pub const SEAL_DIR: &str = ".chain";
+pub const TOMBSTONES: &str = "tombstones.jsonl";
+pub const KEY_ENV: &str = "WEB_HOOK_CHAIN_KEY";
+
+// bytes a link adds to a line, the tab and the hash
+pub const LINK_LEN: u64 = 65;
+
+//   "chain": { "key": "..." }
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChainConfig {
+    // seal key, defaults to $WEB_HOOK_CHAIN_KEY
+    pub key: Option<String>,
+}
+
+impl ChainConfig {
+    pub fn key(&self) -> io::Result<Vec<u8>> {
+        self.key
+            .clone()
+            .or_else(|| env::var(KEY_ENV).ok())
+            .filter(|k| !k.is_empty())
+            .map(String::into_bytes)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("chain key missing, set \"key\" or ${}", KEY_ENV),
+                )
+            })
+    }
+}
+
+pub fn link(prev: &str, line: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(prev.as_bytes());
+    hasher.update(line.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// The line and its hash, if it carries one.
+pub fn split(line: &str) -> Option<(&str, &str)> {
+    line.rsplit_once('\t')
+        .filter(|(_, hash)| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+// Line count and last hash of a segment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Head {
+    pub lines: u64,
+    pub head: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Seal {
+    pub bucket: String,
+    // day, or month for monthly partitions
+    pub date: String,
+    pub sealed_at: DateTime<Utc>,
+    // by path below the work dir, without a compression suffix
+    pub segments: BTreeMap<String, Head>,
+    pub mac: String,
+}
+
+impl Seal {
+    fn sign(&self, key: &[u8]) -> String {
+        let signed = (&self.bucket, &self.date, &self.sealed_at, &self.segments);
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(&serde_json::to_vec(&signed).unwrap());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+// A segment removed on purpose, with where it went.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    // by path below the work dir, as in seals
+    pub name: String,
+    pub removed_at: DateTime<Utc>,
+    pub reason: String,
+    pub mac: String,
+}
+
+impl Tombstone {
+    fn sign(&self, key: &[u8]) -> String {
+        let signed = (&self.name, &self.removed_at, &self.reason);
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(&serde_json::to_vec(&signed).unwrap());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+// The first link that does not hold. `line` is 0 for problems with a whole
+// file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Broken {
+    pub path: PathBuf,
+    pub line: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub files: usize,
+    pub lines: u64,
+    pub seals: usize,
+    // sealed segments gone with a tombstone
+    pub removed: usize,
+    pub broken: Option<Broken>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.broken {
+            Some(b) if b.line > 0 => {
+                write!(f, "broken: {}:{}: {}", b.path.display(), b.line, b.reason)
+            }
+            Some(b) => write!(f, "broken: {}: {}", b.path.display(), b.reason),
+            None => {
+                write!(
+                    f,
+                    "ok: {} files, {} lines, {} seals",
+                    self.files, self.lines, self.seals
+                )?;
+                if self.removed > 0 {
+                    write!(f, ", {} removed", self.removed)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Seal period of a partition key, hours go into their day.
+fn period(key: &str) -> &str {
+    &key[..key.len().min(8)]
+}
+
+// Name of a segment in seals, the same before and after compaction.
+fn name(dir: &Path, segment: &Segment) -> String {
+    let path = match segment.codec {
+        Some(_) => segment.path.with_extension(""),
+        None => segment.path.clone(),
+    };
+    path.strip_prefix(dir)
+        .unwrap_or(&path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+fn seal_path(dir: &Path, bucket: &str, date: &str) -> PathBuf {
+    dir.join(SEAL_DIR)
+        .join(bucket)
+        .join(format!("{}.seal", date))
+}
+
+// Record that a segment is about to be removed, before it is. Nothing to
+// do without "chain" in the config.
+pub fn bury(
+    dir: &Path,
+    config: &Config,
+    segment: &Segment,
+    reason: &str,
+    now: DateTime<Utc>,
+) -> io::Result<()> {
+    let key = match &config.chain {
+        Some(chain) => chain.key()?,
+        None => return Ok(()),
+    };
+    let mut tombstone = Tombstone {
+        name: name(dir, segment),
+        removed_at: now,
+        reason: reason.to_string(),
+        mac: String::new(),
+    };
+    tombstone.mac = tombstone.sign(key.as_slice());
+    fs::create_dir_all(dir.join(SEAL_DIR))?;
+    let mut line = serde_json::to_vec(&tombstone)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(SEAL_DIR).join(TOMBSTONES))?;
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+// Tombstones by name, or the first one that is not signed with `key`.
+fn tombstones(dir: &Path, key: &[u8]) -> io::Result<Result<HashMap<String, Tombstone>, Broken>> {
+    let path = dir.join(SEAL_DIR).join(TOMBSTONES);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Ok(HashMap::new())),
+        Err(e) => return Err(e),
+    };
+    let mut tombstones = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let broken = |reason: &str| Broken {
+            path: path.clone(),
+            line: i as u64 + 1,
+            reason: reason.to_string(),
+        };
+        match serde_json::from_str::<Tombstone>(line) {
+            Ok(t) if t.sign(key) == t.mac => {
+                tombstones.insert(t.name.clone(), t);
+            }
+            Ok(_) => return Ok(Err(broken("tombstone signature does not match"))),
+            Err(_) => return Ok(Err(broken("not a tombstone"))),
+        }
+    }
+    Ok(Ok(tombstones))
+}
+
+// Follow the chain of a segment to its head.
+pub fn walk(segment: &Segment) -> io::Result<Result<Head, Broken>> {
+    let mut head = String::from(GENESIS);
+    let mut lines = 0;
+    for line in open_segment(segment)?.lines() {
+        let line = line?;
+        lines += 1;
+        let broken = |reason: &str| Broken {
+            path: segment.path.clone(),
+            line: lines,
+            reason: reason.to_string(),
+        };
+        match split(&line) {
+            Some((text, hash)) if link(&head, text) == hash => head = hash.to_string(),
+            Some(_) => return Ok(Err(broken("hash does not match"))),
+            None => return Ok(Err(broken("no hash"))),
+        }
+    }
+    Ok(Ok(Head { lines, head }))
+}
+
+// Seal the past days of each bucket, returned as "{bucket}/{date}". A day
+// with a broken link is left unsealed.
+pub fn seal(
+    dir: &Path,
+    config: &Config,
+    key: &[u8],
+    now: DateTime<Utc>,
+) -> io::Result<Vec<String>> {
+    let mut current: HashMap<String, String> = HashMap::new();
+    let mut days: BTreeMap<(String, String), Vec<Segment>> = BTreeMap::new();
+    for segment in config.layout.list(dir, Filter::default())? {
+        let current = current.entry(segment.bucket.clone()).or_insert_with(|| {
+            let clock = config.clock(&segment.bucket);
+            period(&config.layout.key(&clock, &clock.at(now))).to_string()
+        });
+        let date = period(&segment.date).to_string();
+        if date < *current && !seal_path(dir, &segment.bucket, &date).exists() {
+            days.entry((segment.bucket.clone(), date))
+                .or_default()
+                .push(segment);
+        }
+    }
+
+    let mut sealed = Vec::new();
+    'days: for ((bucket, date), segments) in days {
+        let day = format!("{}/{}", bucket, date);
+        let mut heads = BTreeMap::new();
+        for segment in segments.iter() {
+            match walk(segment) {
+                Ok(Ok(head)) => heads.insert(name(dir, segment), head),
+                Ok(Err(b)) => {
+                    warn!(
+                        "not sealing {}: {}:{}: {}",
+                        day,
+                        b.path.display(),
+                        b.line,
+                        b.reason
+                    );
+                    continue 'days;
+                }
+                Err(e) => {
+                    warn!("not sealing {}: {}: {}", day, segment.path.display(), e);
+                    continue 'days;
+                }
+            };
+        }
+        let mut seal = Seal {
+            bucket: bucket.clone(),
+            date: date.clone(),
+            sealed_at: now,
+            segments: heads,
+            mac: String::new(),
+        };
+        seal.mac = seal.sign(key);
+        let path = seal_path(dir, &bucket, &date);
+        fs::create_dir_all(dir.join(SEAL_DIR).join(&bucket))?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&seal)?)?;
+        fs::rename(&tmp, &path)?;
+        sealed.push(day);
+    }
+    Ok(sealed)
+}
+
+// Walk every segment and seal below `dir`, stopping at the first broken link.
+pub fn verify(dir: &Path, config: &Config, key: &[u8]) -> io::Result<Report> {
+    let mut report = Report::default();
+    let mut heads = HashMap::new();
+    let mut days: HashMap<(String, String), Vec<String>> = HashMap::new();
+    for segment in config.layout.list(dir, Filter::default())? {
+        match walk(&segment)? {
+            Ok(head) => {
+                report.files += 1;
+                report.lines += head.lines;
+                let name = name(dir, &segment);
+                days.entry((segment.bucket.clone(), period(&segment.date).to_string()))
+                    .or_default()
+                    .push(name.clone());
+                heads.insert(name, head);
+            }
+            Err(broken) => {
+                report.broken = Some(broken);
+                return Ok(report);
+            }
+        }
+    }
+
+    let tombstones = match tombstones(dir, key)? {
+        Ok(tombstones) => tombstones,
+        Err(broken) => {
+            report.broken = Some(broken);
+            return Ok(report);
+        }
+    };
+
+    // one dir of seals per bucket
+    let mut seals = Vec::new();
+    if let Ok(buckets) = fs::read_dir(dir.join(SEAL_DIR)) {
+        for bucket in buckets {
+            let bucket = bucket?.path();
+            if !bucket.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&bucket)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|e| e == "seal") {
+                    seals.push(path);
+                }
+            }
+        }
+    }
+    seals.sort();
+    for path in seals {
+        let broken = |path: PathBuf, reason: String| Broken {
+            path,
+            line: 0,
+            reason,
+        };
+        let seal: Seal = match serde_json::from_slice(&fs::read(&path)?) {
+            Ok(seal) => seal,
+            Err(e) => {
+                report.broken = Some(broken(path, format!("not a seal: {}", e)));
+                return Ok(report);
+            }
+        };
+        if seal.sign(key) != seal.mac {
+            report.broken = Some(broken(path, String::from("seal signature does not match")));
+            return Ok(report);
+        }
+        for (name, sealed) in seal.segments.iter() {
+            let reason = match heads.get(name) {
+                None if tombstones.contains_key(name) => {
+                    report.removed += 1;
+                    continue;
+                }
+                None => format!("missing, sealed with {} lines", sealed.lines),
+                Some(head) if head.lines != sealed.lines => {
+                    format!("{} lines, sealed with {}", head.lines, sealed.lines)
+                }
+                Some(head) if head.head != sealed.head => String::from("head does not match seal"),
+                Some(_) => continue,
+            };
+            report.broken = Some(broken(dir.join(name), reason));
+            return Ok(report);
+        }
+        let day = (seal.bucket.clone(), seal.date.clone());
+        for name in days.get(&day).into_iter().flatten() {
+            if !seal.segments.contains_key(name) {
+                report.broken = Some(broken(dir.join(name), format!("not in seal {}", seal.date)));
+                return Ok(report);
+            }
+        }
+        report.seals += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Location, Rotation, SegmentWriter};
+    use super::*;
+
+    fn write(dir: &Path, writer: &SegmentWriter, device_id: &str, line: &str) {
+        let location = Location {
+            bucket: String::from("sms"),
+            device_id: device_id.to_string(),
+            key: String::from("20220401"),
+            stem: dir.join("sms").join(device_id).join("20220401"),
+        };
+        writer
+            .append(&location, &Rotation::default(), None, line)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_chain_seal_verify() {
+        let dir = Path::new("./logs/web_hook_test/chain");
+        let _ = fs::remove_dir_all(dir);
+        let config = Config::default();
+        let key = b"k3y";
+        write(dir, &SegmentWriter::chained(), "100", "line 1");
+        write(dir, &SegmentWriter::chained(), "200", "line 1");
+        // a fresh writer picks the chain up where it ended
+        write(dir, &SegmentWriter::chained(), "100", "line 2");
+
+        let log = dir.join("sms/100/20220401.log");
+        let text = fs::read_to_string(&log).unwrap();
+        let first = link(GENESIS, "line 1");
+        assert_eq!(
+            text,
+            format!("line 1\t{}\nline 2\t{}\n", first, link(&first, "line 2"))
+        );
+
+        // today is not sealed yet
+        let today = Utc.with_ymd_and_hms(2022, 4, 1, 12, 0, 0).unwrap();
+        assert!(seal(dir, &config, key, today).unwrap().is_empty());
+        let tomorrow = Utc.with_ymd_and_hms(2022, 4, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            seal(dir, &config, key, tomorrow).unwrap(),
+            vec!["sms/20220401"]
+        );
+        assert!(seal(dir, &config, key, tomorrow).unwrap().is_empty());
+        let report = verify(dir, &config, key).unwrap();
+        assert_eq!(report.to_string(), "ok: 2 files, 3 lines, 1 seals");
+        assert!(verify(dir, &config, b"other").unwrap().broken.is_some());
+
+        // a segment taken by retention is buried first
+        let mut config = config;
+        config.chain = Some(ChainConfig {
+            key: Some(String::from("k3y")),
+        });
+        let segments = config.layout.list(dir, Filter::default()).unwrap();
+        let other = &segments[1];
+        bury(dir, &config, other, "retention", tomorrow).unwrap();
+        fs::remove_file(&other.path).unwrap();
+        let report = verify(dir, &config, key).unwrap();
+        assert_eq!(
+            report.to_string(),
+            "ok: 1 files, 2 lines, 1 seals, 1 removed"
+        );
+
+        // an edited line breaks its link
+        fs::write(&log, text.replace("line 2", "line 3")).unwrap();
+        let broken = verify(dir, &config, key).unwrap().broken.unwrap();
+        assert_eq!((broken.path, broken.line), (log.clone(), 2));
+
+        // a chain rebuilt from scratch no longer matches the seal
+        let first = link(GENESIS, "line 0");
+        fs::write(&log, format!("line 0\t{}\n", first)).unwrap();
+        let broken = verify(dir, &config, key).unwrap().broken.unwrap();
+        assert_eq!(broken.path, log);
+        assert_eq!(broken.reason, "1 lines, sealed with 2");
+
+        // a forged tombstone does not hide a missing segment
+        let tombstones = dir.join(SEAL_DIR).join(TOMBSTONES);
+        let forged = fs::read_to_string(&tombstones)
+            .unwrap()
+            .replace("retention", "cleanup");
+        fs::write(&tombstones, forged).unwrap();
+        let broken = verify(dir, &config, key).unwrap().broken.unwrap();
+        assert_eq!((broken.path, broken.line), (tombstones, 1));
+    }
+
+    #[test]
+    fn test_chain_seal_skips_broken_days() {
+        let dir = Path::new("./logs/web_hook_test/chain_skip");
+        let _ = fs::remove_dir_all(dir);
+        let config = Config::default();
+        let writer = SegmentWriter::chained();
+        for date in ["20220331", "20220401"] {
+            let location = Location {
+                bucket: String::from("sms"),
+                device_id: String::from("100"),
+                key: date.to_string(),
+                stem: dir.join("sms/100").join(date),
+            };
+            writer
+                .append(&location, &Rotation::default(), None, "line 1")
+                .unwrap();
+        }
+        fs::write(dir.join("sms/100/20220331.log"), "line 1\n").unwrap();
+
+        let now = Utc.with_ymd_and_hms(2022, 4, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            seal(dir, &config, b"k3y", now).unwrap(),
+            vec!["sms/20220401"]
+        );
+        assert!(!seal_path(dir, "sms", "20220331").exists());
+    }
+
+    #[test]
+    fn test_chain_seal_per_bucket() {
+        let dir = Path::new("./logs/web_hook_test/chain_buckets");
+        let _ = fs::remove_dir_all(dir);
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "buckets": { "gps": { "timezone": "America/Los_Angeles" } }
+        }))
+        .unwrap();
+        let key = b"k3y";
+        let writer = SegmentWriter::chained();
+        for bucket in ["gps", "sms"] {
+            let location = Location {
+                bucket: bucket.to_string(),
+                device_id: String::from("100"),
+                key: String::from("20220401"),
+                stem: dir.join(bucket).join("100/20220401"),
+            };
+            writer
+                .append(&location, &Rotation::default(), None, "line 1")
+                .unwrap();
+        }
+
+        // still the 1st in Los Angeles, gps waits for its own seal
+        let now = Utc.with_ymd_and_hms(2022, 4, 2, 1, 0, 0).unwrap();
+        assert_eq!(seal(dir, &config, key, now).unwrap(), vec!["sms/20220401"]);
+        let later = Utc.with_ymd_and_hms(2022, 4, 2, 8, 0, 0).unwrap();
+        assert_eq!(
+            seal(dir, &config, key, later).unwrap(),
+            vec!["gps/20220401"]
+        );
+        let report = verify(dir, &config, key).unwrap();
+        assert_eq!(report.to_string(), "ok: 2 files, 2 lines, 2 seals");
+
+        // a seal that does not parse is a broken link, not an error
+        let path = seal_path(dir, "sms", "20220401");
+        fs::write(&path, "{").unwrap();
+        let broken = verify(dir, &config, key).unwrap().broken.unwrap();
+        assert_eq!(broken.path, path);
+    }
+}
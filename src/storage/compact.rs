@@ -0,0 +1,226 @@
+use super::{Filter, Segment};
+use crate::config::Config;
+use chrono::prelude::*;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" | "gz" => Ok(Codec::Gzip),
+            "zstd" | "zst" => Ok(Codec::Zstd),
+            _ => Err(format!("unknown codec: {}", s)),
+        }
+    }
+}
+
+impl Codec {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "gz",
+            Codec::Zstd => "zst",
+        }
+    }
+
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Codec::Gzip => 6,
+            Codec::Zstd => 3,
+        }
+    }
+
+    pub fn decoder(&self, file: File) -> io::Result<Box<dyn BufRead>> {
+        match self {
+            Codec::Gzip => Ok(Box::new(BufReader::new(MultiGzDecoder::new(file)))),
+            Codec::Zstd => Ok(Box::new(BufReader::new(zstd::Decoder::new(file)?))),
+        }
+    }
+
+    fn compress(&self, level: i32, src: &mut impl Read, dst: File) -> io::Result<File> {
+        match self {
+            Codec::Gzip => {
+                let level = flate2::Compression::new(level.clamp(0, 9) as u32);
+                let mut encoder = GzEncoder::new(dst, level);
+                io::copy(src, &mut encoder)?;
+                encoder.finish()
+            }
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::new(dst, level)?;
+                io::copy(src, &mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// Compresses the segments of past dates, the current one is still written.
+#[derive(Debug, Clone)]
+pub struct Compactor {
+    pub codec: Codec,
+    pub level: i32,
+}
+
+impl Compactor {
+    pub fn new(codec: Codec, level: Option<i32>) -> Self {
+        Compactor {
+            codec,
+            level: level.unwrap_or_else(|| codec.default_level()),
+        }
+    }
+
+    // Compact every segment before the current partition of its bucket.
+    // Returns the compressed files.
+    pub fn run(&self, dir: &Path, config: &Config, now: DateTime<Utc>) -> io::Result<Vec<PathBuf>> {
+        let mut current: HashMap<String, String> = HashMap::new();
+        let mut done = Vec::new();
+        for segment in config.layout.list(dir, Filter::default())? {
+            let current = current.entry(segment.bucket.clone()).or_insert_with(|| {
+                let clock = config.clock(&segment.bucket);
+                config.layout.key(&clock, &clock.at(now))
+            });
+            if segment.codec.is_some() || segment.date >= *current {
+                continue;
+            }
+            match self.compact(&segment) {
+                Ok(path) => {
+                    info!("compacted {}", path.display());
+                    done.push(path);
+                }
+                Err(e) => warn!("compact {} failed: {}", segment.path.display(), e),
+            }
+        }
+        Ok(done)
+    }
+
+    // Compress into a temp file, verify it decodes to the original content,
+    // then rename into place and drop the original.
+    pub fn compact(&self, segment: &Segment) -> io::Result<PathBuf> {
+        let target = compressed_path(&segment.path, self.codec);
+        let expected = digest(&mut File::open(&segment.path)?)?;
+
+        // a previous run may have died between rename and remove, anything
+        // else in place of the target is never overwritten
+        if target.exists() {
+            if digest(&mut self.codec.decoder(File::open(&target)?)?)? == expected {
+                fs::remove_file(&segment.path)?;
+                return Ok(target);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists with other content", target.display()),
+            ));
+        }
+
+        let tmp = target.with_extension(format!("{}.tmp", self.codec.extension()));
+        let mut src = File::open(&segment.path)?;
+        self.codec
+            .compress(self.level, &mut src, File::create(&tmp)?)?
+            .sync_all()?;
+
+        if digest(&mut self.codec.decoder(File::open(&tmp)?)?)? != expected {
+            fs::remove_file(&tmp)?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed segment does not match original",
+            ));
+        }
+
+        fs::rename(&tmp, &target)?;
+        fs::remove_file(&segment.path)?;
+        Ok(target)
+    }
+}
+
+fn compressed_path(path: &Path, codec: Codec) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(codec.extension());
+    path.with_file_name(name)
+}
+
+fn digest(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{open_segment, Layout, Location, Rotation, SegmentWriter};
+    use super::*;
+
+    #[test]
+    fn test_compact_past_dates() {
+        let dir = PathBuf::from("./logs/web_hook_test/compact");
+        let _ = fs::remove_dir_all(&dir);
+
+        let writer = SegmentWriter::default();
+        let rotation = Rotation {
+            max_size: None,
+            max_records: Some(1),
+        };
+        for date in ["20220401", "20220401", "20220402"] {
+            let location = Location {
+                bucket: String::from("sms"),
+                device_id: String::from("100"),
+                key: date.to_string(),
+                stem: dir.join("sms/100").join(date),
+            };
+            writer.append(&location, &rotation, None, date).unwrap();
+        }
+
+        let now = "2022-04-02T08:00:00Z".parse().unwrap();
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            Compactor::new(codec, None)
+                .run(&dir, &Config::default(), now)
+                .unwrap();
+        }
+
+        let segments = Layout::default().list(&dir, Filter::default()).unwrap();
+        let codecs: Vec<Option<Codec>> = segments.iter().map(|s| s.codec).collect();
+        assert_eq!(codecs, vec![Some(Codec::Zstd), Some(Codec::Zstd), None]);
+
+        let mut lines = Vec::new();
+        for segment in segments.iter() {
+            for line in open_segment(segment).unwrap().lines() {
+                lines.push(line.unwrap());
+            }
+        }
+        assert_eq!(lines, vec!["20220401", "20220401", "20220402"]);
+
+        // a plain file next to a compressed one of other content stays
+        let plain = dir.join("sms/100/20220401.log");
+        fs::write(&plain, "late\n").unwrap();
+        let compactor = Compactor::new(Codec::Zstd, None);
+        let late = Segment {
+            path: plain.clone(),
+            bucket: String::from("sms"),
+            device_id: String::from("100"),
+            date: String::from("20220401"),
+            index: 0,
+            codec: None,
+        };
+        assert!(compactor.compact(&late).is_err());
+        assert!(plain.exists());
+        let first = open_segment(&segments[0]).unwrap().lines().next();
+        assert_eq!(first.unwrap().unwrap(), "20220401");
+    }
+}
@@ -0,0 +1,357 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+
+/**
+ * Bodies of encrypted buckets are stored as base64 of the ciphertext, the
+ * rest of the line stays readable. Each segment starts with a header line
+ * naming the cipher and key, a new key starts a new segment:
+ *
+ *   #encrypted cipher=aes-256-gcm key=2024a
+ *   #encrypted cipher=age recipients=age1...,age1...
+ *
+ * AES-256-GCM bodies are a 12 byte nonce and the sealed body, with the line
+ * before the body as associated data, so a body can not be moved to
+ * another line. age bodies are an age file each.
+ */
+pub const HEADER: &str = "#encrypted";
+
+//   "encryption": { "cipher": "aes-256-gcm", "key_id": "2024a", "key_file": "/etc/web_hook/2024a.key" }
+//   "encryption": { "cipher": "age", "recipients": ["age1..."] }
+// Key files hold 32 bytes as hex or base64.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "cipher", rename_all = "lowercase")]
+pub enum Encryption {
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm {
+        key_id: String,
+        key_file: String,
+    },
+    Age {
+        recipients: Vec<String>,
+    },
+}
+
+pub fn load_key(path: &Path) -> io::Result<[u8; 32]> {
+    let text = fs::read_to_string(path)?;
+    let text = text.trim();
+    let bytes = hex::decode(text)
+        .ok()
+        .or_else(|| base64::decode(text).ok())
+        .unwrap_or_default();
+    bytes.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: not a 32 byte key", path.display()),
+        )
+    })
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Encrypts bodies for one bucket config.
+#[derive(Clone)]
+pub enum Cipher {
+    Aes { key_id: String, key: [u8; 32] },
+    Age { recipients: Vec<String> },
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.header())
+    }
+}
+
+impl Cipher {
+    pub fn new(encryption: &Encryption) -> io::Result<Cipher> {
+        match encryption {
+            Encryption::Aes256Gcm { key_id, key_file } => Ok(Cipher::Aes {
+                key_id: key_id.clone(),
+                key: load_key(Path::new(key_file))?,
+            }),
+            Encryption::Age { recipients } => {
+                if recipients.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "age encryption needs recipients",
+                    ));
+                }
+                for recipient in recipients {
+                    age::x25519::Recipient::from_str(recipient)
+                        .map_err(|e| invalid(format!("{}: {}", recipient, e)))?;
+                }
+                Ok(Cipher::Age {
+                    recipients: recipients.clone(),
+                })
+            }
+        }
+    }
+
+    pub fn header(&self) -> String {
+        match self {
+            Cipher::Aes { key_id, .. } => format!("{} cipher=aes-256-gcm key={}", HEADER, key_id),
+            Cipher::Age { recipients } => {
+                format!("{} cipher=age recipients={}", HEADER, recipients.join(","))
+            }
+        }
+    }
+
+    // `aad` is the line up to the body.
+    pub fn encrypt(&self, aad: &str, body: &str) -> io::Result<String> {
+        let sealed = match self {
+            Cipher::Aes { key, .. } => {
+                let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let payload = Payload {
+                    msg: body.as_bytes(),
+                    aad: aad.as_bytes(),
+                };
+                let sealed = cipher.encrypt(&nonce, payload).map_err(invalid)?;
+                [nonce.as_slice(), &sealed].concat()
+            }
+            Cipher::Age { recipients } => {
+                let recipients = recipients
+                    .iter()
+                    .map(|r| {
+                        let r = age::x25519::Recipient::from_str(r).map_err(invalid)?;
+                        Ok(Box::new(r) as Box<dyn age::Recipient + Send>)
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                let encryptor = age::Encryptor::with_recipients(recipients)
+                    .ok_or_else(|| invalid("no recipients"))?;
+                let mut sealed = Vec::new();
+                let mut writer = encryptor.wrap_output(&mut sealed).map_err(invalid)?;
+                writer.write_all(body.as_bytes())?;
+                writer.finish()?;
+                sealed
+            }
+        };
+        Ok(base64::encode(sealed))
+    }
+}
+
+// A segment header, the cipher and the keys it names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub cipher: String,
+    pub keys: Vec<String>,
+}
+
+impl Header {
+    pub fn parse(line: &str) -> Option<Header> {
+        let fields = line.strip_prefix(HEADER)?;
+        let mut header = Header {
+            cipher: String::new(),
+            keys: Vec::new(),
+        };
+        for field in fields.split_whitespace() {
+            match field.split_once('=') {
+                Some(("cipher", cipher)) => header.cipher = cipher.to_string(),
+                Some(("key", key)) => header.keys = vec![key.to_string()],
+                Some(("recipients", keys)) => {
+                    header.keys = keys.split(',').map(String::from).collect()
+                }
+                _ => {}
+            }
+        }
+        Some(header)
+    }
+}
+
+// The keys an operator has at hand for reading encrypted segments.
+#[derive(Default)]
+pub struct Keyring {
+    pub aes: HashMap<String, [u8; 32]>,
+    pub age: Vec<age::x25519::Identity>,
+}
+
+impl Keyring {
+    // Adds every x25519 identity of an age identity file.
+    pub fn add_identities(&mut self, path: &Path) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        let before = self.age.len();
+        for line in text.lines().map(str::trim) {
+            if line.starts_with("AGE-SECRET-KEY-") {
+                self.age
+                    .push(age::x25519::Identity::from_str(line).map_err(invalid)?);
+            }
+        }
+        if self.age.len() == before {
+            return Err(invalid(format!("{}: no age identities", path.display())));
+        }
+        Ok(())
+    }
+
+    // Whether a segment with this header can be read.
+    pub fn check(&self, header: &Header) -> io::Result<()> {
+        let ok = match header.cipher.as_str() {
+            "aes-256-gcm" => header.keys.iter().all(|k| self.aes.contains_key(k)),
+            "age" => !self.age.is_empty(),
+            _ => false,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("no key for {} {}", header.cipher, header.keys.join(",")),
+            ))
+        }
+    }
+
+    pub fn decrypt(&self, header: &Header, aad: &str, body: &str) -> io::Result<String> {
+        let sealed = base64::decode(body).map_err(invalid)?;
+        let plain = match header.cipher.as_str() {
+            "aes-256-gcm" => {
+                let key = header
+                    .keys
+                    .first()
+                    .and_then(|k| self.aes.get(k))
+                    .ok_or_else(|| invalid("no key"))?;
+                if sealed.len() < 12 {
+                    return Err(invalid("body too short"));
+                }
+                let (nonce, sealed) = sealed.split_at(12);
+                let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+                let payload = Payload {
+                    msg: sealed,
+                    aad: aad.as_bytes(),
+                };
+                cipher
+                    .decrypt(Nonce::from_slice(nonce), payload)
+                    .map_err(|_| invalid("body does not decrypt"))?
+            }
+            "age" => {
+                let decryptor = match age::Decryptor::new(&sealed[..]).map_err(invalid)? {
+                    age::Decryptor::Recipients(d) => d,
+                    age::Decryptor::Passphrase(_) => return Err(invalid("passphrase body")),
+                };
+                let identities = self.age.iter().map(|i| i as &dyn age::Identity);
+                let mut reader = decryptor.decrypt(identities).map_err(invalid)?;
+                let mut plain = Vec::new();
+                reader.read_to_end(&mut plain)?;
+                plain
+            }
+            cipher => return Err(invalid(format!("unknown cipher {}", cipher))),
+        };
+        String::from_utf8(plain).map_err(invalid)
+    }
+
+    // The line with its body in the clear, in the log line format. A hash
+    // chain link, if any, is kept.
+    pub fn decrypt_line(&self, header: &Header, line: &str) -> io::Result<String> {
+        let (line, link) = match super::chain::split(line) {
+            Some((line, hash)) => (line, Some(hash)),
+            None => (line, None),
+        };
+        let (aad, body) = line
+            .rsplit_once('\t')
+            .ok_or_else(|| invalid("not a log line"))?;
+        let body = self.decrypt(header, &format!("{}\t", aad), body)?;
+        // as `Record::line` writes it
+        let body = body.replace(['\r', '\n'], "||");
+        Ok(match link {
+            Some(hash) => format!("{}\t{}\t{}", aad, body, hash),
+            None => format!("{}\t{}", aad, body),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::sink::FileSink;
+    use crate::sink::Record;
+    use chrono::prelude::*;
+
+    fn record() -> Record {
+        Record {
+            // late records move to the open partition, see `FileSink`
+            time: Utc::now().with_timezone(&FixedOffset::east_opt(0).unwrap()),
+            bucket: String::from("sms"),
+            device_id: String::from("100"),
+            cat: String::from("text"),
+            from: String::from("10086"),
+            body: String::from("中文\n你好"),
+            meta: Default::default(),
+        }
+    }
+
+    fn config(dir: &Path, key_id: &str) -> Config {
+        let key_file = dir.join(format!("{}.key", key_id));
+        fs::write(&key_file, hex::encode([7u8; 32])).unwrap();
+        serde_json::from_value(serde_json::json!({
+            "buckets": { "sms": { "encryption": {
+                "cipher": "aes-256-gcm",
+                "key_id": key_id,
+                "key_file": key_file,
+            } } }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_aes_segments() {
+        let dir = Path::new("./logs/web_hook_test/crypt");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let sink = FileSink::new(dir, config(dir, "k1"), Default::default()).unwrap();
+        let record = record();
+        let path = sink.append(&record).unwrap();
+        sink.append(&record).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "#encrypted cipher=aes-256-gcm key=k1");
+        assert!(!text.contains("你好"));
+        // fresh nonces, the same body encrypts differently
+        assert_ne!(lines[1], lines[2]);
+
+        let header = Header::parse(lines[0]).unwrap();
+        let mut keyring = Keyring::default();
+        assert!(keyring.check(&header).is_err());
+        keyring.aes.insert(String::from("k1"), [7u8; 32]);
+        assert_eq!(
+            keyring.decrypt_line(&header, lines[1]).unwrap(),
+            record.line()
+        );
+        // the body is bound to its line
+        let moved = lines[1].replace("\t10086\t", "\t10010\t");
+        assert!(keyring.decrypt_line(&header, &moved).is_err());
+
+        // a new key starts a new segment
+        let sink = FileSink::new(dir, config(dir, "k2"), Default::default()).unwrap();
+        let path = sink.append(&record).unwrap();
+        assert!(path.to_string_lossy().ends_with(".1.log"));
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("#encrypted cipher=aes-256-gcm key=k2\n"));
+    }
+
+    #[test]
+    fn test_age_body() {
+        let identity = age::x25519::Identity::generate();
+        let cipher = Cipher::new(&Encryption::Age {
+            recipients: vec![identity.to_public().to_string()],
+        })
+        .unwrap();
+        let sealed = cipher.encrypt("", "中文\n你好").unwrap();
+        let header = Header::parse(&cipher.header()).unwrap();
+        assert_eq!(header.cipher, "age");
+
+        let mut keyring = Keyring::default();
+        assert!(keyring.decrypt(&header, "", &sealed).is_err());
+        keyring.age.push(identity);
+        assert_eq!(keyring.decrypt(&header, "", &sealed).unwrap(), "中文\n你好");
+    }
+}
@@ -0,0 +1,557 @@
+use super::{Clock, Codec, LogPath, Partition, PathError, Segment};
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+pub const DEFAULT_LAYOUT: &str = "{bucket}/{device_id}/{date}.log";
+
+/**
+ * Where segments live below the work dir. Placeholders:
+ *   {bucket} {device_id}     validated path segments
+ *   {device_hash:N}          first N hex chars of sha256(device_id)
+ *   {date}                   partition key, see `Partition`
+ *   {yyyy} {mm} {dd} {hh}    parts of the partition time
+ * The template must end in `.log`, rolled and compressed segments get
+ * `.{n}.log` and `.log.gz` / `.log.zst` in its place. Without `{date}` the
+ * time parts cut the files, the "partition" of every bucket must match.
+ *
+ *   {bucket}/{yyyy}/{mm}/{dd}/{device_id}.log
+ *   {bucket}/{device_hash:2}/{device_id}/{date}.log
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Layout {
+    template: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Lit(String),
+    Bucket,
+    DeviceId,
+    DeviceHash(usize),
+    Date,
+    Year,
+    Month,
+    Day,
+    Hour,
+}
+
+impl Part {
+    // (capture name, start, end) of a time part within a YYYYMMDDHH key
+    fn time(&self) -> Option<(&'static str, usize, usize)> {
+        match self {
+            Part::Year => Some(("yyyy", 0, 4)),
+            Part::Month => Some(("mm", 4, 6)),
+            Part::Day => Some(("dd", 6, 8)),
+            Part::Hour => Some(("hh", 8, 10)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::parse(DEFAULT_LAYOUT).unwrap()
+    }
+}
+
+impl TryFrom<String> for Layout {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        Layout::parse(&template)
+    }
+}
+
+impl std::str::FromStr for Layout {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        Layout::parse(template)
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+// A segment position resolved for writing: `stem` is the path of the first
+// segment without its `.log` suffix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub bucket: String,
+    pub device_id: String,
+    pub key: String,
+    pub stem: PathBuf,
+}
+
+impl Location {
+    pub fn segment(&self, index: u32) -> PathBuf {
+        let mut name = self.stem.clone().into_os_string();
+        if index > 0 {
+            name.push(format!(".{}", index));
+        }
+        name.push(".log");
+        PathBuf::from(name)
+    }
+}
+
+// Narrows a listing, `key` matches as a prefix so a day also selects its hours.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Filter<'a> {
+    pub bucket: Option<&'a str>,
+    pub device_id: Option<&'a str>,
+    pub key: Option<&'a str>,
+}
+
+impl Layout {
+    pub fn parse(template: &str) -> Result<Layout, String> {
+        let stem = template
+            .strip_suffix(".log")
+            .ok_or_else(|| format!("layout must end with .log: {}", template))?;
+        let re = Regex::new(r"\{([a-z_]+)(?::(\d+))?\}").unwrap();
+
+        let mut parts = Vec::new();
+        let mut last = 0;
+        for caps in re.captures_iter(stem) {
+            let m = caps.get(0).unwrap();
+            if m.start() > last {
+                parts.push(Part::Lit(stem[last..m.start()].to_string()));
+            }
+            last = m.end();
+            let part = match (&caps[1], caps.get(2)) {
+                ("bucket", None) => Part::Bucket,
+                ("device_id", None) => Part::DeviceId,
+                ("device_hash", Some(n)) => match n.as_str().parse() {
+                    Ok(n) if (1..=64).contains(&n) => Part::DeviceHash(n),
+                    _ => return Err(format!("bad hash length in layout: {}", m.as_str())),
+                },
+                ("date", None) => Part::Date,
+                ("yyyy", None) => Part::Year,
+                ("mm", None) => Part::Month,
+                ("dd", None) => Part::Day,
+                ("hh", None) => Part::Hour,
+                _ => return Err(format!("unknown placeholder in layout: {}", m.as_str())),
+            };
+            if parts.contains(&part) {
+                return Err(format!("duplicate placeholder in layout: {}", m.as_str()));
+            }
+            parts.push(part);
+        }
+        if last < stem.len() {
+            parts.push(Part::Lit(stem[last..].to_string()));
+        }
+
+        for part in [Part::Bucket, Part::DeviceId] {
+            if !parts.contains(&part) {
+                return Err(format!(
+                    "layout needs {{bucket}} and {{device_id}}: {}",
+                    template
+                ));
+            }
+        }
+        if !parts.iter().any(|p| *p == Part::Date || p.time().is_some()) {
+            return Err(format!("layout needs {{date}} or {{yyyy}}: {}", template));
+        }
+        // time parts join into the partition key, so they must not skip any
+        let times = [Part::Year, Part::Month, Part::Day, Part::Hour];
+        let used = times.iter().filter(|p| parts.contains(p)).count();
+        if times.iter().skip(used).any(|p| parts.contains(p)) {
+            return Err(format!(
+                "layout time parts must start at {{yyyy}}: {}",
+                template
+            ));
+        }
+        // literals must not climb out of the work dir or hide in dot dirs
+        let unsafe_lit = parts.iter().any(|p| match p {
+            Part::Lit(s) => {
+                s.contains('\\')
+                    || s.split('/').any(|c| c.starts_with('.') && !c.is_empty())
+                    || s.contains('{')
+                    || s.contains('}')
+            }
+            _ => false,
+        });
+        if template.starts_with('/') || unsafe_lit {
+            return Err(format!("bad literal in layout: {}", template));
+        }
+
+        Ok(Layout {
+            template: template.to_string(),
+            parts,
+        })
+    }
+
+    // A name placed right before `.log` must not look like a rolled
+    // segment, else `{device_id}.log` of "100.1" is `.1` of device "100".
+    pub fn check(&self, log_path: &LogPath) -> Result<(), PathError> {
+        static ROLLED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\.\d+$").unwrap());
+        let (what, value) = match self.parts.last() {
+            Some(Part::Bucket) => ("bucket", &log_path.bucket),
+            Some(Part::DeviceId) => ("device_id", &log_path.device_id),
+            _ => return Ok(()),
+        };
+        if ROLLED.is_match(value) {
+            return Err(PathError::Reserved(what));
+        }
+        Ok(())
+    }
+
+    // Time parts down to {dd} cut files by day, a bucket partitioned by hour
+    // or month would silently get days.
+    pub fn check_partition(&self, partition: Partition) -> Result<(), String> {
+        if self.parts.contains(&Part::Date) {
+            return Ok(());
+        }
+        let len = self
+            .parts
+            .iter()
+            .filter_map(|p| p.time())
+            .map(|t| t.2)
+            .max();
+        let key_len = match partition {
+            Partition::Hourly => 10,
+            Partition::Daily => 8,
+            Partition::Monthly => 6,
+        };
+        match len == Some(key_len) {
+            true => Ok(()),
+            false => Err(format!(
+                "layout {} does not cut files {:?}",
+                self.template, partition
+            )),
+        }
+    }
+
+    // Partition key of `time` as this layout writes it: the `{date}` if there
+    // is one, else the time parts joined, e.g. "{yyyy}/{mm}" gives "202204".
+    pub fn key(&self, clock: &Clock, time: &DateTime<Tz>) -> String {
+        if self.parts.contains(&Part::Date) {
+            return clock.key(time);
+        }
+        let full = time.format("%Y%m%d%H").to_string();
+        let len = self
+            .parts
+            .iter()
+            .filter_map(|p| p.time())
+            .map(|t| t.2)
+            .max();
+        full[..len.unwrap_or(0)].to_string()
+    }
+
+    pub fn locate(
+        &self,
+        dir: &Path,
+        bucket: &str,
+        device_id: &str,
+        clock: &Clock,
+        time: &DateTime<Tz>,
+    ) -> Location {
+        let date = clock.key(time);
+        let full = time.format("%Y%m%d%H").to_string();
+        let mut stem = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Lit(s) => stem.push_str(s),
+                Part::Bucket => stem.push_str(bucket),
+                Part::DeviceId => stem.push_str(device_id),
+                Part::DeviceHash(n) => stem.push_str(&device_hash(device_id, *n)),
+                Part::Date => stem.push_str(&date),
+                part => {
+                    let (_, start, end) = part.time().unwrap();
+                    stem.push_str(&full[start..end]);
+                }
+            }
+        }
+        Location {
+            bucket: bucket.to_string(),
+            device_id: device_id.to_string(),
+            key: self.key(clock, time),
+            stem: dir.join(stem),
+        }
+    }
+
+    // All segments below `dir` this layout could have written, ordered by
+    // bucket, device, partition key and index. A plain file shadows a
+    // compressed copy of itself left behind by an interrupted compaction.
+    pub fn list(&self, dir: &Path, filter: Filter) -> io::Result<Vec<Segment>> {
+        let (base, re) = self.matcher(filter);
+        let mut files = Vec::new();
+        walk(&dir.join(&base), &base, &mut files)?;
+
+        let mut segments = Vec::new();
+        for rel in files {
+            if let Some(segment) = self.parse_rel(&re, dir, &rel) {
+                if filter.key.is_none_or(|k| segment.date.starts_with(k)) {
+                    segments.push(segment);
+                }
+            }
+        }
+        segments.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        segments.dedup_by(|b, a| {
+            (&a.bucket, &a.device_id, &a.date, a.index)
+                == (&b.bucket, &b.device_id, &b.date, b.index)
+        });
+        Ok(segments)
+    }
+
+    fn parse_rel(&self, re: &Regex, dir: &Path, rel: &str) -> Option<Segment> {
+        let caps = re.captures(rel)?;
+        let bucket = caps.name("bucket")?.as_str().to_string();
+        let device_id = caps.name("device_id")?.as_str().to_string();
+        for part in self.parts.iter() {
+            if let Part::DeviceHash(n) = part {
+                if caps.name("device_hash")?.as_str() != device_hash(&device_id, *n) {
+                    return None;
+                }
+            }
+        }
+        let date = match caps.name("date") {
+            Some(m) => m.as_str().to_string(),
+            None => ["yyyy", "mm", "dd", "hh"]
+                .iter()
+                .filter_map(|name| caps.name(name))
+                .map(|m| m.as_str())
+                .collect(),
+        };
+        let index = match caps.name("index") {
+            Some(m) => m.as_str().parse().ok()?,
+            None => 0,
+        };
+        let codec = match caps.name("codec") {
+            Some(m) => Some(m.as_str().parse::<Codec>().ok()?),
+            None => None,
+        };
+        Some(Segment {
+            path: dir.join(rel),
+            bucket,
+            device_id,
+            date,
+            index,
+            codec,
+        })
+    }
+
+    // Dir to start walking from, the longest fully known prefix, and the
+    // regex for paths relative to the work dir.
+    fn matcher(&self, filter: Filter) -> (String, Regex) {
+        let mut prefix = Some(String::new());
+        let mut known = String::new();
+        let mut pattern = String::from("^");
+
+        for part in self.parts.iter() {
+            let (value, group, any) = match part {
+                Part::Lit(s) => (Some(s.clone()), None, String::new()),
+                Part::Bucket => (
+                    filter.bucket.map(String::from),
+                    Some("bucket"),
+                    String::from(r"[^/]+?"),
+                ),
+                Part::DeviceId => (
+                    filter.device_id.map(String::from),
+                    Some("device_id"),
+                    String::from(r"[^/]+?"),
+                ),
+                Part::DeviceHash(n) => (
+                    filter.device_id.map(|d| device_hash(d, *n)),
+                    Some("device_hash"),
+                    format!(r"[0-9a-f]{{{}}}", n),
+                ),
+                // keys filter by prefix, checked after parsing
+                Part::Date => (None, Some("date"), String::from(r"\d{4,10}")),
+                part => {
+                    let (name, start, end) = part.time().unwrap();
+                    let value = filter
+                        .key
+                        .filter(|k| k.len() >= end)
+                        .map(|k| k[start..end].to_string());
+                    (value, Some(name), format!(r"\d{{{}}}", end - start))
+                }
+            };
+
+            let body = match &value {
+                Some(v) => regex::escape(v),
+                None => any,
+            };
+            match group {
+                Some(name) => pattern.push_str(&format!("(?P<{}>{})", name, body)),
+                None => pattern.push_str(&body),
+            }
+
+            prefix = match (prefix, value) {
+                (Some(p), Some(v)) => Some(p + &v),
+                (Some(p), None) => {
+                    known = p;
+                    None
+                }
+                (None, _) => None,
+            };
+        }
+        pattern.push_str(r"(?:\.(?P<index>\d+))?\.log(?:\.(?P<codec>gz|zst))?$");
+
+        let known = prefix.unwrap_or(known);
+        let base = match known.rfind('/') {
+            Some(i) => known[..i].to_string(),
+            None => String::new(),
+        };
+        (base, Regex::new(&pattern).unwrap())
+    }
+}
+
+fn device_hash(device_id: &str, n: usize) -> String {
+    let hash = Sha256::digest(device_id.as_bytes());
+    let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    hex[..n].to_string()
+}
+
+// Collect files below `dir` as '/' separated paths relative to the work dir,
+// dot entries are internal state and skipped.
+fn walk(dir: &Path, rel: &str, files: &mut Vec<String>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let child = if rel.is_empty() {
+            name
+        } else {
+            format!("{}/{}", rel, name)
+        };
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), &child, files)?;
+        } else {
+            files.push(child);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Partition, Rotation, SegmentWriter};
+    use super::*;
+
+    fn time() -> DateTime<Tz> {
+        let utc: DateTime<Utc> = "2022-04-01T08:30:00Z".parse().unwrap();
+        utc.with_timezone(&Tz::UTC)
+    }
+
+    #[test]
+    fn test_layout_parse() {
+        assert!(Layout::parse("{bucket}/{device_id}/{date}.txt").is_err());
+        assert!(Layout::parse("{bucket}/{date}.log").is_err());
+        assert!(Layout::parse("{bucket}/{device_id}.log").is_err());
+        assert!(Layout::parse("{bucket}/{yyyy}/{dd}/{device_id}.log").is_err());
+        assert!(Layout::parse("{bucket}/../{device_id}/{date}.log").is_err());
+        assert!(Layout::parse("/{bucket}/{device_id}/{date}.log").is_err());
+        assert!(Layout::parse("{bucket}/{device_hash:0}/{device_id}/{date}.log").is_err());
+        assert!(Layout::parse("{bucket}/{device}/{date}.log").is_err());
+        assert!(Layout::parse("{bucket}/{yyyy}/{mm}/{dd}/{device_id}.log").is_ok());
+    }
+
+    #[test]
+    fn test_layout_locate() {
+        let dir = Path::new("/w");
+        let clock = Clock {
+            tz: Tz::UTC,
+            partition: Partition::Hourly,
+        };
+        let by_day: Layout = "{bucket}/{yyyy}/{mm}/{dd}/{device_id}.log".parse().unwrap();
+        assert!(by_day.check_partition(Partition::Hourly).is_err());
+        assert!(by_day.check_partition(Partition::Monthly).is_err());
+        assert!(by_day.check_partition(Partition::Daily).is_ok());
+        let daily = Clock {
+            partition: Partition::Daily,
+            ..clock
+        };
+        let location = by_day.locate(dir, "sms", "100", &daily, &time());
+        assert_eq!(location.key, "20220401");
+        assert_eq!(
+            location.segment(0),
+            PathBuf::from("/w/sms/2022/04/01/100.log")
+        );
+        assert_eq!(
+            location.segment(2),
+            PathBuf::from("/w/sms/2022/04/01/100.2.log")
+        );
+
+        let hashed: Layout = "{bucket}/{device_hash:2}/{device_id}/{date}.log"
+            .parse()
+            .unwrap();
+        let location = hashed.locate(dir, "sms", "100", &clock, &time());
+        assert_eq!(location.key, "2022040108");
+        assert_eq!(
+            location.segment(0),
+            PathBuf::from("/w/sms/ad/100/2022040108.log")
+        );
+    }
+
+    #[test]
+    fn test_layout_list() {
+        let dir = PathBuf::from("./logs/web_hook_test/layout");
+        let _ = fs::remove_dir_all(&dir);
+        let layout: Layout = "{bucket}/{yyyy}/{mm}/{dd}/{device_id}.log".parse().unwrap();
+        let writer = SegmentWriter::default();
+        let rotation = Rotation {
+            max_size: None,
+            max_records: Some(1),
+        };
+        for device_id in ["100", "200"] {
+            for _ in 0..2 {
+                let location = layout.locate(&dir, "sms", device_id, &Clock::default(), &time());
+                writer
+                    .append(&location, &rotation, None, device_id)
+                    .unwrap();
+            }
+        }
+
+        let all = layout.list(&dir, Filter::default()).unwrap();
+        let found: Vec<(&str, u32)> = all
+            .iter()
+            .map(|s| (s.device_id.as_str(), s.index))
+            .collect();
+        assert_eq!(found, vec![("100", 0), ("100", 1), ("200", 0), ("200", 1)]);
+        assert!(all
+            .iter()
+            .all(|s| s.bucket == "sms" && s.date == "20220401"));
+
+        let filter = Filter {
+            bucket: Some("sms"),
+            device_id: Some("200"),
+            key: Some("202204"),
+        };
+        assert_eq!(layout.list(&dir, filter).unwrap().len(), 2);
+
+        // would be read back as `.1` of device "100"
+        let log_path = LogPath {
+            bucket: String::from("sms"),
+            device_id: String::from("100.1"),
+        };
+        assert!(layout.check(&log_path).is_err());
+        assert!(Layout::default().check(&log_path).is_ok());
+
+        let filter = Filter {
+            key: Some("20220402"),
+            ..filter
+        };
+        assert!(layout.list(&dir, filter).unwrap().is_empty());
+    }
+}
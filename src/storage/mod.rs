@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub mod attach;
+pub mod chain;
+pub mod compact;
+pub mod crypt;
+pub mod layout;
+pub mod partition;
+pub mod path;
+pub mod retention;
+pub mod s3;
+pub mod upload;
+
+pub use attach::Sidecar;
+pub use chain::ChainConfig;
+pub use compact::{Codec, Compactor};
+pub use crypt::{Cipher, Encryption, Keyring};
+pub use layout::{Filter, Layout, Location};
+pub use partition::{Clock, Partition};
+pub use path::{LogPath, PathError, PathRules};
+pub use retention::Retention;
+pub use upload::{UploadConfig, Uploader};
+
+/**
+ * On-disk layout of a device's log, with the default `Layout`:
+ *   {dir}/{bucket}/{device_id}/{date}.log       first segment of the day
+ *   {dir}/{bucket}/{device_id}/{date}.{n}.log   n-th rolled segment (n >= 1)
+ *   {dir}/{bucket}/{device_id}/{date}.log.gz    closed segment, compressed (or .zst)
+ *
+ * `{date}` is YYYYMMDD by default, YYYYMMDDHH or YYYYMM for hourly or
+ * monthly partitions, see `Partition`.
+ */
+
+// Both limits are optional, a segment rolls when any of them is reached.
+#[derive(Debug, Default, Clone)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub max_records: Option<u64>,
+}
+
+impl Rotation {
+    fn should_roll(&self, state: &ActiveSegment, incoming: u64) -> bool {
+        if state.records == 0 {
+            // never leave a segment empty, even for an oversized line
+            return false;
+        }
+        if let Some(max_size) = self.max_size {
+            if state.size + incoming > max_size {
+                return true;
+            }
+        }
+        if let Some(max_records) = self.max_records {
+            if state.records >= max_records {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub path: PathBuf,
+    pub bucket: String,
+    pub device_id: String,
+    // partition key
+    pub date: String,
+    pub index: u32,
+    pub codec: Option<Codec>,
+}
+
+impl Segment {
+    // plain files sort before compressed ones of the same index
+    fn sort_key(&self) -> (&str, &str, &str, u32, bool) {
+        (
+            &self.bucket,
+            &self.device_id,
+            &self.date,
+            self.index,
+            self.codec.is_some(),
+        )
+    }
+}
+
+pub fn open_segment(segment: &Segment) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(&segment.path)?;
+    match segment.codec {
+        Some(codec) => codec.decoder(file),
+        None => Ok(Box::new(BufReader::new(file))),
+    }
+}
+
+// Remembers the active segment of every device so that rotation does not
+// need to rescan the file on each write. Shared by all workers. A chained
+// writer links every line to the one before, see `chain`.
+#[derive(Debug, Default)]
+pub struct SegmentWriter {
+    active: Mutex<Active>,
+    chain: bool,
+}
+
+type Slot = Arc<Mutex<Option<ActiveSegment>>>;
+
+#[derive(Debug, Default)]
+struct Active {
+    // by bucket, device and partition key, locked on their own
+    segments: HashMap<(String, String, String), Slot>,
+    // newest partition key per bucket
+    latest: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+struct ActiveSegment {
+    index: u32,
+    size: u64,
+    records: u64,
+    // hash of the last line, for chained writers
+    head: String,
+    // first line of the segment, see `crypt`
+    header: Option<String>,
+}
+
+impl SegmentWriter {
+    pub fn chained() -> Self {
+        SegmentWriter {
+            chain: true,
+            ..Default::default()
+        }
+    }
+
+    // `header` starts every segment, a segment with another header is
+    // rolled over.
+    pub fn append(
+        &self,
+        location: &Location,
+        rotation: &Rotation,
+        header: Option<&str>,
+        line: &str,
+    ) -> io::Result<PathBuf> {
+        let slot = self.slot(location);
+        let mut slot = slot.lock().unwrap();
+        if slot.is_none() {
+            *slot = Some(scan_active(location)?);
+        }
+        let state = slot.as_mut().unwrap();
+
+        let incoming = self.stored_len(line);
+        let other_header = state.size > 0 && state.header.as_deref() != header;
+        if other_header || rotation.should_roll(state, incoming) {
+            state.index += 1;
+            state.size = 0;
+            state.records = 0;
+            state.head = String::from(chain::GENESIS);
+            state.header = None;
+        }
+
+        let log_file = location.segment(state.index);
+        if let Some(parent) = log_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file)?;
+        if let (0, Some(header)) = (state.size, header) {
+            self.write_line(&mut file, state, header)?;
+            state.header = Some(header.to_string());
+        }
+        self.write_line(&mut file, state, line)?;
+        state.records += 1;
+        Ok(log_file)
+    }
+
+    // Past partitions of a bucket are dropped once a newer one shows up,
+    // unless a write holds them.
+    fn slot(&self, location: &Location) -> Slot {
+        let mut active = self.active.lock().unwrap();
+        let newer = active
+            .latest
+            .get(&location.bucket)
+            .is_none_or(|key| *key < location.key);
+        if newer {
+            active
+                .latest
+                .insert(location.bucket.clone(), location.key.clone());
+            active.segments.retain(|(bucket, _, key), slot| {
+                *bucket != location.bucket || *key >= location.key || Arc::strong_count(slot) > 1
+            });
+        }
+        let id = (
+            location.bucket.clone(),
+            location.device_id.clone(),
+            location.key.clone(),
+        );
+        active.segments.entry(id).or_default().clone()
+    }
+
+    fn stored_len(&self, line: &str) -> u64 {
+        match self.chain {
+            true => line.len() as u64 + 1 + chain::LINK_LEN,
+            false => line.len() as u64 + 1,
+        }
+    }
+
+    fn write_line(&self, file: &mut File, state: &mut ActiveSegment, line: &str) -> io::Result<()> {
+        if self.chain {
+            let head = chain::link(&state.head, line);
+            writeln!(file, "{}\t{}", line, head)?;
+            state.head = head;
+        } else {
+            writeln!(file, "{}", line)?;
+        }
+        state.size += self.stored_len(line);
+        Ok(())
+    }
+}
+
+// Pick up where a previous run left off: the last segment of the partition.
+fn scan_active(location: &Location) -> io::Result<ActiveSegment> {
+    let mut index = 0;
+    while location.segment(index + 1).exists() {
+        index += 1;
+    }
+    let path = location.segment(index);
+    let mut head = String::from(chain::GENESIS);
+    let mut header = None;
+    let (size, records) = match File::open(&path) {
+        Ok(file) => {
+            let mut records = 0;
+            let mut last = Vec::new();
+            for line in BufReader::new(file).split(b'\n') {
+                last = line?;
+                if records == 0 && header.is_none() && last.starts_with(b"#") {
+                    let first = String::from_utf8_lossy(&last).to_string();
+                    header = Some(match chain::split(&first) {
+                        Some((line, _)) => line.to_string(),
+                        None => first,
+                    });
+                    continue;
+                }
+                records += 1;
+            }
+            if let Some((_, hash)) = chain::split(&String::from_utf8_lossy(&last)) {
+                head = hash.to_string();
+            }
+            (fs::metadata(&path)?.len(), records)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (0, 0),
+        Err(e) => return Err(e),
+    };
+    Ok(ActiveSegment {
+        index,
+        size,
+        records,
+        head,
+        header,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from(format!("./logs/web_hook_test/storage/{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn location(dir: &Path, key: &str) -> Location {
+        Location {
+            bucket: String::from("sms"),
+            device_id: String::from("100"),
+            key: key.to_string(),
+            stem: dir.join("sms").join("100").join(key),
+        }
+    }
+
+    #[test]
+    fn test_rotate_by_records() {
+        let dir = test_dir("records");
+        let writer = SegmentWriter::default();
+        let rotation = Rotation {
+            max_size: None,
+            max_records: Some(2),
+        };
+        for i in 0..5 {
+            writer
+                .append(
+                    &location(&dir, "20220401"),
+                    &rotation,
+                    None,
+                    &format!("line {}", i),
+                )
+                .unwrap();
+        }
+
+        let segments = Layout::default().list(&dir, Filter::default()).unwrap();
+        let indexes: Vec<u32> = segments.iter().map(|s| s.index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+
+        let mut lines = Vec::new();
+        for segment in segments.iter() {
+            for line in open_segment(segment).unwrap().lines() {
+                lines.push(line.unwrap());
+            }
+        }
+        assert_eq!(
+            lines,
+            vec!["line 0", "line 1", "line 2", "line 3", "line 4"]
+        );
+    }
+
+    #[test]
+    fn test_writer_drops_past_partitions() {
+        let dir = test_dir("past");
+        let writer = SegmentWriter::default();
+        let mut other = location(&dir, "20220401");
+        other.device_id = String::from("200");
+        for location in [location(&dir, "20220401"), other] {
+            writer
+                .append(&location, &Rotation::default(), None, "line")
+                .unwrap();
+        }
+        assert_eq!(writer.active.lock().unwrap().segments.len(), 2);
+
+        writer
+            .append(
+                &location(&dir, "20220402"),
+                &Rotation::default(),
+                None,
+                "line",
+            )
+            .unwrap();
+        let active = writer.active.lock().unwrap();
+        let keys: Vec<&String> = active.segments.keys().map(|(_, _, key)| key).collect();
+        assert_eq!(keys, vec!["20220402"]);
+    }
+
+    #[test]
+    fn test_rotate_by_size_resumes() {
+        let dir = test_dir("size");
+        let rotation = Rotation {
+            max_size: Some(10),
+            max_records: None,
+        };
+        SegmentWriter::default()
+            .append(&location(&dir, "20220401"), &rotation, None, "12345678")
+            .unwrap();
+
+        // a fresh writer must continue the existing segment state
+        let writer = SegmentWriter::default();
+        let path = writer
+            .append(&location(&dir, "20220401"), &rotation, None, "abc")
+            .unwrap();
+        assert!(path.ends_with("20220401.1.log"));
+        let path = writer
+            .append(&location(&dir, "20220402"), &rotation, None, "abc")
+            .unwrap();
+        assert!(path.ends_with("20220402.log"));
+    }
+}
@@ -0,0 +1,106 @@
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+// How much time one log file covers, keys sort in time order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Partition {
+    Hourly,
+    #[default]
+    Daily,
+    Monthly,
+}
+
+impl Partition {
+    pub fn format(&self) -> &'static str {
+        match self {
+            Partition::Hourly => "%Y%m%d%H",
+            Partition::Daily => "%Y%m%d",
+            Partition::Monthly => "%Y%m",
+        }
+    }
+
+    // Start of the partition a key stands for, whatever its granularity.
+    pub fn parse(key: &str) -> Option<NaiveDateTime> {
+        match key.len() {
+            10 => NaiveDateTime::parse_from_str(&format!("{}0000", key), "%Y%m%d%H%M%S").ok(),
+            8 => NaiveDate::parse_from_str(key, "%Y%m%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0)),
+            6 => NaiveDate::parse_from_str(&format!("{}01", key), "%Y%m%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0)),
+            4 => NaiveDate::parse_from_str(&format!("{}0101", key), "%Y%m%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0)),
+            _ => None,
+        }
+    }
+}
+
+// Time zone and granularity of the files of a bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clock {
+    pub tz: Tz,
+    pub partition: Partition,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock {
+            tz: Tz::UTC,
+            partition: Partition::default(),
+        }
+    }
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Tz> {
+        self.at(Utc::now())
+    }
+
+    pub fn at(&self, time: DateTime<Utc>) -> DateTime<Tz> {
+        time.with_timezone(&self.tz)
+    }
+
+    pub fn key(&self, time: &DateTime<Tz>) -> String {
+        time.format(self.partition.format()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_key() {
+        let time: DateTime<Utc> = "2022-03-31T16:30:00Z".parse().unwrap();
+        let utc = Clock::default();
+        let shanghai = Clock {
+            tz: "Asia/Shanghai".parse().unwrap(),
+            partition: Partition::Hourly,
+        };
+        assert_eq!(utc.key(&utc.at(time)), "20220331");
+        assert_eq!(shanghai.key(&shanghai.at(time)), "2022040100");
+        assert_eq!(
+            shanghai.at(time).format("%+").to_string(),
+            "2022-04-01T00:30:00+08:00"
+        );
+        let monthly = Clock {
+            partition: Partition::Monthly,
+            ..shanghai
+        };
+        assert_eq!(monthly.key(&monthly.at(time)), "202204");
+    }
+
+    #[test]
+    fn test_partition_parse() {
+        let at = |s: &str| s.parse::<NaiveDateTime>().ok();
+        assert_eq!(Partition::parse("2022040108"), at("2022-04-01T08:00:00"));
+        assert_eq!(Partition::parse("20220401"), at("2022-04-01T00:00:00"));
+        assert_eq!(Partition::parse("202204"), at("2022-04-01T00:00:00"));
+        assert_eq!(Partition::parse("2022"), at("2022-01-01T00:00:00"));
+        assert_eq!(Partition::parse("20224"), None);
+    }
+}
@@ -0,0 +1,142 @@
+use regex::Regex;
+use std::error::Error;
+use std::fmt;
+use std::path::{Component, Path};
+
+pub const DEFAULT_CHARS: &str = "A-Za-z0-9_.-";
+pub const DEFAULT_MAX_LEN: usize = 64;
+
+// Bucket and device_id become dir names, checked before touching disk.
+#[derive(Debug, Clone)]
+pub struct PathRules {
+    pub max_len: usize,
+    pub lowercase: bool,
+    allowed: Regex,
+}
+
+impl Default for PathRules {
+    fn default() -> Self {
+        PathRules::new(DEFAULT_CHARS, DEFAULT_MAX_LEN, false).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    Empty(&'static str),
+    TooLong(&'static str, usize),
+    BadChar(&'static str, char),
+    Reserved(&'static str),
+    Escapes,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Empty(what) => write!(f, "invalid {}: empty", what),
+            PathError::TooLong(what, max) => {
+                write!(f, "invalid {}: longer than {} chars", what, max)
+            }
+            PathError::BadChar(what, c) => write!(f, "invalid {}: char {:?} not allowed", what, c),
+            PathError::Reserved(what) => write!(f, "invalid {}: reserved name", what),
+            PathError::Escapes => write!(f, "invalid path: outside of work dir"),
+        }
+    }
+}
+
+impl Error for PathError {}
+
+// A validated bucket and device_id, safe to put into a `Layout`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogPath {
+    pub bucket: String,
+    pub device_id: String,
+}
+
+impl PathRules {
+    // `chars` is a regex character class body, e.g. "A-Za-z0-9_.-"
+    pub fn new(chars: &str, max_len: usize, lowercase: bool) -> Result<Self, regex::Error> {
+        Ok(PathRules {
+            max_len,
+            lowercase,
+            allowed: Regex::new(&format!("^[{}]$", chars))?,
+        })
+    }
+
+    pub fn normalize(&self, what: &'static str, segment: &str) -> Result<String, PathError> {
+        let mut segment = segment.trim().to_string();
+        if self.lowercase {
+            segment = segment.to_lowercase();
+        }
+        if segment.is_empty() {
+            return Err(PathError::Empty(what));
+        }
+        if segment.chars().count() > self.max_len {
+            return Err(PathError::TooLong(what, self.max_len));
+        }
+        let mut buf = [0; 4];
+        if let Some(c) = segment
+            .chars()
+            .find(|c| !self.allowed.is_match(c.encode_utf8(&mut buf)))
+        {
+            return Err(PathError::BadChar(what, c));
+        }
+        // dot names are either traversal or internal state dirs
+        if segment.starts_with('.') {
+            return Err(PathError::Reserved(what));
+        }
+        Ok(segment)
+    }
+
+    pub fn resolve(&self, bucket: &str, device_id: &str) -> Result<LogPath, PathError> {
+        let bucket = self.normalize("bucket", bucket)?;
+        let device_id = self.normalize("device_id", device_id)?;
+
+        // whatever the allowed chars are, each must stay one plain path
+        // component, `Layout` literals can not climb out of the work dir
+        for segment in [&bucket, &device_id] {
+            let mut components = Path::new(segment).components();
+            let plain = matches!(components.next(), Some(Component::Normal(_)));
+            if !plain || components.next().is_some() {
+                return Err(PathError::Escapes);
+            }
+        }
+
+        Ok(LogPath { bucket, device_id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let rules = PathRules::default();
+        let ok = rules.resolve(" sms ", "100").unwrap();
+        assert_eq!(ok.bucket, "sms");
+        assert_eq!(ok.device_id, "100");
+
+        assert_eq!(
+            rules.resolve("..", "100"),
+            Err(PathError::Reserved("bucket"))
+        );
+        assert_eq!(
+            rules.resolve("sms", "a/b"),
+            Err(PathError::BadChar("device_id", '/'))
+        );
+        assert_eq!(rules.resolve("sms", ""), Err(PathError::Empty("device_id")));
+        assert_eq!(
+            rules.resolve(&"x".repeat(65), "100"),
+            Err(PathError::TooLong("bucket", 64))
+        );
+    }
+
+    #[test]
+    fn test_resolve_loose_rules() {
+        // even a careless char set cannot escape the work dir
+        let rules = PathRules::new("^\\x00", 256, true).unwrap();
+        assert_eq!(rules.resolve("SMS", "100").unwrap().bucket, "sms");
+        assert_eq!(rules.resolve("sms", "a/../../etc"), Err(PathError::Escapes));
+        assert_eq!(rules.resolve("/etc", "100"), Err(PathError::Escapes));
+    }
+}
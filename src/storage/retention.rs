@@ -0,0 +1,451 @@
+use super::attach::{self, segment_files_dir};
+use super::chain;
+use super::{Filter, Partition, Segment};
+use crate::config::Config;
+use chrono::prelude::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Closed segments beyond any limit expire, oldest first. The current
+// partition counts towards the limits but is never touched.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Retention {
+    pub max_age_days: Option<i64>,
+    pub max_total_bytes: Option<u64>,
+    pub max_files_per_device: Option<usize>,
+    // move expired segments here instead of deleting them
+    pub archive_dir: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    MaxAge,
+    MaxFiles,
+    MaxBytes,
+}
+
+#[derive(Debug)]
+pub struct Expired {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub reason: Reason,
+    pub archived_to: Option<PathBuf>,
+}
+
+// A segment that could not be looked at, moved or removed. The run goes on
+// with the others.
+#[derive(Debug)]
+pub struct Failed {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub dry_run: bool,
+    pub expired: Vec<Expired>,
+    pub failed: Vec<Failed>,
+}
+
+impl Report {
+    pub fn bytes(&self) -> u64 {
+        self.expired.iter().map(|e| e.bytes).sum()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = if self.dry_run {
+            "would expire"
+        } else {
+            "expired"
+        };
+        for e in self.expired.iter() {
+            match &e.archived_to {
+                Some(to) => writeln!(
+                    f,
+                    "{} {} ({} bytes, {:?}) -> {}",
+                    verb,
+                    e.path.display(),
+                    e.bytes,
+                    e.reason,
+                    to.display()
+                )?,
+                None => writeln!(
+                    f,
+                    "{} {} ({} bytes, {:?})",
+                    verb,
+                    e.path.display(),
+                    e.bytes,
+                    e.reason
+                )?,
+            }
+        }
+        for e in self.failed.iter() {
+            writeln!(f, "failed {}: {}", e.path.display(), e.error)?;
+        }
+        write!(
+            f,
+            "{} {} files, {} bytes",
+            verb,
+            self.expired.len(),
+            self.bytes()
+        )?;
+        if !self.failed.is_empty() {
+            write!(f, ", {} failed", self.failed.len())?;
+        }
+        Ok(())
+    }
+}
+
+// Apply the retention rule of every bucket under `dir`. In dry-run mode the
+// report is built but nothing is deleted or moved. Segments that fail are
+// in the report, the rest are still expired.
+pub fn enforce(
+    dir: &Path,
+    config: &Config,
+    now: DateTime<Utc>,
+    dry_run: bool,
+) -> io::Result<Report> {
+    let removed_at = now;
+    let mut report = Report {
+        dry_run,
+        ..Default::default()
+    };
+    let mut buckets: BTreeMap<String, Vec<Segment>> = BTreeMap::new();
+    for segment in config.layout.list(dir, Filter::default())? {
+        buckets
+            .entry(segment.bucket.clone())
+            .or_default()
+            .push(segment);
+    }
+
+    for (bucket, segments) in buckets {
+        let rule = match config.bucket(&bucket).and_then(|b| b.retention.as_ref()) {
+            Some(rule) => rule,
+            None => continue,
+        };
+        let clock = config.clock(&bucket);
+        let now = clock.at(now);
+        let current = config.layout.key(&clock, &now);
+        // segments left of each partition, its files dir goes with the last
+        let mut left: HashMap<(String, String), usize> = HashMap::new();
+        for segment in segments.iter() {
+            *left
+                .entry((segment.device_id.clone(), segment.date.clone()))
+                .or_default() += 1;
+        }
+        let expired = expire_bucket(
+            segments,
+            rule,
+            &current,
+            now.naive_local(),
+            &mut report.failed,
+        );
+        for (segment, bytes, reason) in expired {
+            // the archive keeps the layout of the work dir
+            let archived_to = rule.archive_dir.as_ref().map(|archive| {
+                let rel = segment.path.strip_prefix(dir).unwrap_or(&segment.path);
+                Path::new(archive).join(rel)
+            });
+            if !dry_run {
+                // sealed segments leave a tombstone for verify
+                let reason = match &archived_to {
+                    Some(to) => format!("archived to {}", to.display()),
+                    None => String::from("expired"),
+                };
+                let result =
+                    chain::bury(dir, config, &segment, &reason, removed_at).and_then(|_| {
+                        match &archived_to {
+                            Some(to) => move_file(&segment.path, to),
+                            None => fs::remove_file(&segment.path),
+                        }
+                    });
+                if let Err(error) = result {
+                    report.failed.push(Failed {
+                        path: segment.path,
+                        error,
+                    });
+                    continue;
+                }
+            }
+            let partition = (segment.device_id.clone(), segment.date.clone());
+            let files = segment_files_dir(&segment);
+            report.expired.push(Expired {
+                path: segment.path,
+                bytes,
+                reason,
+                archived_to,
+            });
+            match left.get_mut(&partition) {
+                Some(n) if *n > 1 => *n -= 1,
+                _ => expire_files(dir, &files, rule, reason, dry_run, &mut report),
+            }
+        }
+    }
+    Ok(report)
+}
+
+// `segments` come sorted by device, then partition and index.
+fn expire_bucket(
+    segments: Vec<Segment>,
+    rule: &Retention,
+    current: &str,
+    now: NaiveDateTime,
+    failed: &mut Vec<Failed>,
+) -> Vec<(Segment, u64, Reason)> {
+    let mut expired = Vec::new();
+    let mut kept = Vec::new();
+    let mut total: u64 = 0;
+
+    let mut devices: Vec<Vec<Segment>> = Vec::new();
+    for segment in segments {
+        match devices.last_mut() {
+            Some(device) if device[0].device_id == segment.device_id => device.push(segment),
+            _ => devices.push(vec![segment]),
+        }
+    }
+
+    for segments in devices {
+        let over = rule
+            .max_files_per_device
+            .map_or(0, |max| segments.len().saturating_sub(max));
+
+        for (i, segment) in segments.into_iter().enumerate() {
+            let bytes = match fs::metadata(&segment.path) {
+                Ok(metadata) => metadata.len(),
+                Err(error) => {
+                    failed.push(Failed {
+                        path: segment.path,
+                        error,
+                    });
+                    continue;
+                }
+            };
+            total += bytes;
+            if segment.date.as_str() >= current {
+                continue;
+            }
+            let too_old = match (rule.max_age_days, Partition::parse(&segment.date)) {
+                (Some(days), Some(start)) => (now.date() - start.date()).num_days() > days,
+                _ => false,
+            };
+            if too_old {
+                expired.push((segment, bytes, Reason::MaxAge));
+            } else if i < over {
+                expired.push((segment, bytes, Reason::MaxFiles));
+            } else {
+                kept.push((segment, bytes));
+            }
+        }
+    }
+    total -= expired.iter().map(|(_, bytes, _)| bytes).sum::<u64>();
+
+    if let Some(max) = rule.max_total_bytes {
+        // oldest first across all devices of the bucket
+        kept.sort_by(|(a, _), (b, _)| (&a.date, a.index).cmp(&(&b.date, b.index)));
+        for (segment, bytes) in kept {
+            if total <= max {
+                break;
+            }
+            total -= bytes;
+            expired.push((segment, bytes, Reason::MaxBytes));
+        }
+    }
+    expired
+}
+
+// The files dir of a partition whose segments are all gone, archived or
+// removed the same way.
+fn expire_files(
+    dir: &Path,
+    files: &Path,
+    rule: &Retention,
+    reason: Reason,
+    dry_run: bool,
+    report: &mut Report,
+) {
+    let paths = match attach::list_files(files) {
+        Ok(paths) if paths.is_empty() => return,
+        Ok(paths) => paths,
+        Err(error) => {
+            report.failed.push(Failed {
+                path: files.to_path_buf(),
+                error,
+            });
+            return;
+        }
+    };
+    let archived_to = rule.archive_dir.as_ref().map(|archive| {
+        let rel = files.strip_prefix(dir).unwrap_or(files);
+        Path::new(archive).join(rel)
+    });
+    let mut bytes = 0;
+    for path in paths {
+        let result = fs::metadata(&path).map(|m| m.len()).and_then(|len| {
+            bytes += len;
+            match (&archived_to, dry_run) {
+                (_, true) => Ok(()),
+                (Some(to), false) => move_file(&path, &to.join(path.file_name().unwrap())),
+                (None, false) => fs::remove_file(&path),
+            }
+        });
+        if let Err(error) = result {
+            report.failed.push(Failed { path, error });
+            return;
+        }
+    }
+    if !dry_run {
+        let _ = fs::remove_dir(files);
+    }
+    report.expired.push(Expired {
+        path: files.to_path_buf(),
+        bytes,
+        reason,
+        archived_to,
+    });
+}
+
+// Rename, falling back to copy and remove across filesystems.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Location, Rotation, SegmentWriter};
+    use super::*;
+    use crate::config::BucketConfig;
+
+    fn setup(name: &str) -> PathBuf {
+        let dir = PathBuf::from(format!("./logs/web_hook_test/retention/{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        let writer = SegmentWriter::default();
+        let rotation = Rotation::default();
+        for device_id in ["100", "200"] {
+            for date in ["20220301", "20220330", "20220331", "20220401"] {
+                let location = Location {
+                    bucket: String::from("sms"),
+                    device_id: device_id.to_string(),
+                    key: date.to_string(),
+                    stem: dir.join("sms").join(device_id).join(date),
+                };
+                writer
+                    .append(&location, &rotation, None, "0123456789")
+                    .unwrap();
+            }
+        }
+        dir
+    }
+
+    fn config(retention: Retention) -> Config {
+        let mut config = Config::default();
+        config.buckets.insert(
+            String::from("sms"),
+            BucketConfig {
+                retention: Some(retention),
+                ..Default::default()
+            },
+        );
+        config
+    }
+
+    fn now() -> DateTime<Utc> {
+        "2022-04-01T08:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn test_retention_dry_run() {
+        let dir = setup("dry_run");
+        let config = config(Retention {
+            max_age_days: Some(7),
+            max_files_per_device: Some(3),
+            ..Default::default()
+        });
+
+        let report = enforce(&dir, &config, now(), true).unwrap();
+        assert_eq!(report.expired.len(), 2);
+        assert!(report.expired.iter().all(|e| e.reason == Reason::MaxAge));
+        assert!(dir.join("sms/100/20220301.log").exists());
+    }
+
+    #[test]
+    fn test_retention_quota_and_archive() {
+        let dir = setup("quota");
+        let archive = dir.join("archive");
+        let config = config(Retention {
+            max_total_bytes: Some(50),
+            archive_dir: Some(archive.to_string_lossy().to_string()),
+            ..Default::default()
+        });
+
+        // 8 files of 11 bytes, the two of today are kept
+        let report = enforce(&dir, &config, now(), false).unwrap();
+        assert_eq!(report.expired.len(), 4);
+        assert!(report.expired.iter().all(|e| e.reason == Reason::MaxBytes));
+        assert!(!dir.join("sms/100/20220330.log").exists());
+        assert!(dir.join("sms/100/20220331.log").exists());
+        assert!(archive.join("sms/200/20220330.log").exists());
+        assert!(dir.join("sms/200/20220401.log").exists());
+    }
+
+    #[test]
+    fn test_retention_failures() {
+        let dir = setup("failures");
+        let archive = dir.join("archive");
+        let config = config(Retention {
+            max_age_days: Some(7),
+            archive_dir: Some(archive.to_string_lossy().to_string()),
+            ..Default::default()
+        });
+
+        // a file where the archive of device 100 goes, device 200 still expires
+        fs::create_dir_all(archive.join("sms")).unwrap();
+        fs::write(archive.join("sms/100"), b"").unwrap();
+        let report = enforce(&dir, &config, now(), false).unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].path, dir.join("sms/100/20220301.log"));
+        assert_eq!(report.expired.len(), 1);
+        assert!(dir.join("sms/100/20220301.log").exists());
+        assert!(archive.join("sms/200/20220301.log").exists());
+        assert!(report.to_string().ends_with(", 1 failed"));
+    }
+
+    #[test]
+    fn test_retention_files() {
+        let dir = setup("files");
+        fs::write(dir.join("sms/100/20220301.1.log"), b"0123456789\n").unwrap();
+        for date in ["20220301", "20220331"] {
+            let files = dir.join(format!("sms/100/{}.files", date));
+            fs::create_dir_all(&files).unwrap();
+            fs::write(files.join("01G0Z5W3B3N3YV1QKX4CQ4G9HD-body"), b"body").unwrap();
+        }
+        let config = config(Retention {
+            max_age_days: Some(7),
+            ..Default::default()
+        });
+
+        // the files dir goes with the last segment of its partition
+        let report = enforce(&dir, &config, now(), false).unwrap();
+        assert_eq!(report.expired.len(), 4);
+        let files = report
+            .expired
+            .iter()
+            .find(|e| e.path == dir.join("sms/100/20220301.files"))
+            .unwrap();
+        assert_eq!(files.bytes, 4);
+        assert!(!dir.join("sms/100/20220301.files").exists());
+        assert!(dir.join("sms/100/20220331.files").exists());
+    }
+}
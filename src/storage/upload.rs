@@ -0,0 +1,501 @@
+use super::attach::{self, segment_files_dir, FILES_SUFFIX};
+use super::chain;
+use super::s3::{self, S3Client, S3Config};
+use super::{Filter, Segment};
+use crate::config::Config;
+use actix_web::web;
+use chrono::prelude::*;
+use chrono::Duration;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST: &str = ".upload/manifest.json";
+// the manifest is saved after this many changes, and at the end of a run
+const SAVE_EVERY: usize = 100;
+// deleted entries whose object is checked per run
+const PRUNE_EVERY: usize = 100;
+
+// "upload" in the config file:
+//
+//   { "endpoint": "http://localhost:9000", "bucket": "hooks", "path_style": true,
+//     "prefix": "logs/", "delete_local": true }
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadConfig {
+    #[serde(flatten)]
+    pub s3: S3Config,
+    // prepended to the path below the work dir to make the object key
+    #[serde(default)]
+    pub prefix: String,
+    // remove the local file once the upload is verified
+    #[serde(default)]
+    pub delete_local: bool,
+    // seconds before the first retry, doubled on each failure
+    #[serde(default = "default_backoff")]
+    pub backoff: i64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: i64,
+}
+
+fn default_backoff() -> i64 {
+    60
+}
+
+fn default_max_backoff() -> i64 {
+    6 * 3600
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    Pending,
+    Uploaded,
+    // uploaded, and the local copy removed
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    pub size: u64,
+    pub sha256: String,
+    pub state: UploadState,
+    pub attempts: u32,
+    pub next_try: Option<DateTime<Utc>>,
+    pub uploaded_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+// Upload state of every segment, by path below the work dir.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<String, Entry>,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> io::Result<Manifest> {
+        match fs::read(dir.join(MANIFEST)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let path = dir.join(MANIFEST);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &path)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Uploader {
+    pub config: UploadConfig,
+    client: S3Client,
+}
+
+impl Uploader {
+    pub fn new(config: UploadConfig) -> io::Result<Self> {
+        Ok(Uploader {
+            client: S3Client::new(&config.s3)?,
+            config,
+        })
+    }
+
+    // Upload closed segments and their files, only compressed segments with
+    // `compressed`. Returns the uploaded keys.
+    pub async fn run(
+        &self,
+        http: &awc::Client,
+        dir: &Path,
+        config: &Config,
+        now: DateTime<Utc>,
+        compressed: bool,
+    ) -> io::Result<Vec<String>> {
+        let (owned_dir, owned_config) = (dir.to_path_buf(), config.clone());
+        let (mut manifest, paths, gone) =
+            blocking(move || scan(&owned_dir, &owned_config, now, compressed)).await?;
+
+        let mut done = Vec::new();
+        let mut changed = 0;
+        for (path, segment, size) in paths {
+            let name = relative(dir, &path);
+            let entry = manifest.files.get(&name);
+            match entry {
+                Some(e) if e.state == UploadState::Uploaded && e.size == size => {
+                    // delete_local may have been turned on since
+                    if self.config.delete_local {
+                        self.delete(dir, config, path, segment, &mut manifest, now)
+                            .await?;
+                        changed += 1;
+                    }
+                    continue;
+                }
+                Some(e) if e.next_try.is_some_and(|t| t > now) => continue,
+                _ => {}
+            }
+
+            let attempts = entry.map_or(0, |e| e.attempts);
+            let result = self.upload(http, &path, &name).await;
+            let entry = match result {
+                Ok(entry) => {
+                    info!("uploaded {} to {}", name, entry.key);
+                    done.push(entry.key.clone());
+                    Entry {
+                        uploaded_at: Some(now),
+                        ..entry
+                    }
+                }
+                Err(e) => {
+                    warn!("upload {} failed: {}", name, e);
+                    let wait = self
+                        .config
+                        .backoff
+                        .saturating_mul(1 << attempts.min(20))
+                        .min(self.config.max_backoff);
+                    Entry {
+                        key: self.key(&name),
+                        size,
+                        sha256: String::new(),
+                        state: UploadState::Pending,
+                        attempts: attempts + 1,
+                        next_try: Some(now + Duration::seconds(wait)),
+                        uploaded_at: None,
+                        error: Some(e.to_string()),
+                    }
+                }
+            };
+            let uploaded = entry.state == UploadState::Uploaded;
+            manifest.files.insert(name.clone(), entry);
+            if uploaded && self.config.delete_local {
+                self.delete(dir, config, path, segment, &mut manifest, now)
+                    .await?;
+            }
+            changed += 1;
+            if changed % SAVE_EVERY == 0 {
+                save(dir, &manifest).await?;
+            }
+        }
+        self.prune(http, &mut manifest, gone, now).await;
+        save(dir, &manifest).await?;
+        Ok(done)
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.config.prefix, name)
+    }
+
+    // Put the file, then check the object is there with the same size.
+    // The payload hash is signed, so the store rejects a corrupted body.
+    // Hashing and reading both stay off the arbiter, the file is streamed.
+    async fn upload(&self, http: &awc::Client, path: &Path, name: &str) -> io::Result<Entry> {
+        let file = path.to_path_buf();
+        let (size, sha256) = blocking(move || s3::digest(&file)).await?;
+        let key = self.key(name);
+        self.client
+            .put_object(http, &key, path, size, &sha256)
+            .await?;
+        match self.client.head_object(http, &key).await? {
+            Some(info) if info.size == size => Ok(Entry {
+                key,
+                size,
+                sha256,
+                state: UploadState::Uploaded,
+                attempts: 0,
+                next_try: None,
+                uploaded_at: None,
+                error: None,
+            }),
+            Some(info) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("uploaded {} bytes, stored {}", size, info.size),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "object missing after upload",
+            )),
+        }
+    }
+
+    // A sealed segment leaves a tombstone first, see `chain`. A deleted
+    // entry is not looked at again by `prune` before max_backoff.
+    async fn delete(
+        &self,
+        dir: &Path,
+        config: &Config,
+        path: PathBuf,
+        segment: Option<Segment>,
+        manifest: &mut Manifest,
+        now: DateTime<Utc>,
+    ) -> io::Result<()> {
+        let name = relative(dir, &path);
+        let reason = format!("uploaded to {}", self.key(&name));
+        let (owned_dir, owned_config) = (dir.to_path_buf(), config.clone());
+        blocking(move || {
+            if let Some(segment) = segment {
+                chain::bury(&owned_dir, &owned_config, &segment, &reason, now)?;
+            }
+            fs::remove_file(&path)?;
+            // a files dir goes once its last file is shipped
+            if let Some(parent) = path.parent() {
+                if parent.to_string_lossy().ends_with(FILES_SUFFIX) {
+                    let _ = fs::remove_dir(parent);
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        if let Some(entry) = manifest.files.get_mut(&name) {
+            entry.state = UploadState::Deleted;
+            entry.next_try = Some(now + Duration::seconds(self.config.max_backoff));
+        }
+        Ok(())
+    }
+
+    // Drop entries whose local file is gone: at once when never uploaded,
+    // once the object is gone too when deleted after upload. A few objects
+    // are checked per run.
+    async fn prune(
+        &self,
+        http: &awc::Client,
+        manifest: &mut Manifest,
+        gone: Vec<String>,
+        now: DateTime<Utc>,
+    ) {
+        let mut checked = 0;
+        for name in gone {
+            let Some(entry) = manifest.files.get_mut(&name) else {
+                continue;
+            };
+            if entry.state != UploadState::Deleted {
+                manifest.files.remove(&name);
+                continue;
+            }
+            if entry.next_try.is_some_and(|t| t > now) || checked == PRUNE_EVERY {
+                continue;
+            }
+            checked += 1;
+            match self.client.head_object(http, &entry.key).await {
+                Ok(None) => {
+                    manifest.files.remove(&name);
+                }
+                Ok(Some(_)) => {
+                    entry.next_try = Some(now + Duration::seconds(self.config.max_backoff))
+                }
+                Err(e) => warn!("check {} failed: {}", entry.key, e),
+            }
+        }
+    }
+}
+
+// Closed segments with their sizes, and the files of their partitions,
+// along with the manifest and the names in it without a local file.
+type Scan = (Manifest, Vec<(PathBuf, Option<Segment>, u64)>, Vec<String>);
+
+fn scan(dir: &Path, config: &Config, now: DateTime<Utc>, compressed: bool) -> io::Result<Scan> {
+    let manifest = Manifest::load(dir)?;
+    let mut current: HashMap<String, String> = HashMap::new();
+    let mut paths = Vec::new();
+    let mut files_dirs = HashSet::new();
+    for segment in config.layout.list(dir, Filter::default())? {
+        let current = current.entry(segment.bucket.clone()).or_insert_with(|| {
+            let clock = config.clock(&segment.bucket);
+            config.layout.key(&clock, &clock.at(now))
+        });
+        if segment.date >= *current || (compressed && segment.codec.is_none()) {
+            continue;
+        }
+        let files = segment_files_dir(&segment);
+        let size = fs::metadata(&segment.path)?.len();
+        paths.push((segment.path.clone(), Some(segment), size));
+        if files_dirs.insert(files.clone()) {
+            for path in attach::list_files(&files)? {
+                let size = fs::metadata(&path)?.len();
+                paths.push((path, None, size));
+            }
+        }
+    }
+    let gone = manifest
+        .files
+        .keys()
+        .filter(|name| !dir.join(name).exists())
+        .cloned()
+        .collect();
+    Ok((manifest, paths, gone))
+}
+
+async fn save(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    let (dir, manifest) = (dir.to_path_buf(), manifest.clone());
+    blocking(move || manifest.save(&dir)).await
+}
+
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f)
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+}
+
+// Path below the work dir with '/' separators, also the object key.
+fn relative(dir: &Path, path: &Path) -> String {
+    let path = path.strip_prefix(dir).unwrap_or(path);
+    let parts: Vec<String> = path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Location, Rotation, SegmentWriter};
+    use super::*;
+    use actix_web::{rt, web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::Mutex;
+
+    type Store = web::Data<Mutex<(u32, HashMap<String, Vec<u8>>)>>;
+
+    // A bucket that fails the first PUT.
+    async fn object(req: HttpRequest, body: web::Bytes, store: Store) -> HttpResponse {
+        let mut store = store.lock().unwrap();
+        let key = req.path().to_string();
+        match req.method().as_str() {
+            "PUT" => {
+                store.0 += 1;
+                if store.0 == 1 {
+                    return HttpResponse::InternalServerError().finish();
+                }
+                store.1.insert(key, body.to_vec());
+                HttpResponse::Ok().finish()
+            }
+            _ => match store.1.get(&key) {
+                Some(body) => HttpResponse::Ok().body(body.clone()),
+                None => HttpResponse::NotFound().finish(),
+            },
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_upload_closed_segments() {
+        let store: Store = web::Data::new(Mutex::new((0, HashMap::new())));
+        let data = store.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .default_service(web::to(object))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        rt::spawn(server.run());
+
+        let dir = Path::new("./logs/web_hook_test/upload");
+        let _ = fs::remove_dir_all(dir);
+        let writer = SegmentWriter::default();
+        for date in ["20220401", "20220402"] {
+            let location = Location {
+                bucket: String::from("sms"),
+                device_id: String::from("100"),
+                key: date.to_string(),
+                stem: dir.join("sms/100").join(date),
+            };
+            writer
+                .append(&location, &Rotation::default(), None, date)
+                .unwrap();
+        }
+        let files = dir.join("sms/100/20220401.files");
+        fs::create_dir_all(&files).unwrap();
+        fs::write(files.join("01G0Z5W3B3N3YV1QKX4CQ4G9HD-body"), b"body").unwrap();
+
+        let uploader = Uploader::new(UploadConfig {
+            s3: S3Config {
+                endpoint: format!("http://127.0.0.1:{}", port),
+                bucket: String::from("hooks"),
+                region: String::from("us-east-1"),
+                access_key: Some(String::from("minio")),
+                secret_key: Some(String::from("minio123")),
+                path_style: true,
+            },
+            prefix: String::from("logs/"),
+            delete_local: true,
+            backoff: default_backoff(),
+            max_backoff: default_max_backoff(),
+        })
+        .unwrap();
+        let http = super::super::s3::client(std::time::Duration::from_secs(5));
+        let config = Config::default();
+        let now: DateTime<Utc> = "2022-04-02T08:00:00Z".parse().unwrap();
+
+        // first attempt fails and backs off, the files of the day go anyway
+        let done = uploader.run(&http, dir, &config, now, false).await.unwrap();
+        assert_eq!(
+            done,
+            vec!["logs/sms/100/20220401.files/01G0Z5W3B3N3YV1QKX4CQ4G9HD-body"]
+        );
+        assert!(!files.exists());
+        let manifest = Manifest::load(dir).unwrap();
+        let entry = &manifest.files["sms/100/20220401.log"];
+        assert_eq!(entry.state, UploadState::Pending);
+        assert_eq!(entry.next_try, Some(now + Duration::seconds(60)));
+
+        let done = uploader.run(&http, dir, &config, now, false).await.unwrap();
+        assert!(done.is_empty());
+
+        let later = now + Duration::minutes(5);
+        let done = uploader
+            .run(&http, dir, &config, later, false)
+            .await
+            .unwrap();
+        assert_eq!(done, vec!["logs/sms/100/20220401.log"]);
+        assert_eq!(
+            store.lock().unwrap().1["/hooks/logs/sms/100/20220401.log"],
+            b"20220401\n"
+        );
+
+        // verified, so the local copy is gone, the open date is kept
+        let manifest = Manifest::load(dir).unwrap();
+        assert_eq!(
+            manifest.files["sms/100/20220401.log"].state,
+            UploadState::Deleted
+        );
+        assert!(!dir.join("sms/100/20220401.log").exists());
+        assert!(dir.join("sms/100/20220402.log").exists());
+
+        // the entry goes once the object is gone too
+        store
+            .lock()
+            .unwrap()
+            .1
+            .remove("/hooks/logs/sms/100/20220401.log");
+        uploader
+            .run(&http, dir, &config, later, false)
+            .await
+            .unwrap();
+        assert!(Manifest::load(dir)
+            .unwrap()
+            .files
+            .contains_key("sms/100/20220401.log"));
+        let much_later = later + Duration::seconds(default_max_backoff());
+        uploader
+            .run(&http, dir, &config, much_later, false)
+            .await
+            .unwrap();
+        let manifest = Manifest::load(dir).unwrap();
+        assert!(!manifest.files.contains_key("sms/100/20220401.log"));
+        assert!(manifest
+            .files
+            .contains_key("sms/100/20220401.files/01G0Z5W3B3N3YV1QKX4CQ4G9HD-body"));
+    }
+}